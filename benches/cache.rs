@@ -0,0 +1,95 @@
+/*
+    Cache strategies under a single caller and under contention of several threads.
+    Run with `cargo bench --bench cache`.
+ */
+
+use std::thread;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::executor::block_on;
+use ngamahi_id_gen::cache::{self, CacheClient};
+use ngamahi_id_gen::range::Range;
+
+const THREADS: u64 = 8;
+const SEQUENCES: usize = 16;
+const REFILL_SIZE: u64 = 10_000;
+
+type NewCache = fn() -> CacheClient;
+
+
+// takes a few ids, refilling the cache the way RangeProvider does when it runs dry
+async fn take_ids(cache: &CacheClient, seq_name: &str, next_begin: &mut u64) {
+    let (_, needed) = cache.get(seq_name.to_string(), 10).await;
+
+    if needed > 0 {
+        cache.put(seq_name.to_string(), Range::new(*next_begin, *next_begin + REFILL_SIZE)).await;
+        *next_begin += REFILL_SIZE;
+    }
+}
+
+fn strategies() -> Vec<(&'static str, NewCache)> {
+    vec![
+        ("common", cache::new_common),
+        ("thread_local", cache::new_thread_local),
+        ("hybrid", || cache::new_hybrid(200)),
+    ]
+}
+
+fn single_thread(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_thread");
+
+    for (name, new_cache) in strategies() {
+        let cache = new_cache();
+        let mut next_begin = 0;
+        let mut i = 0;
+
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| {
+            i = (i + 1) % SEQUENCES;
+            block_on(take_ids(&cache, &format!("seq-{}", i), &mut next_begin));
+        }));
+
+        block_on(cache.stop());
+    }
+
+    group.finish();
+}
+
+fn contended(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("{}_threads", THREADS));
+
+    for (name, new_cache) in strategies() {
+        let cache = new_cache();
+
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter_custom(|iters| {
+            let start = Instant::now();
+
+            thread::scope(|s| {
+                for t in 0..THREADS {
+                    let cache = cache.clone();
+
+                    s.spawn(move || {
+                        let mut next_begin = t << 48;
+
+                        for i in 0..iters / THREADS {
+                            let seq_name = format!("seq-{}", i as usize % SEQUENCES);
+                            block_on(take_ids(&cache, &seq_name, &mut next_begin));
+                        }
+                    });
+                }
+            });
+
+            start.elapsed()
+        }));
+
+        block_on(cache.stop());
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = single_thread, contended
+}
+criterion_main!(benches);
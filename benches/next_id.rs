@@ -0,0 +1,59 @@
+/*
+    Single ids taken from a per worker cursor vs from the cache, i.e. what
+    GET /sequence/{seq}/next does vs GET /sequence/{seq}?size=1.
+    Run with `cargo bench --bench next_id`.
+ */
+
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use ngamahi_id_gen::cache;
+use ngamahi_id_gen::cache::cursor::IdCursors;
+use ngamahi_id_gen::range::Range;
+
+const SEQ_NAME: &str = "seq";
+const REFILL_SIZE: u64 = 10_000;
+
+
+fn single_id(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_id");
+
+    let cache = cache::new_thread_local();
+    let mut next_begin = 0;
+
+    group.bench_function("cache", |b| b.iter(|| {
+        let (ranges, needed) = block_on(cache.get(SEQ_NAME.to_string(), 1));
+
+        if needed > 0 {
+            block_on(cache.put(SEQ_NAME.to_string(), Range::new(next_begin, next_begin + REFILL_SIZE)));
+            next_begin += REFILL_SIZE;
+        }
+
+        ranges.first().map(|r| r.begin)
+    }));
+
+    block_on(cache.stop());
+
+    let cursors = IdCursors::new();
+    let mut next_begin = 0;
+
+    group.bench_function("cursor", |b| b.iter(|| {
+        let id = cursors.take(SEQ_NAME);
+
+        if id.is_none() {
+            cursors.refill(SEQ_NAME, vec![Range::new(next_begin, next_begin + REFILL_SIZE)]);
+            next_begin += REFILL_SIZE;
+        }
+
+        id
+    }));
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = single_id
+}
+criterion_main!(benches);
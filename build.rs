@@ -0,0 +1,14 @@
+// gRPC code is generated from proto/ at build time. Protos are parsed by protox,
+// so building doesn't need protoc installed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+
+    let descriptors = protox::compile(["proto/id_gen.proto"], ["proto"])?;
+
+    // the client is only for tests of the server
+    tonic_build::configure()
+        .client_mod_attribute("ngamahi.idgen.v1", "#[cfg(test)]")
+        .compile_fds(descriptors)?;
+
+    Ok(())
+}
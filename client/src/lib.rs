@@ -0,0 +1,282 @@
+/*
+    Client of ngamahi id server.
+
+    It keeps a local pool of ranges per sequence and hands out ids from it, so most calls
+    don't leave the process. When a pool runs low, a new range is fetched in background.
+
+    The client is built on awc, so it must be used from within an actix (or tokio local set) runtime.
+ */
+
+mod pool;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use awc::error::{JsonPayloadError, SendRequestError};
+use awc::http::{header, StatusCode};
+use log::warn;
+use crate::pool::Pool;
+
+use ngamahi_id_gen_types::{decode_binary, BINARY_CONTENT_TYPE};
+
+pub use ngamahi_id_gen_types::Range;
+
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    // base urls of id servers, e.g. "http://id-gen:8080". Tried in turn when one fails
+    pub endpoints: Vec<String>,
+
+    // sequences are taken from /tenants/{tenant}/sequence/{seq} if set
+    pub tenant: Option<String>,
+    pub api_key: Option<String>,
+
+    // ids requested from server at once, must not exceed server's client_range_max_size
+    pub fetch_size: u64,
+
+    // background fetch starts when a pool has fewer ids than this
+    pub prefetch_threshold: u64,
+
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(endpoints: Vec<String>) -> Self {
+        Self {
+            endpoints,
+            tenant: None,
+            api_key: None,
+            fetch_size: 100,
+            prefetch_threshold: 20,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct IdClient {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    config: ClientConfig,
+    http: awc::Client,
+    pools: RefCell<HashMap<String, Pool>>,
+
+    // index of endpoint to send the next request to
+    endpoint: Cell<usize>,
+}
+
+
+impl IdClient {
+    pub fn new(config: ClientConfig) -> Self {
+        assert!(!config.endpoints.is_empty(), "At least one endpoint of id server is needed");
+        assert!(config.fetch_size > 0, "fetch_size must be greater than 0");
+
+        Self {
+            inner: Rc::new(Inner {
+                config,
+                http: awc::Client::default(),
+                pools: RefCell::new(HashMap::new()),
+                endpoint: Cell::new(0),
+            })
+        }
+    }
+
+    pub async fn next_id(&self, seq_name: &str) -> Result<u64, ClientErr> {
+        let ids = self.next_ids(seq_name, 1).await?;
+        Ok(ids[0])
+    }
+
+    /// Returns `count` unique ids. They are ascending but not necessarily contiguous
+    pub async fn next_ids(&self, seq_name: &str, count: u64) -> Result<Vec<u64>, ClientErr> {
+        let mut ids = Vec::with_capacity(count as usize);
+
+        loop {
+            let needed = count - ids.len() as u64;
+            self.pool(seq_name, |pool| pool.take(needed, &mut ids));
+
+            if ids.len() as u64 == count {
+                self.prefetch_if_low(seq_name);
+                return Ok(ids);
+            }
+
+            // pool is empty, client has to wait for the server.
+            // Other tasks may take fetched ids before this one wakes up, hence the loop
+            let fetch_size = self.inner.config.fetch_size;
+            let ranges = self.inner.fetch(seq_name, fetch_size).await?;
+
+            self.pool(seq_name, |pool| pool.push(ranges));
+        }
+    }
+
+    fn prefetch_if_low(&self, seq_name: &str) {
+        let threshold = self.inner.config.prefetch_threshold;
+
+        let must_fetch = self.pool(seq_name, |pool| {
+            let must_fetch = pool.available() < threshold && !pool.prefetching;
+            pool.prefetching |= must_fetch;
+            must_fetch
+        });
+
+        if !must_fetch {
+            return;
+        }
+
+        let client = self.clone();
+        let seq_name = seq_name.to_string();
+
+        actix_rt::spawn(async move {
+            let fetch_size = client.inner.config.fetch_size;
+            let result = client.inner.fetch(&seq_name, fetch_size).await;
+
+            client.pool(&seq_name, |pool| {
+                pool.prefetching = false;
+
+                match result {
+                    Ok(ranges) => pool.push(ranges),
+                    Err(err) => warn!("Couldn't prefetch ids of sequence '{}': {:?}", &seq_name, err),
+                }
+            });
+        });
+    }
+
+    fn pool<T>(&self, seq_name: &str, f: impl FnOnce(&mut Pool) -> T) -> T {
+        let mut pools = self.inner.pools.borrow_mut();
+        f(pools.entry(seq_name.to_string()).or_default())
+    }
+}
+
+
+impl Inner {
+    async fn fetch(&self, seq_name: &str, size: u64) -> Result<Vec<Range>, ClientErr> {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let err = match self.fetch_once(seq_name, size).await {
+                Ok(ranges) => return Ok(ranges),
+                Err(err) => err,
+            };
+
+            attempt += 1;
+            if !err.is_retryable() || attempt > self.config.max_retries {
+                return Err(err);
+            }
+
+            warn!("Request for ids of sequence '{}' failed (attempt {}): {:?}", seq_name, attempt, err);
+
+            // next attempt goes to another server
+            self.endpoint.set((self.endpoint.get() + 1) % self.config.endpoints.len());
+
+            let wait = match &err {
+                ClientErr::Status { retry_after: Some(retry_after), .. } => *retry_after,
+                _ => backoff,
+            };
+
+            actix_rt::time::sleep(wait).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+        }
+    }
+
+    async fn fetch_once(&self, seq_name: &str, size: u64) -> Result<Vec<Range>, ClientErr> {
+        let endpoint = &self.config.endpoints[self.endpoint.get()];
+        let seq_name = encode_path_segment(seq_name);
+
+        let url = match &self.config.tenant {
+            Some(tenant) => format!("{}/tenants/{}/sequence/{}?size={}", endpoint, encode_path_segment(tenant), seq_name, size),
+            None => format!("{}/sequence/{}?size={}", endpoint, seq_name, size),
+        };
+
+        // binary ranges are the cheapest to parse, servers that don't know them answer with JSON
+        let mut req = self.http.get(url)
+            .insert_header(("User-Agent", "id-gen-client/1.0"))
+            .insert_header((header::ACCEPT, format!("{}, application/json;q=0.5", BINARY_CONTENT_TYPE)));
+        if let Some(api_key) = &self.config.api_key {
+            req = req.bearer_auth(api_key);
+        }
+
+        let mut res = req.send().await?;
+
+        if !res.status().is_success() {
+            let retry_after = res.headers().get(header::RETRY_AFTER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<u64>().ok())
+                .map(Duration::from_secs);
+
+            let body = res.body().await.map(|b| String::from_utf8_lossy(&b).to_string()).unwrap_or_default();
+
+            return Err(ClientErr::Status { status: res.status(), body, retry_after });
+        }
+
+        let is_binary = res.headers().get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| h.starts_with(BINARY_CONTENT_TYPE));
+
+        let body = res.body().await.map_err(JsonPayloadError::Payload)?;
+
+        if is_binary {
+            decode_binary(&body).ok_or(ClientErr::MalformedRanges(body.len()))
+        } else {
+            serde_json::from_slice(&body).map_err(|err| JsonPayloadError::Deserialize(err).into())
+        }
+    }
+}
+
+
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+
+// =========| ERRORS |=========
+
+#[derive(Debug)]
+pub enum ClientErr {
+    Request(SendRequestError),
+    Response(JsonPayloadError),
+
+    // binary body of this length is not a whole number of ranges
+    MalformedRanges(usize),
+    Status { status: StatusCode, body: String, retry_after: Option<Duration> },
+}
+
+impl ClientErr {
+    // network failures, overloaded or throttling servers are worth another attempt,
+    // but e.g. missing sequence or permission won't change
+    fn is_retryable(&self) -> bool {
+        match self {
+            ClientErr::Request(_) => true,
+            ClientErr::Response(_) | ClientErr::MalformedRanges(_) => false,
+            ClientErr::Status { status, .. } =>
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl From<SendRequestError> for ClientErr {
+    fn from(value: SendRequestError) -> Self {
+        Self::Request(value)
+    }
+}
+
+impl From<JsonPayloadError> for ClientErr {
+    fn from(value: JsonPayloadError) -> Self {
+        Self::Response(value)
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use ngamahi_id_gen_types::Range;
+
+
+/// Ranges of one sequence that were fetched from the server but not handed out yet
+#[derive(Default)]
+pub(crate) struct Pool {
+    ranges: VecDeque<Range>,
+    available: u64,
+
+    // set while a background fetch for this sequence is in flight
+    pub prefetching: bool,
+}
+
+impl Pool {
+    pub fn available(&self) -> u64 {
+        self.available
+    }
+
+    pub fn push(&mut self, ranges: Vec<Range>) {
+        for range in ranges {
+            self.available += range.len();
+            self.ranges.push_back(range);
+        }
+    }
+
+    /// Takes up to `count` ids, oldest ranges first
+    pub fn take(&mut self, count: u64, ids: &mut Vec<u64>) {
+        let mut left = count;
+
+        while left > 0 {
+            let range = match self.ranges.front_mut() {
+                Some(range) => range,
+                None => return,
+            };
+
+            let taken = left.min(range.len());
+            ids.extend(range.ids().take(taken as usize));
+
+            range.begin += taken * range.stride;
+            self.available -= taken;
+            left -= taken;
+
+            if range.is_empty() {
+                self.ranges.pop_front();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ngamahi_id_gen_types::Range;
+    use super::Pool;
+
+    #[test]
+    fn takes_ids_across_ranges() {
+        let mut pool = Pool::default();
+        pool.push(vec![Range::new(0, 3), Range::new(10, 12)]);
+
+        let mut ids = vec![];
+        pool.take(4, &mut ids);
+
+        assert_eq!(vec![0, 1, 2, 10], ids);
+        assert_eq!(1, pool.available());
+
+        ids.clear();
+        pool.take(5, &mut ids);
+
+        assert_eq!(vec![11], ids);
+        assert_eq!(0, pool.available());
+    }
+
+    #[test]
+    fn takes_every_stride_id_of_interleaved_ranges() {
+        let mut pool = Pool::default();
+        pool.push(vec![Range { begin: 1, end: 9, stride: 2 }]);
+
+        let mut ids = vec![];
+        pool.take(3, &mut ids);
+        assert_eq!(vec![1, 3, 5], ids);
+
+        ids.clear();
+        pool.take(3, &mut ids);
+        assert_eq!(vec![7], ids);
+        assert_eq!(0, pool.available());
+    }
+}
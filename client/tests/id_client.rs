@@ -0,0 +1,91 @@
+mod stub_server;
+
+use std::time::Duration;
+use awc::http::StatusCode;
+use ngamahi_id_gen_client::{ClientConfig, ClientErr, IdClient};
+use stub_server::StubServer;
+
+
+fn config(endpoints: Vec<String>) -> ClientConfig {
+    ClientConfig {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(5),
+        ..ClientConfig::new(endpoints)
+    }
+}
+
+// the background fetch of the pool has landed
+async fn wait_for_requests(server: &StubServer, requests: usize) {
+    for _ in 0..200 {
+        if server.requests() >= requests {
+            // the response still has to reach the pool
+            actix_rt::time::sleep(Duration::from_millis(20)).await;
+            return;
+        }
+        actix_rt::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("expected {} requests, got {}", requests, server.requests());
+}
+
+
+#[actix_rt::test]
+async fn fails_over_to_the_next_server_when_one_is_down() {
+    let server = StubServer::start();
+    let client = IdClient::new(config(vec![StubServer::down(), server.url()]));
+
+    assert_eq!((0..10).collect::<Vec<_>>(), client.next_ids("orders", 10).await.unwrap());
+    assert_eq!(vec!["/sequence/orders?size=100"], server.paths());
+
+    // the client stays with the server that answered
+    client.next_ids("invoices", 1).await.unwrap();
+    assert_eq!(2, server.requests());
+}
+
+#[actix_rt::test]
+async fn retries_server_errors_until_one_succeeds_or_attempts_run_out() {
+    let server = StubServer::start();
+    let client = IdClient::new(config(vec![server.url()]));
+
+    server.fail_next(503, None);
+    server.fail_next(500, None);
+    assert_eq!(0, client.next_id("orders").await.unwrap());
+    assert_eq!(3, server.requests());
+
+    // a first attempt and max_retries more
+    for _ in 0..4 {
+        server.fail_next(503, None);
+    }
+    let failed = client.next_ids("invoices", 1).await;
+    assert!(matches!(failed, Err(ClientErr::Status { status: StatusCode::SERVICE_UNAVAILABLE, .. })), "{:?}", failed);
+    assert_eq!(7, server.requests());
+
+    // a client error won't change on retry
+    server.fail_next(404, None);
+    let failed = client.next_ids("missing", 1).await;
+    assert!(matches!(failed, Err(ClientErr::Status { status: StatusCode::NOT_FOUND, .. })), "{:?}", failed);
+    assert_eq!(8, server.requests());
+}
+
+#[actix_rt::test]
+async fn refills_the_pool_before_it_runs_out() {
+    let server = StubServer::start();
+    let client = IdClient::new(ClientConfig { fetch_size: 100, prefetch_threshold: 20, ..config(vec![server.url()]) });
+
+    // leaves 15 ids in the pool, fewer than the threshold
+    let mut ids = client.next_ids("orders", 85).await.unwrap();
+    assert_eq!(1, server.requests());
+
+    // only one background fetch is in flight however many ids are taken meanwhile
+    for _ in 0..5 {
+        ids.push(client.next_id("orders").await.unwrap());
+    }
+    wait_for_requests(&server, 2).await;
+    assert_eq!(2, server.requests());
+
+    // the refilled pool serves these without waiting for the server
+    ids.extend(client.next_ids("orders", 100).await.unwrap());
+    assert_eq!(2, server.requests());
+
+    assert_eq!((0..190).collect::<Vec<_>>(), ids);
+}
@@ -0,0 +1,138 @@
+/*
+    Stand-in for an id server: hands out contiguous ranges of one counter to GET requests of any
+    sequence, or answers the next requests with scripted failures. It records paths of requests.
+
+    Every connection is served by its own thread with blocking I/O, like the fake etcd of the server's tests.
+ */
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+pub struct StubServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    // statuses of the next responses, with Retry-After if set
+    failures: VecDeque<(u16, Option<u64>)>,
+    paths: Vec<String>,
+}
+
+
+impl StubServer {
+    pub fn start() -> StubServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+
+        StubServer { addr, state }
+    }
+
+    /// Base url of a server that is down: nothing listens at its address
+    pub fn down() -> String {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        format!("http://{}", addr)
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The next request is answered with the status and an error body instead of ranges
+    pub fn fail_next(&self, status: u16, retry_after: Option<u64>) {
+        self.state.lock().unwrap().failures.push_back((status, retry_after));
+    }
+
+    /// Paths with queries of requests served so far, failed ones included
+    pub fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().paths.clone()
+    }
+
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().paths.len()
+    }
+}
+
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let _ = writer.set_nodelay(true);
+
+    while let Some(path) = read_request(&mut reader) {
+        let response = {
+            let mut state = state.lock().unwrap();
+            state.paths.push(path.clone());
+
+            match state.failures.pop_front() {
+                Some((status, retry_after)) => {
+                    let retry_after = retry_after.map(|secs| format!("Retry-After: {}\r\n", secs)).unwrap_or_default();
+                    response(status, &retry_after, "Error: injected failure")
+                }
+                None => {
+                    let size: u64 = path.split_once("size=").and_then(|(_, size)| size.parse().ok()).unwrap_or(1);
+                    let begin = state.next_id;
+                    state.next_id += size;
+
+                    response(200, "Content-Type: application/json\r\n", &format!(r#"[{{"begin":{},"end":{}}}]"#, begin, begin + size))
+                }
+            }
+        };
+
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn response(status: u16, headers: &str, body: &str) -> String {
+    format!("HTTP/1.1 {} Stub\r\n{}Content-Length: {}\r\n\r\n{}", status, headers, body.len(), body)
+}
+
+// None once the client closes the connection. Requests of the client have no body
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let path = request_line.split_whitespace().nth(1)?.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(path)
+}
@@ -1,35 +1,592 @@
-use actix_web::{HttpResponse, Responder, web, get, post};
-use serde::Deserialize;
+use std::future::{ready, Ready};
+use actix_web::{delete, Error, FromRequest, HttpRequest, HttpResponse, Responder, web, get, post, put};
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::{self, Header};
+use serde::{Deserialize, Serialize};
 use crate::AppData;
+use crate::auth::Caller;
+use crate::config::Permission;
+use crate::demand::DemandStats;
+use crate::encoding::{Format, JSON_CONTENT_TYPE};
+use crate::etcd_client::{EtcdErr, GetRangeErr, ResetTxErr, SeqState};
+use crate::range::{checked_seq_name, is_seq_name, Range, RangeProviderErr};
+use crate::snapshot::{ImportOutcome, ImportPolicy, ImportResult, NDJSON_CONTENT_TYPE, Snapshot, SnapshotFormat};
+use crate::metrics::{MetricsText, CONTENT_TYPE as METRICS_CONTENT_TYPE};
+use crate::policy::SeqPolicy;
+use crate::audit::{AuditEntry, Operation};
 
 #[derive(Deserialize)]
 pub struct Query{
     size: u64,
+
+    // respond with every id of the ranges instead of their bounds
+    #[serde(default)]
+    expand: bool,
+}
+
+
+/// Full name of the sequence the request is about. Endpoints that take it are served both
+/// at the root and within /tenants/{tenant} scope, tenant sequences are named tenants/{tenant}/{seq}
+pub struct SeqName(pub String);
+
+impl FromRequest for SeqName {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let seq_id = req.match_info().get("seq").unwrap_or_default();
+        let tenant = req.match_info().get("tenant");
+
+        if let Some(tenant) = tenant {
+            let known = req.app_data::<web::Data<AppData>>()
+                .is_some_and(|d| d.seq_provider.tenants.contains_key(tenant));
+
+            if !known {
+                return ready(Err(ErrorNotFound(format!("No such tenant '{}'", tenant))));
+            }
+        }
+
+        ready(checked_seq_name(tenant, seq_id).map(SeqName).map_err(|err| ErrorBadRequest(format!("{:?}", err))))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdvanceQuery{
+    min: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ResetQuery{
+    expected: u64,
+    value: u64,
+
+    // reset may move a sequence backwards, so it must be asked for explicitly
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery{
+    #[serde(default)]
+    format: SnapshotFormat,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery{
+    policy: ImportPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery{
+    // id of the last entry of the previous page
+    #[serde(default)]
+    after: String,
+
+    #[serde(default)]
+    sequence: Option<String>,
+
+    #[serde(default = "default_audit_limit")]
+    limit: u64,
+}
+
+fn default_audit_limit() -> u64 {
+    100
+}
+
+// larger limits are lowered to it, the rest comes on next pages
+const MAX_AUDIT_LIMIT: u64 = 1000;
+
+#[derive(Serialize)]
+struct SeqInfo {
+    #[serde(flatten)]
+    state: SeqState,
+
+    // absent until this instance serves the sequence
+    demand: Option<DemandStats>,
+}
+
+#[derive(Serialize)]
+struct ValueChange {
+    old_value: u64,
+    value: u64,
+}
+
+#[derive(Serialize)]
+struct NextId {
+    id: u64,
+}
+
+#[derive(Serialize)]
+struct SeqList {
+    // etcd revision sequences were read at
+    revision: u64,
+    sequences: Vec<SeqState>,
 }
 
 
 #[get("/sequence/{seq}")]
-pub async fn get_next_range(data: web::Data<AppData>, path: web::Path<String>, query: web::Query<Query>) -> impl Responder {
+pub async fn get_next_range(data: web::Data<AppData>, path: web::Path<String>, query: web::Query<Query>, caller: Caller, req: HttpRequest) -> impl Responder {
     let seq_id = path.into_inner();
+    if let Some(forbidden) = caller.forbidden(&seq_id, Permission::Read) {
+        return forbidden;
+    }
+
+    let format = match Format::negotiate(&req) {
+        Some(format) => format,
+        None => return not_acceptable(),
+    };
+
+    if let Some(too_many) = rate_limited(&data, &caller, &req, &seq_id, query.size) {
+        return too_many;
+    }
+
     let next_range = data.seq_provider.get_next_range(
         seq_id,
         query.size.to_owned(),
     ).await;
 
     match next_range {
-        Ok(seq) => HttpResponse::Ok().body(serde_json::to_string(&seq).unwrap()),
+        Ok(ranges) => ranges_response(format, query.expand, ranges),
+        Err(err @ RangeProviderErr::Validation(_)) => HttpResponse::BadRequest().body(format!("Error: '{:?}'", err)),
+        Err(err) => HttpResponse::NotFound().body(format!("Error: '{:?}'", err))
+    }
+}
+
+// single id as plain text, or as {"id": ...} if the client prefers JSON
+#[get("/sequence/{seq}/next")]
+pub async fn get_next_id(data: web::Data<AppData>, path: web::Path<String>, caller: Caller, req: HttpRequest) -> impl Responder {
+    let seq_id = path.into_inner();
+    if let Some(forbidden) = caller.forbidden(&seq_id, Permission::Read) {
+        return forbidden;
+    }
+
+    if let Some(too_many) = rate_limited(&data, &caller, &req, &seq_id, 1) {
+        return too_many;
+    }
+
+    match data.seq_provider.get_next_id(seq_id).await {
+        Ok(id) if prefers_json(&req) => HttpResponse::Ok()
+            .content_type(JSON_CONTENT_TYPE)
+            .body(serde_json::to_string(&NextId { id }).unwrap()),
+        Ok(id) => HttpResponse::Ok().content_type("text/plain").body(id.to_string()),
+        Err(err @ RangeProviderErr::Validation(_)) => HttpResponse::BadRequest().body(format!("Error: '{:?}'", err)),
         Err(err) => HttpResponse::NotFound().body(format!("Error: '{:?}'", err))
     }
 }
 
 #[post("/sequence/{seq}")]
-pub async fn create_seq(data: web::Data<AppData>, path: web::Path<String>) -> impl Responder {
+pub async fn create_seq(data: web::Data<AppData>, path: web::Path<String>, caller: Caller, req: HttpRequest) -> impl Responder {
     let seq_id = path.into_inner();
+    if let Some(forbidden) = caller.forbidden(&seq_id, Permission::Admin) {
+        return forbidden;
+    }
+
     let result = data.seq_provider.create_sequence(seq_id.clone()).await;
 
     match result {
-        Ok(_) => HttpResponse::Ok().body(format!("Sequence '{}' created successfully", seq_id)),
+        Ok(write) => {
+            data.audit.record(AuditEntry::of_request(Operation::Create, &seq_id, &caller, &req).with_write(write)).await;
+            HttpResponse::Ok().body(format!("Sequence '{}' created successfully", seq_id))
+        }
+        Err(err @ RangeProviderErr::Validation(_)) =>
+            HttpResponse::BadRequest().body(format!("Unable to create sequence: '{:?}'", err)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Something bad happened. Unable to create sequence '{:?}'", err))
+    }
+}
+
+
+#[get("/tenants/{tenant}/sequence/{seq}")]
+pub async fn get_next_tenant_range(data: web::Data<AppData>, path: web::Path<(String, String)>, query: web::Query<Query>, caller: Caller, req: HttpRequest) -> impl Responder {
+    let (tenant, seq_id) = path.into_inner();
+    let seq_name = match checked_seq_name(Some(&tenant), &seq_id) {
+        Ok(seq_name) => seq_name,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Error: '{:?}'", err)),
+    };
+    if let Some(forbidden) = caller.forbidden(&seq_name, Permission::Read) {
+        return forbidden;
+    }
+
+    let format = match Format::negotiate(&req) {
+        Some(format) => format,
+        None => return not_acceptable(),
+    };
+
+    if let Some(too_many) = rate_limited(&data, &caller, &req, &seq_name, query.size) {
+        return too_many;
+    }
+
+    let next_range = data.seq_provider.get_next_tenant_range(
+        tenant,
+        seq_id,
+        query.size.to_owned(),
+    ).await;
+
+    match next_range {
+        Ok(ranges) => ranges_response(format, query.expand, ranges),
+        Err(err @ RangeProviderErr::Validation(_)) => HttpResponse::BadRequest().body(format!("Error: '{:?}'", err)),
+        Err(err) => HttpResponse::NotFound().body(format!("Error: '{:?}'", err))
+    }
+}
+
+#[post("/tenants/{tenant}/sequence/{seq}")]
+pub async fn create_tenant_seq(data: web::Data<AppData>, path: web::Path<(String, String)>, caller: Caller, req: HttpRequest) -> impl Responder {
+    let (tenant, seq_id) = path.into_inner();
+    let seq_name = match checked_seq_name(Some(&tenant), &seq_id) {
+        Ok(seq_name) => seq_name,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Error: '{:?}'", err)),
+    };
+    if let Some(forbidden) = caller.forbidden(&seq_name, Permission::Admin) {
+        return forbidden;
+    }
+
+    let result = data.seq_provider.create_tenant_sequence(tenant.clone(), seq_id.clone()).await;
+
+    match result {
+        Ok(write) => {
+            data.audit.record(AuditEntry::of_request(Operation::Create, &seq_name, &caller, &req).with_write(write)).await;
+            HttpResponse::Ok().body(format!("Sequence '{}' of tenant '{}' created successfully", seq_id, tenant))
+        }
+        Err(err @ RangeProviderErr::NoSuchTenant(_)) =>
+            HttpResponse::NotFound().body(format!("Error: '{:?}'", err)),
+        Err(err @ RangeProviderErr::TenantLimit(_)) =>
+            HttpResponse::Conflict().body(format!("Unable to create sequence: '{:?}'", err)),
+        Err(err @ RangeProviderErr::Validation(_)) =>
+            HttpResponse::BadRequest().body(format!("Unable to create sequence: '{:?}'", err)),
         Err(err) =>
             HttpResponse::InternalServerError().body(format!("Something bad happened. Unable to create sequence '{:?}'", err))
     }
 }
+
+
+#[get("/sequences")]
+pub async fn list_seqs(data: web::Data<AppData>, caller: Caller) -> impl Responder {
+    match data.seq_provider.list_sequences().await {
+        Ok((revision, sequences)) => {
+            let sequences = sequences.into_iter()
+                .filter(|s| caller.is_allowed(&s.name, Permission::Read))
+                .collect();
+
+            HttpResponse::Ok().body(serde_json::to_string(&SeqList { revision, sequences }).unwrap())
+        }
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to list sequences: '{:?}'", err))
+    }
+}
+
+// stats of sequences the caller may read
+#[get("/metrics")]
+pub async fn get_metrics(data: web::Data<AppData>, caller: Caller) -> impl Responder {
+    let stats: Vec<_> = data.seq_provider.demand.all_stats().into_iter()
+        .filter(|(name, _)| caller.is_allowed(name, Permission::Read))
+        .collect();
+
+    let mut text = MetricsText::default();
+
+    text.per_sequence("id_gen_demand_ids_per_second", "gauge",
+        "Recent rate of ids served to clients",
+        stats.iter().map(|(name, s)| (name.as_str(), s.ids_per_sec)));
+
+    text.per_sequence("id_gen_etcd_fetch_size", "gauge",
+        "Size of the last range taken from etcd",
+        stats.iter().map(|(name, s)| (name.as_str(), s.fetch_size as f64)));
+
+    text.per_sequence("id_gen_etcd_fetches_total", "counter",
+        "Ranges taken from etcd",
+        stats.iter().map(|(name, s)| (name.as_str(), s.etcd_fetches as f64)));
+
+    // as the capacity check computed them last, only the leader has them
+    let forecasts: Vec<_> = data.forecasts.get().into_iter()
+        .filter(|(name, _)| caller.is_allowed(name, Permission::Read))
+        .collect();
+
+    text.per_sequence("id_gen_capacity_used_ratio", "gauge",
+        "Part of the declared capacity a sequence has used",
+        forecasts.iter().map(|(name, f)| (name.as_str(), f.used_ratio)));
+
+    text.per_sequence("id_gen_exhaustion_seconds", "gauge",
+        "Forecast time until a sequence runs out of its capacity",
+        forecasts.iter().filter_map(|(name, f)| Some((name.as_str(), f.exhaustion_secs? as f64))));
+
+    HttpResponse::Ok().content_type(METRICS_CONTENT_TYPE).body(text.finish())
+}
+
+#[get("/sequence/{seq}/info")]
+pub async fn seq_info(data: web::Data<AppData>, seq: SeqName, caller: Caller) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Read) {
+        return forbidden;
+    }
+
+    match data.seq_provider.sequence_info(seq.0.clone()).await {
+        Ok(state) => {
+            let info = SeqInfo { state, demand: data.seq_provider.demand.stats(&seq.0) };
+            HttpResponse::Ok().body(serde_json::to_string(&info).unwrap())
+        }
+        Err(err @ EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(_))) =>
+            HttpResponse::NotFound().body(format!("Error: '{:?}'", err)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to get sequence info: '{:?}'", err))
+    }
+}
+
+#[delete("/sequence/{seq}")]
+pub async fn delete_seq(data: web::Data<AppData>, seq: SeqName, caller: Caller, req: HttpRequest) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Admin) {
+        return forbidden;
+    }
+
+    match data.seq_provider.delete_sequence(seq.0.clone()).await {
+        Ok(write) if write.revision.is_some() => {
+            data.audit.record(AuditEntry::of_request(Operation::Delete, &seq.0, &caller, &req).with_write(write)).await;
+            HttpResponse::Ok().body(format!("Sequence '{}' deleted", seq.0))
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("No such sequence '{}'", seq.0)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to delete sequence: '{:?}'", err))
+    }
+}
+
+// moves sequence to max(current value, min), e.g. above ids already used in a migrated database
+#[post("/sequence/{seq}/advance")]
+pub async fn advance_seq(data: web::Data<AppData>, seq: SeqName, query: web::Query<AdvanceQuery>, caller: Caller, req: HttpRequest) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Admin) {
+        return forbidden;
+    }
+
+    match data.seq_provider.advance_sequence(seq.0.clone(), query.min).await {
+        Ok(write) => {
+            data.audit.record(AuditEntry::of_request(Operation::Advance, &seq.0, &caller, &req).with_write(write)).await;
+
+            let change = ValueChange { old_value: write.old_value.unwrap_or_default(), value: write.value.unwrap_or_default() };
+            HttpResponse::Ok().body(serde_json::to_string(&change).unwrap())
+        }
+        Err(err @ EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(_))) =>
+            HttpResponse::NotFound().body(format!("Error: '{:?}'", err)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to advance sequence: '{:?}'", err))
+    }
+}
+
+// sets sequence to any value, but only if it still has the value the caller expects
+#[post("/sequence/{seq}/reset")]
+pub async fn reset_seq(data: web::Data<AppData>, seq: SeqName, query: web::Query<ResetQuery>, caller: Caller, req: HttpRequest) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Admin) {
+        return forbidden;
+    }
+
+    if !query.confirm {
+        return HttpResponse::BadRequest()
+            .body("Reset may hand out already used ids again, add confirm=true to proceed");
+    }
+
+    match data.seq_provider.reset_sequence(seq.0.clone(), query.expected, query.value).await {
+        Ok(write) => {
+            data.audit.record(AuditEntry::of_request(Operation::Reset, &seq.0, &caller, &req).with_write(write)).await;
+            HttpResponse::Ok().body(serde_json::to_string(&ValueChange { old_value: query.expected, value: query.value }).unwrap())
+        }
+        Err(EtcdErr::ResetTxErr(ResetTxErr::UnexpectedValue { actual })) =>
+            HttpResponse::Conflict().body(format!("Sequence '{}' has value {}, expected {}", seq.0, actual, query.expected)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to reset sequence: '{:?}'", err))
+    }
+}
+
+// time to exhaustion of the sequence's capacity, u64 if its policy declares none
+#[get("/sequence/{seq}/forecast")]
+pub async fn seq_forecast(data: web::Data<AppData>, seq: SeqName, caller: Caller) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Read) {
+        return forbidden;
+    }
+
+    match data.seq_provider.sequence_forecast(seq.0).await {
+        Ok(forecast) => HttpResponse::Ok()
+            .content_type(JSON_CONTENT_TYPE)
+            .body(serde_json::to_string(&forecast).unwrap()),
+        Err(err @ EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(_))) =>
+            HttpResponse::NotFound().body(format!("Error: '{:?}'", err)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to forecast sequence: '{:?}'", err))
+    }
+}
+
+// {} if the sequence has no policy
+#[get("/sequence/{seq}/policy")]
+pub async fn get_seq_policy(data: web::Data<AppData>, seq: SeqName, caller: Caller) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Read) {
+        return forbidden;
+    }
+
+    match data.seq_provider.sequence_policy(seq.0).await {
+        Ok(policy) => HttpResponse::Ok()
+            .content_type(JSON_CONTENT_TYPE)
+            .body(serde_json::to_string(&policy.unwrap_or_default()).unwrap()),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to get sequence policy: '{:?}'", err))
+    }
+}
+
+// replaces the whole policy, {} removes it
+#[put("/sequence/{seq}/policy")]
+pub async fn put_seq_policy(data: web::Data<AppData>, seq: SeqName, caller: Caller, req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Some(forbidden) = caller.forbidden(&seq.0, Permission::Admin) {
+        return forbidden;
+    }
+
+    let policy: SeqPolicy = match serde_json::from_slice(&body) {
+        Ok(policy) => policy,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Bad policy: '{}'", err)),
+    };
+
+    match data.seq_provider.set_sequence_policy(seq.0.clone(), policy.clone()).await {
+        Ok((old_policy, revision)) => {
+            let (old_value, new_value) = (policy_value(&old_policy), policy_value(&policy));
+            data.audit.record(AuditEntry::of_request(Operation::Policy, &seq.0, &caller, &req)
+                .with_values(old_value, new_value, revision)).await;
+
+            HttpResponse::Ok()
+                .content_type(JSON_CONTENT_TYPE)
+                .body(serde_json::to_string(&policy).unwrap())
+        }
+        Err(RangeProviderErr::Validation(err)) =>
+            HttpResponse::BadRequest().body(format!("Bad policy: '{}'", err)),
+        Err(err @ RangeProviderErr::Etcd(EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(_)))) =>
+            HttpResponse::NotFound().body(format!("Error: '{:?}'", err)),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to set sequence policy: '{:?}'", err))
+    }
+}
+
+
+// exports sequences the caller may read
+#[get("/admin/export")]
+pub async fn export_seqs(data: web::Data<AppData>, caller: Caller, query: web::Query<ExportQuery>) -> impl Responder {
+    let (revision, sequences) = match data.seq_provider.list_sequences().await {
+        Ok(seqs) => seqs,
+        Err(err) =>
+            return HttpResponse::InternalServerError().body(format!("Unable to export sequences: '{:?}'", err))
+    };
+
+    let sequences = sequences.into_iter()
+        .filter(|s| caller.is_allowed(&s.name, Permission::Read))
+        .collect();
+
+    let snapshot = Snapshot::new(revision, sequences);
+
+    match query.format {
+        SnapshotFormat::Json => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&snapshot).unwrap()),
+
+        SnapshotFormat::Ndjson => HttpResponse::Ok()
+            .content_type(NDJSON_CONTENT_TYPE)
+            .body(snapshot.to_ndjson()),
+    }
+}
+
+// Body is a snapshot produced by export, NDJSON ones must be sent with NDJSON content type.
+// Sequences are imported one by one, the response tells what happened to each of them.
+// Served at POST /admin/import, registered by hand to allow bigger bodies than other endpoints
+pub async fn import_seqs(data: web::Data<AppData>, caller: Caller, query: web::Query<ImportQuery>, req: HttpRequest, body: web::Bytes) -> impl Responder {
+    let is_ndjson = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with(NDJSON_CONTENT_TYPE));
+
+    let snapshot = if is_ndjson { Snapshot::from_ndjson(&body) } else { Snapshot::from_json(&body) };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Bad snapshot: '{:?}'", err)),
+    };
+
+    let mut results = Vec::with_capacity(snapshot.sequences.len());
+
+    for seq in &snapshot.sequences {
+        let outcome = if !is_seq_name(&seq.name) {
+            ImportOutcome::Rejected { reason: "Not a valid sequence name".to_string() }
+        } else if !caller.is_allowed(&seq.name, Permission::Admin) {
+            ImportOutcome::Rejected { reason: format!("Key '{}' has no Admin permission", caller.name()) }
+        } else {
+            match data.seq_provider.import_sequence(seq, query.policy).await {
+                Ok((outcome, revision)) => {
+                    if let Some(old_value) = outcome.imported_over() {
+                        data.audit.record(AuditEntry::of_request(Operation::Import, &seq.name, &caller, &req)
+                            .with_values(old_value.map(Into::into), Some(seq.value.into()), revision)).await;
+                    }
+                    outcome
+                }
+                Err(err) => ImportOutcome::Rejected { reason: format!("{:?}", err) },
+            }
+        };
+
+        results.push(ImportResult { name: seq.name.clone(), outcome });
+    }
+
+    HttpResponse::Ok().body(serde_json::to_string(&results).unwrap())
+}
+
+// Entries about sequences the caller may administer, oldest first. Served from etcd,
+// so only if audit.store_in_etcd is on
+#[get("/admin/audit")]
+pub async fn get_audit(data: web::Data<AppData>, caller: Caller, query: web::Query<AuditQuery>) -> impl Responder {
+    if !data.audit.stores_in_etcd() {
+        return HttpResponse::NotFound().body("Audit entries are not stored in etcd, see audit.store_in_etcd. They are in the audit log file");
+    }
+
+    if query.limit == 0 {
+        return HttpResponse::BadRequest().body("limit must be greater than 0");
+    }
+
+    let page = data.audit.query(&query.after, query.limit.min(MAX_AUDIT_LIMIT), |entry| {
+        caller.is_allowed(&entry.sequence, Permission::Admin)
+            && query.sequence.as_ref().is_none_or(|seq| *seq == entry.sequence)
+    }).await;
+
+    match page {
+        Ok(page) => HttpResponse::Ok()
+            .content_type(JSON_CONTENT_TYPE)
+            .body(serde_json::to_string(&page).unwrap()),
+        Err(err) =>
+            HttpResponse::InternalServerError().body(format!("Unable to read audit entries: '{:?}'", err))
+    }
+}
+
+
+fn ranges_response(format: Format, expand: bool, ranges: Vec<Range>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.content_type(format.content_type());
+
+    if expand {
+        response.streaming(format.stream_ids(ranges))
+    } else {
+        response.body(format.encode_ranges(&ranges))
+    }
+}
+
+// an empty policy is no policy
+fn policy_value(policy: &SeqPolicy) -> Option<serde_json::Value> {
+    (!policy.is_empty()).then(|| serde_json::to_value(policy).unwrap())
+}
+
+fn prefers_json(req: &HttpRequest) -> bool {
+    let accept = match header::Accept::parse(req) {
+        Ok(accept) => accept,
+        Err(_) => return false,
+    };
+
+    accept.ranked().iter()
+        .find(|mime| matches!(mime.essence_str(), JSON_CONTENT_TYPE | "text/plain" | "text/*" | "*/*"))
+        .is_some_and(|mime| mime.essence_str() == JSON_CONTENT_TYPE)
+}
+
+fn not_acceptable() -> HttpResponse {
+    HttpResponse::NotAcceptable().body(format!("Ranges are served as one of: {}",
+        [Format::Json, Format::MsgPack, Format::Cbor, Format::Binary].map(|f| f.content_type()).join(", ")))
+}
+
+fn rate_limited(data: &AppData, caller: &Caller, req: &HttpRequest, seq_name: &str, size: u64) -> Option<HttpResponse> {
+    let wait = data.rate_limiter.check(&data.rate_limiter.client_identity(caller, req), seq_name, size).err()?;
+
+    Some(HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, wait.as_secs_f64().ceil().to_string()))
+        .body(format!("Rate limit for sequence '{}' exceeded", seq_name)))
+}
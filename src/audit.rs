@@ -0,0 +1,192 @@
+/*
+    Audit log of administrative operations: creation, deletion, reset and advance of sequences,
+    policy changes and imports.
+
+    Every entry is logged as a JSON line to the 'audit' target, which logs.yaml sends to its own
+    file. With audit.store_in_etcd entries are also kept in etcd at meta/audit/{id}, so entries of
+    all instances are in one place and GET /admin/audit can serve them. Ids start with unix
+    nanoseconds of the entry, so keys sort by time, and end with the instance that wrote it.
+
+    Entries are written after the operation succeeds. A failure to store one in etcd is logged
+    and doesn't fail the operation, the entry is still in the log file.
+ */
+
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::HttpRequest;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::auth::Caller;
+use crate::config::AuditProps;
+use crate::etcd_client::{EtcdClient, EtcdErr, SeqWrite};
+
+const AUDIT_KEY_PREFIX: &str = "audit/";
+
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Create,
+    Delete,
+    Reset,
+    Advance,
+    Policy,
+    Import,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    // unix milliseconds
+    pub at: u64,
+    pub operation: Operation,
+    pub sequence: String,
+    // name of the API key, or anonymous
+    pub caller: String,
+    pub source_ip: Option<String>,
+    // the client address proxies reported, if it differs from source_ip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    // sequence value, or policy for policy changes. None if there was none
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+    // etcd revision of the write, None if nothing was written
+    pub revision: Option<u64>,
+}
+
+impl AuditEntry {
+    pub fn new(operation: Operation, sequence: &str, caller: &Caller, source_ip: Option<IpAddr>) -> Self {
+        Self {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            operation,
+            sequence: sequence.to_string(),
+            caller: caller.name().to_string(),
+            source_ip: source_ip.map(|ip| ip.to_string()),
+            forwarded_for: None,
+            old_value: None,
+            new_value: None,
+            revision: None,
+        }
+    }
+
+    /// Entry of an operation made directly in etcd by a local user, e.g. through ngamahi-ctl --etcd
+    pub fn of_local_user(operation: Operation, sequence: &str, user: &str) -> Self {
+        let mut entry = Self::new(operation, sequence, &Caller::Anonymous, None);
+        entry.caller = format!("local:{}", user);
+        entry
+    }
+
+    pub fn of_request(operation: Operation, sequence: &str, caller: &Caller, req: &HttpRequest) -> Self {
+        let source_ip = req.peer_addr().map(|a| a.ip());
+        let mut entry = Self::new(operation, sequence, caller, source_ip);
+
+        let real_ip = req.connection_info().realip_remote_addr().map(str::to_string);
+        entry.forwarded_for = real_ip.filter(|ip| Some(ip.as_str()) != entry.source_ip.as_deref());
+        entry
+    }
+
+    pub fn with_write(self, write: SeqWrite) -> Self {
+        self.with_values(write.old_value.map(Value::from), write.value.map(Value::from), write.revision)
+    }
+
+    pub fn with_values(mut self, old_value: Option<Value>, new_value: Option<Value>, revision: Option<u64>) -> Self {
+        self.old_value = old_value;
+        self.new_value = new_value;
+        self.revision = revision;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<StoredEntry>,
+    // pass as `after` to get the next page, None if there are no more entries
+    pub next: Option<String>,
+}
+
+
+#[derive(Clone)]
+pub struct Auditor {
+    props: AuditProps,
+    instance_id: String,
+    etcd: EtcdClient,
+}
+
+impl Auditor {
+    pub fn new(props: AuditProps, instance_id: String, etcd: EtcdClient) -> Self {
+        Self { props, instance_id, etcd }
+    }
+
+    pub fn stores_in_etcd(&self) -> bool {
+        self.props.store_in_etcd
+    }
+
+    pub async fn record(&self, entry: AuditEntry) {
+        let json = serde_json::to_string(&entry).unwrap();
+        info!(target: "audit", "{}", json);
+
+        if !self.props.store_in_etcd {
+            return;
+        }
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        let key = format!("{}{:020}-{}", AUDIT_KEY_PREFIX, nanos, self.instance_id);
+
+        if let Err(err) = self.etcd.put_meta(&key, json.as_bytes()).await {
+            warn!("Couldn't store audit entry in etcd: {:?}, entry: {}", err, json);
+        }
+    }
+
+    /// Up to `limit` entries with ids after `after`, oldest first. Entries the filter rejects
+    /// count against the limit, so a page may hold fewer entries even if there are more
+    pub async fn query(&self, after: &str, limit: u64, filter: impl Fn(&AuditEntry) -> bool) -> Result<AuditPage, EtcdErr> {
+        let values = self.etcd.list_meta_after(AUDIT_KEY_PREFIX, after, limit).await?;
+        let next = values.last().filter(|_| values.len() as u64 >= limit).map(|(id, _)| id.clone());
+
+        let entries = values.into_iter()
+            .filter_map(|(id, value)| match serde_json::from_slice(&value) {
+                Ok(entry) => Some(StoredEntry { id, entry }),
+                Err(err) => {
+                    warn!("Audit entry '{}' in etcd is malformed and is skipped: {}", id, err);
+                    None
+                }
+            })
+            .filter(|stored| filter(&stored.entry))
+            .collect();
+
+        Ok(AuditPage { entries, next })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::Caller;
+    use crate::etcd_client::SeqWrite;
+    use super::{AuditEntry, Operation};
+
+    #[test]
+    fn entries_carry_values_of_writes() {
+        let write = SeqWrite { old_value: Some(10), value: None, revision: Some(42) };
+        let entry = AuditEntry::new(Operation::Delete, "orders", &Caller::Anonymous, "10.0.0.1".parse().ok())
+            .with_write(write);
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!("delete", json["operation"]);
+        assert_eq!("anonymous", json["caller"]);
+        assert_eq!("10.0.0.1", json["source_ip"]);
+        assert_eq!(10, json["old_value"]);
+        assert!(json["new_value"].is_null());
+        assert_eq!(42, json["revision"]);
+        assert!(json.get("forwarded_for").is_none());
+
+        assert_eq!(entry, serde_json::from_value(json).unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+/*
+    API key authentication and per-sequence access control.
+
+    Clients send their key as "Authorization: Bearer <key>" (or in "X-Api-Key" header).
+    The middleware resolves the key to a Caller and stores it in request extensions,
+    endpoints then check caller's grants against the sequence they touch.
+ */
+
+use std::future::{ready, Ready};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use crate::AppData;
+use crate::config::{ApiKeyProps, AuthProps, GrantProps, Permission};
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+
+/// Whoever made the request
+#[derive(Clone, Debug)]
+pub enum Caller {
+    // authentication is turned off, everything is allowed
+    Anonymous,
+    ApiKey { name: String, grants: Vec<GrantProps> },
+}
+
+impl Caller {
+    pub fn name(&self) -> &str {
+        match self {
+            Caller::Anonymous => "anonymous",
+            Caller::ApiKey { name, .. } => name,
+        }
+    }
+
+    pub fn is_allowed(&self, seq_name: &str, permission: Permission) -> bool {
+        match self {
+            Caller::Anonymous => true,
+            Caller::ApiKey { grants, .. } => grants.iter().any(|g|
+                g.permissions.contains(&permission) && matches_pattern(&g.pattern, seq_name)),
+        }
+    }
+
+    /// Returns ready to send 403 response if the caller lacks given permission
+    pub fn forbidden(&self, seq_name: &str, permission: Permission) -> Option<HttpResponse> {
+        if self.is_allowed(seq_name, permission) {
+            return None
+        }
+
+        Some(HttpResponse::Forbidden().body(
+            format!("Key '{}' has no {:?} permission for sequence '{}'", self.name(), permission, seq_name)))
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // the middleware puts a caller in extensions unless authentication is off
+        let caller = req.extensions().get::<Caller>().cloned().unwrap_or(Caller::Anonymous);
+        ready(Ok(caller))
+    }
+}
+
+
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let data = req.app_data::<Data<AppData>>().cloned();
+
+    let auth = match data.as_ref().and_then(|d| d.auth.as_ref()) {
+        Some(auth) => auth,
+        None => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+
+    match resolve_caller(Some(auth), request_key(&req)) {
+        Some(caller) => {
+            req.extensions_mut().insert(caller);
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+
+        None => {
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .body("Missing or unknown API key");
+
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+/// Caller with the given key, None if authentication is on and the key is missing or unknown
+pub fn resolve_caller(auth: Option<&AuthProps>, key: Option<&str>) -> Option<Caller> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Some(Caller::Anonymous),
+    };
+
+    key.and_then(|key| find_key(&auth.api_keys, key))
+        .map(|key| Caller::ApiKey { name: key.name.clone(), grants: key.grants.clone() })
+}
+
+fn request_key(req: &ServiceRequest) -> Option<&str> {
+    let headers = req.headers();
+
+    if let Some(bearer) = headers.get(header::AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+        return bearer.strip_prefix("Bearer ").map(str::trim);
+    }
+
+    headers.get(API_KEY_HEADER).and_then(|h| h.to_str().ok()).map(str::trim)
+}
+
+// all keys are compared so that response time doesn't tell which key is closer to the given one
+fn find_key<'a>(api_keys: &'a [ApiKeyProps], key: &str) -> Option<&'a ApiKeyProps> {
+    api_keys.iter().fold(None, |found, k| {
+        if constant_time_eq(k.key.as_bytes(), key.as_bytes()) { Some(k) } else { found }
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0_u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Glob-like matching where '*' stands for any (possibly empty) sequence of characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // pattern without '*' must match exactly
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let middle: Vec<&str> = parts.collect();
+    let last = match middle.last() {
+        Some(last) => *last,
+        None => return rest.is_empty(),
+    };
+
+    for part in &middle[..middle.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::matches_pattern;
+
+    #[test]
+    fn pattern_matching() {
+        assert!(matches_pattern("*", "orders"));
+        assert!(matches_pattern("orders", "orders"));
+        assert!(!matches_pattern("orders", "orders-eu"));
+        assert!(matches_pattern("orders-*", "orders-eu"));
+        assert!(matches_pattern("tenants/acme/*", "tenants/acme/users"));
+        assert!(!matches_pattern("tenants/acme/*", "tenants/other/users"));
+        assert!(matches_pattern("*-eu-*", "orders-eu-1"));
+        assert!(!matches_pattern("a*b*c", "abca-"));
+    }
+}
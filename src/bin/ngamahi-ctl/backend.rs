@@ -0,0 +1,336 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use ngamahi_id_gen::audit::{AuditEntry, Auditor, Operation};
+use ngamahi_id_gen::config::AuditProps;
+use ngamahi_id_gen::etcd_client::{EtcdClient, EtcdErr, SeqState, SeqWrite};
+use ngamahi_id_gen::leader::default_instance_id;
+use ngamahi_id_gen::range::{is_seq_name, tenant_seq_name};
+use ngamahi_id_gen::snapshot::{ImportOutcome, ImportPolicy, ImportResult, Snapshot, SnapshotErr};
+
+
+pub enum Backend {
+    Http(HttpBackend),
+    Etcd(EtcdBackend),
+}
+
+/// Writes to etcd directly. Changes are recorded in the audit log in etcd as made by the local OS user,
+/// as there is no server to do it
+pub struct EtcdBackend {
+    etcd: EtcdClient,
+    audit: Auditor,
+    user: String,
+}
+
+pub struct HttpBackend {
+    client: awc::Client,
+    server: String,
+    api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ValueChange {
+    old_value: u64,
+    value: u64,
+}
+
+#[derive(Deserialize)]
+struct SeqList {
+    revision: u64,
+    sequences: Vec<SeqState>,
+}
+
+
+impl Backend {
+    pub async fn create(&self, tenant: Option<&str>, seq: &str) -> Result<(), CtlErr> {
+        match self {
+            Backend::Http(http) => http.send(awc::http::Method::POST, &seq_path(tenant, seq)).await.map(|_| ()),
+            Backend::Etcd(backend) => {
+                let seq_name = seq_name(tenant, seq);
+                let write = backend.etcd.create_seq(seq_name.clone()).await?;
+                backend.record(Operation::Create, &seq_name, write).await;
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn list(&self, tenant: Option<&str>) -> Result<Vec<SeqState>, CtlErr> {
+        let (_, seqs) = self.list_all().await?;
+
+        // both endpoints return all sequences, tenant ones are named tenants/{tenant}/{seq}
+        let seqs = match tenant {
+            Some(tenant) => {
+                let prefix = tenant_seq_name(tenant, "");
+                seqs.into_iter().filter(|s| s.name.starts_with(&prefix)).collect()
+            }
+            None => seqs,
+        };
+
+        Ok(seqs)
+    }
+
+    pub async fn inspect(&self, tenant: Option<&str>, seq: &str) -> Result<SeqState, CtlErr> {
+        match self {
+            Backend::Http(http) => http.get_json(&(seq_path(tenant, seq) + "/info")).await,
+            Backend::Etcd(backend) => Ok(backend.etcd.get_seq(seq_name(tenant, seq)).await?),
+        }
+    }
+
+    pub async fn reset(&self, tenant: Option<&str>, seq: &str, expected: u64, to: u64) -> Result<(), CtlErr> {
+        match self {
+            Backend::Http(http) => {
+                let path = format!("{}/reset?expected={}&value={}&confirm=true", seq_path(tenant, seq), expected, to);
+                http.send(awc::http::Method::POST, &path).await.map(|_| ())
+            }
+            Backend::Etcd(backend) => {
+                let seq_name = seq_name(tenant, seq);
+                let write = backend.etcd.reset_seq(seq_name.clone(), expected, to).await?;
+                backend.record(Operation::Reset, &seq_name, write).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the value before and after the call
+    pub async fn advance(&self, tenant: Option<&str>, seq: &str, min: u64) -> Result<(u64, u64), CtlErr> {
+        match self {
+            Backend::Http(http) => {
+                let change: ValueChange = http.get_json_with(
+                    awc::http::Method::POST, &format!("{}/advance?min={}", seq_path(tenant, seq), min)).await?;
+                Ok((change.old_value, change.value))
+            }
+            Backend::Etcd(backend) => {
+                let seq_name = seq_name(tenant, seq);
+                let write = backend.etcd.advance_seq(seq_name.clone(), min).await?;
+                backend.record(Operation::Advance, &seq_name, write).await;
+                Ok((write.old_value.unwrap_or_default(), write.value.unwrap_or_default()))
+            }
+        }
+    }
+
+    pub async fn delete(&self, tenant: Option<&str>, seq: &str) -> Result<bool, CtlErr> {
+        match self {
+            Backend::Http(http) => match http.send(awc::http::Method::DELETE, &seq_path(tenant, seq)).await {
+                Ok(_) => Ok(true),
+                Err(CtlErr::Http { status: 404, .. }) => Ok(false),
+                Err(err) => Err(err),
+            },
+            Backend::Etcd(backend) => {
+                let seq_name = seq_name(tenant, seq);
+                let write = backend.etcd.delete_seq(seq_name.clone()).await?;
+                if write.revision.is_some() {
+                    backend.record(Operation::Delete, &seq_name, write).await;
+                }
+                Ok(write.revision.is_some())
+            }
+        }
+    }
+
+    pub async fn export(&self, tenant: Option<&str>) -> Result<Snapshot, CtlErr> {
+        let mut snapshot = match self {
+            Backend::Http(http) => Snapshot::from_json(http.send(awc::http::Method::GET, "/admin/export").await?.as_bytes())?,
+            Backend::Etcd(_) => {
+                let (revision, seqs) = self.list_all().await?;
+                Snapshot::new(revision, seqs)
+            }
+        };
+
+        if let Some(tenant) = tenant {
+            let prefix = tenant_seq_name(tenant, "");
+            snapshot.sequences.retain(|s| s.name.starts_with(&prefix));
+        }
+
+        Ok(snapshot)
+    }
+
+    pub async fn import(&self, snapshot: Snapshot, policy: ImportPolicy) -> Result<Vec<ImportResult>, CtlErr> {
+        let backend = match self {
+            Backend::Http(http) => {
+                let path = format!("/admin/import?policy={}", serde_json::to_string(&policy)?.trim_matches('"'));
+                let body = http.send_body(awc::http::Method::POST, &path, serde_json::to_string(&snapshot)?).await?;
+
+                return Ok(serde_json::from_str(&body)?);
+            }
+            Backend::Etcd(backend) => backend,
+        };
+
+        let mut results = Vec::with_capacity(snapshot.sequences.len());
+
+        for seq in snapshot.sequences {
+            let outcome = if is_seq_name(&seq.name) {
+                let (outcome, revision) = backend.etcd.import_seq(seq.name.clone(), seq.value, policy).await?;
+                if let Some(old_value) = outcome.imported_over() {
+                    backend.record(Operation::Import, &seq.name, SeqWrite { old_value, value: Some(seq.value), revision }).await;
+                }
+                outcome
+            } else {
+                ImportOutcome::Rejected { reason: "Not a valid sequence name".to_string() }
+            };
+
+            results.push(ImportResult { name: seq.name, outcome });
+        }
+
+        Ok(results)
+    }
+
+    async fn list_all(&self) -> Result<(u64, Vec<SeqState>), CtlErr> {
+        match self {
+            Backend::Http(http) => {
+                let list: SeqList = http.get_json("/sequences").await?;
+                Ok((list.revision, list.sequences))
+            }
+            Backend::Etcd(backend) => {
+                let (revision, seqs) = backend.etcd.list_seqs("".to_string()).await?;
+                Ok((revision, seqs.into_iter().filter(|s| is_seq_name(&s.name)).collect()))
+            }
+        }
+    }
+}
+
+
+impl EtcdBackend {
+    pub fn new(etcd: EtcdClient) -> Self {
+        let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "unknown".to_string());
+        let audit = Auditor::new(AuditProps { store_in_etcd: true }, format!("ngamahi-ctl-{}", default_instance_id()), etcd.clone());
+
+        Self { etcd, audit, user }
+    }
+
+    async fn record(&self, operation: Operation, seq_name: &str, write: SeqWrite) {
+        self.audit.record(AuditEntry::of_local_user(operation, seq_name, &self.user).with_write(write)).await;
+    }
+}
+
+
+impl HttpBackend {
+    pub fn new(server: String, api_key: Option<String>) -> Self {
+        Self { client: awc::Client::default(), server, api_key }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, CtlErr> {
+        self.get_json_with(awc::http::Method::GET, path).await
+    }
+
+    async fn get_json_with<T: DeserializeOwned>(&self, method: awc::http::Method, path: &str) -> Result<T, CtlErr> {
+        let body = self.send(method, path).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn send(&self, method: awc::http::Method, path: &str) -> Result<String, CtlErr> {
+        self.send_body(method, path, String::new()).await
+    }
+
+    async fn send_body(&self, method: awc::http::Method, path: &str, body: String) -> Result<String, CtlErr> {
+        let mut req = self.client.request(method, self.server.clone() + path)
+            .insert_header(("Content-Type", "application/json"));
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+
+        let mut res = req.send_body(body).await.map_err(|e| CtlErr::Request(format!("{:?}", e)))?;
+        let body = res.body().limit(64 * 1024 * 1024).await.map_err(|e| CtlErr::Request(format!("{:?}", e)))?;
+        let body = String::from_utf8_lossy(&body).to_string();
+
+        if !res.status().is_success() {
+            return Err(CtlErr::Http { status: res.status().as_u16(), body });
+        }
+
+        Ok(body)
+    }
+}
+
+
+fn seq_name(tenant: Option<&str>, seq: &str) -> String {
+    match tenant {
+        Some(tenant) => tenant_seq_name(tenant, seq),
+        None => seq.to_string(),
+    }
+}
+
+fn seq_path(tenant: Option<&str>, seq: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("/tenants/{}/sequence/{}", encode_path_segment(tenant), encode_path_segment(seq)),
+        None => format!("/sequence/{}", encode_path_segment(seq)),
+    }
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+
+// =========| ERRORS |=========
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum CtlErr {
+    Etcd(EtcdErr),
+    Request(String),
+    Http { status: u16, body: String },
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Snapshot(SnapshotErr),
+}
+
+impl From<SnapshotErr> for CtlErr {
+    fn from(value: SnapshotErr) -> Self {
+        Self::Snapshot(value)
+    }
+}
+
+impl From<EtcdErr> for CtlErr {
+    fn from(value: EtcdErr) -> Self {
+        Self::Etcd(value)
+    }
+}
+
+impl From<std::io::Error> for CtlErr {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for CtlErr {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+
+#[cfg(test)]
+#[path = "../../../tests/fake_etcd/mod.rs"]
+mod fake_etcd;
+
+#[cfg(test)]
+mod tests {
+    use ngamahi_id_gen::audit::Operation;
+    use ngamahi_id_gen::etcd_client;
+    use super::fake_etcd::FakeEtcd;
+    use super::{Backend, EtcdBackend};
+
+    #[actix_web::test]
+    async fn direct_changes_are_audited_in_etcd() {
+        let etcd = FakeEtcd::start();
+        let client = etcd_client::new_etcd_client(etcd_client::new_http_client(awc::Client::default()), etcd.url(), "ids/".to_string());
+        let backend = Backend::Etcd(EtcdBackend::new(client));
+
+        backend.create(Some("acme"), "orders").await.unwrap();
+        backend.advance(Some("acme"), "orders", 50).await.unwrap();
+        backend.delete(Some("acme"), "orders").await.unwrap();
+
+        let Backend::Etcd(direct) = &backend else { unreachable!() };
+        let page = direct.audit.query("", 10, |_| true).await.unwrap();
+        let entries: Vec<_> = page.entries.iter().map(|e| (e.entry.operation, e.entry.new_value.clone())).collect();
+
+        assert_eq!(vec![(Operation::Create, Some(0.into())), (Operation::Advance, Some(50.into())), (Operation::Delete, None)], entries);
+        assert!(page.entries.iter().all(|e| e.entry.sequence == "tenants/acme/orders" && e.entry.caller.starts_with("local:") && e.entry.source_ip.is_none()));
+    }
+}
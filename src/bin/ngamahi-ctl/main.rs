@@ -0,0 +1,231 @@
+/*
+    Command-line admin tool for sequences.
+
+    It talks either to id server's HTTP API (--server) or directly to etcd (--etcd),
+    the latter decodes etcd values itself, so no base64 and byte order juggling is needed.
+    Changes made directly in etcd are recorded in the audit log in etcd, see audit.rs.
+ */
+
+mod backend;
+mod output;
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use ngamahi_id_gen::etcd_client;
+use ngamahi_id_gen::snapshot::{ImportPolicy, Snapshot};
+use crate::backend::{Backend, CtlErr, EtcdBackend, HttpBackend};
+
+
+#[derive(Parser)]
+#[command(name = "ngamahi-ctl", about = "Manage sequences of ngamahi id server")]
+struct Cli {
+    /// Base url of id server, e.g. http://localhost:8080
+    #[arg(long, required_unless_present = "etcd", conflicts_with = "etcd")]
+    server: Option<String>,
+
+    /// API key sent to id server
+    #[arg(long, requires = "server")]
+    api_key: Option<String>,
+
+    /// Url of etcd, e.g. http://localhost:2379. Sequences are managed bypassing id server
+    #[arg(long)]
+    etcd: Option<String>,
+
+    /// Key prefix the id server is configured with
+    #[arg(long, default_value = "", requires = "etcd")]
+    key_prefix: String,
+
+    /// Work with sequences of given tenant
+    #[arg(long)]
+    tenant: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a sequence starting from 0
+    Create { seq: String },
+
+    /// List all sequences
+    List,
+
+    /// Show current value and revisions of a sequence
+    Inspect { seq: String },
+
+    /// Set value of a sequence if it currently equals to the expected one
+    Reset {
+        seq: String,
+
+        #[arg(long)]
+        to: u64,
+
+        #[arg(long)]
+        expected: u64,
+    },
+
+    /// Move a sequence to at least the given value, e.g. above ids already used elsewhere
+    Advance {
+        seq: String,
+
+        #[arg(long)]
+        min: u64,
+    },
+
+    /// Delete a sequence
+    Delete { seq: String },
+
+    /// Write all sequences to a file (or stdout)
+    Export {
+        #[arg(long)]
+        file: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = SnapshotFormat::Json)]
+        format: SnapshotFormat,
+    },
+
+    /// Restore sequences from an export file (or stdin)
+    Import {
+        #[arg(long)]
+        file: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = SnapshotFormat::Json)]
+        format: SnapshotFormat,
+
+        /// What to do with sequences that already exist
+        #[arg(long, value_enum, default_value_t = Policy::Skip)]
+        policy: Policy,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SnapshotFormat {
+    Json,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Policy {
+    /// Leave existing sequences as they are
+    Skip,
+    /// Set imported values, even if it moves sequences backwards
+    Overwrite,
+    /// Set imported values only where they are greater than current ones
+    TakeMax,
+}
+
+impl From<Policy> for ImportPolicy {
+    fn from(value: Policy) -> Self {
+        match value {
+            Policy::Skip => ImportPolicy::Skip,
+            Policy::Overwrite => ImportPolicy::Overwrite,
+            Policy::TakeMax => ImportPolicy::TakeMax,
+        }
+    }
+}
+
+
+#[actix_web::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(err) = run(cli).await {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), CtlErr> {
+    let backend = match (cli.server, cli.etcd) {
+        (Some(server), _) => Backend::Http(HttpBackend::new(server, cli.api_key)),
+        (None, Some(etcd)) => Backend::Etcd(EtcdBackend::new(etcd_client::new_etcd_client(
+            etcd_client::new_http_client(awc::Client::default()), etcd, cli.key_prefix))),
+        (None, None) => unreachable!("clap requires one of --server and --etcd"),
+    };
+
+    let tenant = cli.tenant.as_deref();
+
+    match cli.command {
+        Command::Create { seq } => {
+            backend.create(tenant, &seq).await?;
+            println!("Sequence '{}' created", seq);
+        }
+
+        Command::List => output::print_seqs(cli.output, &backend.list(tenant).await?),
+
+        Command::Inspect { seq } => output::print_seqs(cli.output, &[backend.inspect(tenant, &seq).await?]),
+
+        Command::Reset { seq, to, expected } => {
+            backend.reset(tenant, &seq, expected, to).await?;
+            println!("Sequence '{}' is set to {}", seq, to);
+        }
+
+        Command::Advance { seq, min } => {
+            let (old_value, value) = backend.advance(tenant, &seq, min).await?;
+
+            if old_value == value {
+                println!("Sequence '{}' is already at {}", seq, value);
+            } else {
+                println!("Sequence '{}' advanced from {} to {}", seq, old_value, value);
+            }
+        }
+
+        Command::Delete { seq } => {
+            if backend.delete(tenant, &seq).await? {
+                println!("Sequence '{}' deleted", seq);
+            } else {
+                println!("No such sequence '{}'", seq);
+            }
+        }
+
+        Command::Export { file, format } => {
+            let snapshot = backend.export(tenant).await?;
+
+            match file {
+                Some(path) => write_snapshot(BufWriter::new(File::create(path)?), &snapshot, format)?,
+                None => write_snapshot(std::io::stdout().lock(), &snapshot, format)?,
+            }
+        }
+
+        Command::Import { file, format, policy } => {
+            let mut input = vec![];
+            match file {
+                Some(path) => File::open(path)?.read_to_end(&mut input)?,
+                None => std::io::stdin().lock().read_to_end(&mut input)?,
+            };
+
+            let snapshot = match format {
+                SnapshotFormat::Json => Snapshot::from_json(&input),
+                SnapshotFormat::Ndjson => Snapshot::from_ndjson(&input),
+            }?;
+
+            output::print_import(cli.output, &backend.import(snapshot, policy.into()).await?);
+        }
+    }
+
+    Ok(())
+}
+
+fn write_snapshot(mut out: impl Write, snapshot: &Snapshot, format: SnapshotFormat) -> Result<(), CtlErr> {
+    match format {
+        SnapshotFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, snapshot)?;
+            writeln!(out)?;
+        }
+        SnapshotFormat::Ndjson => out.write_all(snapshot.to_ndjson().as_bytes())?,
+    }
+
+    Ok(out.flush()?)
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use ngamahi_id_gen::etcd_client::SeqState;
+use ngamahi_id_gen::snapshot::{ImportOutcome, ImportResult};
+use crate::Format;
+
+
+pub fn print_seqs(format: Format, seqs: &[SeqState]) {
+    match format {
+        Format::Json => print_json(seqs),
+        Format::Table => print_table(
+            &["NAME", "VALUE", "VERSION", "CREATE_REVISION", "MOD_REVISION"],
+            seqs.iter().map(|s| vec![
+                s.name.clone(),
+                s.value.to_string(),
+                s.version.to_string(),
+                s.create_revision.to_string(),
+                s.mod_revision.to_string(),
+            ]).collect(),
+        ),
+    }
+}
+
+pub fn print_import(format: Format, results: &[ImportResult]) {
+    match format {
+        Format::Json => print_json(results),
+        Format::Table => print_table(
+            &["NAME", "RESULT"],
+            results.iter().map(|r| vec![
+                r.name.clone(),
+                match &r.outcome {
+                    ImportOutcome::Created => "created".to_string(),
+                    ImportOutcome::Updated { old_value } => format!("updated, was {}", old_value),
+                    ImportOutcome::Skipped { current_value } => format!("skipped, current value is {}", current_value),
+                    ImportOutcome::Rejected { reason } => format!("rejected: {}", reason),
+                },
+            ]).collect(),
+        ),
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let widths: Vec<usize> = header.iter().enumerate()
+        .map(|(i, h)| rows.iter().map(|r| r[i].len()).chain([h.len()]).max().unwrap_or(0))
+        .collect();
+
+    let format_row = |cells: Vec<&str>| cells.iter().zip(&widths)
+        .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+        .collect::<Vec<_>>()
+        .join("  ");
+
+    println!("{}", format_row(header.to_vec()).trim_end());
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()).trim_end());
+    }
+}
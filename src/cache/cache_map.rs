@@ -1,32 +1,133 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use crate::range::{get_range_size, Range, split_range};
 
-pub type CacheMap = HashMap<String, Vec<Range>>;
+// idle sequences are looked for at most this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+
+pub struct CacheMap {
+    seqs: HashMap<String, CachedSeq>,
+
+    // ranges dropped to keep the map within limits, until someone takes them
+    evicted: Vec<(String, Range)>,
+    last_sweep: Instant,
+}
+
+struct CachedSeq {
+    // sorted by begin, adjacent ranges are merged
+    ranges: Vec<Range>,
+    last_used: Instant,
+}
+
+/// Bounds of a cache map. None means no bound
+#[derive(Clone, Debug, Default)]
+pub struct CacheLimits {
+    // least recently used sequences are evicted beyond this
+    pub max_sequences: Option<usize>,
+
+    // sequences unused for this long are evicted
+    pub idle_ttl: Option<Duration>,
+
+    // smallest ranges of a sequence are evicted beyond this
+    pub max_fragments: Option<usize>,
+}
+
+impl CacheMap {
+    pub fn new() -> Self {
+        Self { seqs: HashMap::new(), evicted: vec![], last_sweep: Instant::now() }
+    }
+
+    pub fn remove(&mut self, seq_name: &str) {
+        self.seqs.remove(seq_name);
+        self.evicted.retain(|(name, _)| name != seq_name);
+    }
+
+    pub fn clear(&mut self) {
+        self.seqs.clear();
+        self.evicted.clear();
+    }
+
+    pub fn drain_evicted(&mut self) -> Vec<(String, Range)> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    fn evict(&mut self, seq_name: &str) {
+        if let Some(seq) = self.seqs.remove(seq_name) {
+            self.evicted.extend(seq.ranges.into_iter().map(|r| (seq_name.to_string(), r)));
+        }
+    }
+
+    fn sweep(&mut self, limits: &CacheLimits, now: Instant) {
+        let idle_ttl = match limits.idle_ttl {
+            Some(ttl) if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL => ttl,
+            _ => return,
+        };
+
+        self.last_sweep = now;
+
+        let idle: Vec<String> = self.seqs.iter()
+            .filter(|(_, seq)| now.duration_since(seq.last_used) >= idle_ttl)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for seq_name in idle {
+            self.evict(&seq_name);
+        }
+    }
+}
+
+impl Default for CacheMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
 pub fn new() -> CacheMap {
-    HashMap::new()
+    CacheMap::new()
 }
 
 
-pub fn store_range(seq_name: String, range: Range, map: &mut CacheMap) -> () {
-    let mut default = Vec::<Range>::new();
-    let mut ranges = map.get_mut(&seq_name);
+pub fn store_range(seq_name: String, range: Range, map: &mut CacheMap, limits: &CacheLimits) {
+    let now = Instant::now();
+    map.sweep(limits, now);
+
+    if !map.seqs.contains_key(&seq_name) {
+        while limits.max_sequences.is_some_and(|max| map.seqs.len() >= max) {
+            let lru = map.seqs.iter().min_by_key(|(_, seq)| seq.last_used).map(|(name, _)| name.clone());
+
+            match lru {
+                Some(lru) => map.evict(&lru),
+                None => break,
+            }
+        }
+    }
+
+    let seq = map.seqs.entry(seq_name.clone()).or_insert_with(|| CachedSeq { ranges: vec![], last_used: now });
+    seq.last_used = now;
+
+    merge_into(&mut seq.ranges, range);
 
-    if let Some(ranges) = ranges {
-        ranges.push(range);
-    } else {
-        default.push(range);
-        map.insert(seq_name, default);
+    while limits.max_fragments.is_some_and(|max| seq.ranges.len() > max) {
+        let (smallest, _) = seq.ranges.iter().enumerate().min_by_key(|(_, r)| get_range_size(r)).unwrap();
+        map.evicted.push((seq_name.clone(), seq.ranges.remove(smallest)));
     }
 }
 
-pub fn get_range(seq_name: String, range_size: u64, map: &mut CacheMap) -> (Vec<Range>, u64) {
-    let mut ranges = match map.get_mut(&seq_name) {
-        Some(r) => r,
+pub fn get_range(seq_name: String, range_size: u64, map: &mut CacheMap, limits: &CacheLimits) -> (Vec<Range>, u64) {
+    let now = Instant::now();
+    map.sweep(limits, now);
+
+    let seq = match map.seqs.get_mut(&seq_name) {
+        Some(seq) => seq,
         None => return (vec![], range_size)
     };
 
+    seq.last_used = now;
+    let ranges = &mut seq.ranges;
+
     let mut result = Vec::with_capacity(2);
 
     // sum size of already taken ranges
@@ -35,18 +136,17 @@ pub fn get_range(seq_name: String, range_size: u64, map: &mut CacheMap) -> (Vec<
     loop {
         let needed_size = range_size - total;
 
-        // no need for another range
-        if needed_size == 0 {
-            return (result, needed_size);
-        }
+        // no need for another range or not enough ranges in cache
+        if needed_size == 0 || ranges.is_empty() {
+            if ranges.is_empty() {
+                map.seqs.remove(&seq_name);
+            }
 
-        // not enough ranges in cache
-        if ranges.len() == 0 {
             return (result, needed_size);
         }
 
 
-        let next_range = ranges.swap_remove(0);
+        let next_range = ranges.remove(0);
         let range_size = get_range_size(&next_range);
 
         match needed_size.cmp(&range_size) {
@@ -57,7 +157,7 @@ pub fn get_range(seq_name: String, range_size: u64, map: &mut CacheMap) -> (Vec<
 
                 let (left, right) = split_range(next_range, needed_size).unwrap();
                 result.push(left);
-                ranges.push(right);
+                ranges.insert(0, right);
             }
 
             // if cache contains a range that is smaller than needed, we take it and remember
@@ -75,4 +175,56 @@ pub fn get_range(seq_name: String, range_size: u64, map: &mut CacheMap) -> (Vec<
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// keeps ranges sorted and merges adjacent ones, e.g. consecutive fetches from etcd
+fn merge_into(ranges: &mut Vec<Range>, range: Range) {
+    let i = ranges.partition_point(|r| r.begin < range.begin);
+    ranges.insert(i, range);
+
+    if i + 1 < ranges.len() && ranges[i].end == ranges[i + 1].begin {
+        ranges[i].end = ranges.remove(i + 1).end;
+    }
+
+    if i > 0 && ranges[i - 1].end == ranges[i].begin {
+        ranges[i - 1].end = ranges.remove(i).end;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::range::Range;
+    use super::{CacheLimits, CacheMap, get_range, store_range};
+
+    #[test]
+    fn adjacent_ranges_are_merged_and_fragments_bounded() {
+        let limits = CacheLimits { max_fragments: Some(2), ..Default::default() };
+        let mut map = CacheMap::new();
+
+        store_range("a".to_string(), Range::new(100, 200), &mut map, &limits);
+        store_range("a".to_string(), Range::new(0, 100), &mut map, &limits);
+        assert_eq!(vec![Range::new(0, 200)], map.seqs["a"].ranges);
+
+        store_range("a".to_string(), Range::new(300, 310), &mut map, &limits);
+        store_range("a".to_string(), Range::new(400, 450), &mut map, &limits);
+        assert_eq!(vec![Range::new(0, 200), Range::new(400, 450)], map.seqs["a"].ranges);
+        assert_eq!(vec![("a".to_string(), Range::new(300, 310))], map.drain_evicted());
+    }
+
+    #[test]
+    fn least_recently_used_sequence_is_evicted() {
+        let limits = CacheLimits { max_sequences: Some(2), idle_ttl: Some(Duration::from_secs(3600)), ..Default::default() };
+        let mut map = CacheMap::new();
+
+        store_range("a".to_string(), Range::new(0, 100), &mut map, &limits);
+        store_range("b".to_string(), Range::new(0, 100), &mut map, &limits);
+        std::thread::sleep(Duration::from_millis(1));
+        get_range("a".to_string(), 1, &mut map, &limits);
+        store_range("c".to_string(), Range::new(0, 100), &mut map, &limits);
+
+        assert!(map.seqs.contains_key("a") && map.seqs.contains_key("c"));
+        assert_eq!(vec![("b".to_string(), Range::new(0, 100))], map.drain_evicted());
+    }
+}
@@ -0,0 +1,73 @@
+/*
+    This cache is intended to be common for multiple threads.
+
+    Sequences are spread over shards by hash of their names, each shard is a map behind its own
+    mutex. Locks are held only for a map lookup, so callers never wait on each other for long
+    and there is no thread to talk to.
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use crate::cache::cache_map::{self, CacheLimits, CacheMap};
+use crate::cache::invalidation;
+use crate::range::Range;
+
+pub(in crate::cache) const SHARDS: usize = 16;
+
+
+pub fn new(limits: CacheLimits) -> Cache {
+    // every shard gets its share of the sequence limit, so the limit is rounded up to a multiple of
+    // SHARDS. A shard evicts once it has its share, even if other shards hold fewer sequences
+    let shard_limits = CacheLimits {
+        max_sequences: limits.max_sequences.map(|max| max.div_ceil(SHARDS)),
+        ..limits
+    };
+
+    Cache {
+        shards: Arc::new((0..SHARDS).map(|_| Mutex::new(Shard { map: cache_map::new(), seen_epoch: 0 })).collect()),
+        limits: Arc::new(shard_limits),
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    shards: Arc<Vec<Mutex<Shard>>>,
+    limits: Arc<CacheLimits>,
+}
+
+struct Shard {
+    map: CacheMap,
+    seen_epoch: u64,
+}
+
+
+impl Cache {
+    pub async fn put(&self, key: String, value: Range) {
+        self.with_shard(&key, |m| cache_map::store_range(key.clone(), value, m, &self.limits));
+    }
+
+    pub async fn get(&self, key: String, range_size: u64) -> (Vec<Range>, u64) {
+        self.with_shard(&key, |m| cache_map::get_range(key.clone(), range_size, m, &self.limits))
+    }
+
+    /// Ranges evicted from all shards
+    pub fn drain_evicted(&self) -> Vec<(String, Range)> {
+        self.shards.iter().flat_map(|s| s.lock().unwrap().map.drain_evicted()).collect()
+    }
+
+    pub async fn stop(&self) {
+        // nop
+    }
+
+    fn with_shard<T>(&self, key: &str, f: impl FnOnce(&mut CacheMap) -> T) -> T {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS].lock().unwrap();
+        let shard = &mut *shard;
+
+        shard.seen_epoch = invalidation::apply(shard.seen_epoch, &mut shard.map);
+        f(&mut shard.map)
+    }
+}
@@ -0,0 +1,123 @@
+/*
+    Per worker cursors that serve single ids, for GET /sequence/{seq}/next.
+
+    Taking one id from a cache means splitting a range and shuffling the vector of cached ranges.
+    Instead a worker keeps the range it currently hands ids out of for every sequence, and an id
+    is taken with one atomic bump. Only when the range runs out, the next batch is taken from
+    RangeProvider as usual. Invalidated sequences are dropped the same way caches drop them.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::cache::invalidation;
+use crate::range::Range;
+
+
+pub struct IdCursors {
+    cursors: RefCell<HashMap<String, Cursor>>,
+    seen_epoch: Cell<u64>,
+}
+
+struct Cursor {
+    next: AtomicU64,
+    end: AtomicU64,
+    stride: AtomicU64,
+
+    // ranges taken for the sequence after the current one
+    pending: RefCell<VecDeque<Range>>,
+}
+
+
+impl IdCursors {
+    pub fn new() -> Self {
+        Self { cursors: RefCell::new(HashMap::new()), seen_epoch: Cell::new(invalidation::epoch()) }
+    }
+
+    /// Next id of the sequence, None once its ranges are used up
+    pub fn take(&self, seq_name: &str) -> Option<u64> {
+        self.drop_invalidated();
+
+        let cursors = self.cursors.borrow();
+        let cursor = cursors.get(seq_name)?;
+
+        loop {
+            if let Some(id) = cursor.take() {
+                return Some(id);
+            }
+
+            let next = cursor.pending.borrow_mut().pop_front()?;
+            cursor.start(&next);
+        }
+    }
+
+    /// Adds ranges taken from RangeProvider after the ones the sequence has
+    pub fn refill(&self, seq_name: &str, ranges: Vec<Range>) {
+        self.drop_invalidated();
+
+        let mut cursors = self.cursors.borrow_mut();
+        let cursor = cursors.entry(seq_name.to_string()).or_insert_with(Cursor::empty);
+
+        cursor.pending.borrow_mut().extend(ranges);
+    }
+
+    fn drop_invalidated(&self) {
+        if invalidation::epoch() == self.seen_epoch.get() {
+            return;
+        }
+
+        let (current, invalidated) = invalidation::since(self.seen_epoch.get());
+        let mut cursors = self.cursors.borrow_mut();
+
+        match invalidated {
+            Some(seqs) => seqs.iter().for_each(|seq_name| { cursors.remove(seq_name); }),
+            None => cursors.clear(),
+        }
+
+        self.seen_epoch.set(current);
+    }
+}
+
+impl Default for IdCursors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cursor {
+    fn empty() -> Self {
+        Self { next: AtomicU64::new(0), end: AtomicU64::new(0), stride: AtomicU64::new(1), pending: RefCell::new(VecDeque::new()) }
+    }
+
+    fn take(&self) -> Option<u64> {
+        let end = self.end.load(Ordering::Relaxed);
+        let stride = self.stride.load(Ordering::Relaxed);
+
+        self.next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| (id < end).then(|| id.saturating_add(stride).min(end)))
+            .ok()
+    }
+
+    fn start(&self, range: &Range) {
+        self.end.store(range.end, Ordering::Relaxed);
+        self.stride.store(range.stride, Ordering::Relaxed);
+        self.next.store(range.begin, Ordering::Relaxed);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::range::Range;
+    use super::IdCursors;
+
+    #[test]
+    fn ids_are_taken_across_ranges() {
+        let cursors = IdCursors::new();
+        assert_eq!(None, cursors.take("a"));
+
+        cursors.refill("a", vec![Range::new(1, 3), Range { begin: 10, end: 15, stride: 2 }]);
+
+        let ids: Vec<u64> = std::iter::from_fn(|| cursors.take("a")).collect();
+        assert_eq!(vec![1, 2, 10, 12, 14], ids);
+    }
+}
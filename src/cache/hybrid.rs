@@ -0,0 +1,126 @@
+/*
+    Thread-local buffers in front of a process-wide pool.
+
+    Ranges fetched from etcd go to the pool, so ids are not stranded per worker and workers
+    don't go to etcd one by one. A worker takes ids from its own buffer without any locking and
+    refills it from the pool with up to `buffer_size` extra ids, which are the most that
+    a worker may strand.
+ */
+
+use crate::cache::cache_map::{self, CacheLimits};
+use crate::cache::{common, thread_local};
+use crate::cache::thread_local::LocalMap;
+use crate::range::{get_range_size, Range};
+
+thread_local! {
+    // apart from the thread-local strategy's map, so that its limits don't evict buffers
+    static BUFFERS: LocalMap = LocalMap::default();
+}
+
+
+pub fn new(buffer_size: u64, limits: CacheLimits) -> Cache {
+    // buffers are small and short-lived, so only idle ones are evicted
+    let buffer_limits = CacheLimits { idle_ttl: limits.idle_ttl, ..Default::default() };
+
+    Cache { pool: common::new(limits), buffer: thread_local::new_in(&BUFFERS, buffer_limits), buffer_size }
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    pool: common::Cache,
+    buffer: thread_local::Cache,
+    buffer_size: u64,
+}
+
+
+impl Cache {
+    pub async fn put(&self, key: String, value: Range) {
+        self.pool.put(key, value).await
+    }
+
+    pub async fn get(&self, key: String, range_size: u64) -> (Vec<Range>, u64) {
+        let (mut result, needed) = self.buffer.get(key.clone(), range_size).await;
+        if needed == 0 {
+            return (result, 0);
+        }
+
+        let (from_pool, _) = self.pool.get(key.clone(), needed + self.buffer_size).await;
+        let (taken, rest) = take_ids(from_pool, needed);
+
+        let taken_size: u64 = taken.iter().map(get_range_size).sum();
+        result.extend(taken);
+
+        for range in rest {
+            self.buffer.put(key.clone(), range).await;
+        }
+
+        (result, needed - taken_size)
+    }
+
+    pub fn drain_evicted(&self) -> Vec<(String, Range)> {
+        let mut evicted = self.pool.drain_evicted();
+        evicted.extend(self.buffer.drain_evicted());
+        evicted
+    }
+
+    pub async fn stop(&self) {
+        // nop
+    }
+}
+
+// first `size` ids of the ranges and the rest of them
+fn take_ids(ranges: Vec<Range>, size: u64) -> (Vec<Range>, Vec<Range>) {
+    let limits = CacheLimits::default();
+
+    let mut map = cache_map::new();
+    for range in ranges {
+        cache_map::store_range(String::new(), range, &mut map, &limits);
+    }
+
+    let (taken, _) = cache_map::get_range(String::new(), size, &mut map, &limits);
+    let (rest, _) = cache_map::get_range(String::new(), u64::MAX, &mut map, &limits);
+
+    (taken, rest)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::cache_map::CacheLimits;
+    use crate::cache::thread_local;
+    use crate::range::{get_range_size, Range};
+    use super::new;
+
+    #[actix_web::test]
+    async fn worker_takes_buffer_from_pool() {
+        let cache = new(200, Default::default());
+        cache.put("seq".to_string(), Range::new(0, 10_000)).await;
+
+        let (ranges, needed) = cache.get("seq".to_string(), 10).await;
+        assert_eq!(0, needed);
+        assert_eq!(10, ranges.iter().map(get_range_size).sum::<u64>());
+
+        // the next request is served from the local buffer, pool only lost what the buffer took
+        let (from_buffer, _) = cache.get("seq".to_string(), 10).await;
+        assert!(from_buffer.iter().all(|r| r.end <= 210));
+
+        let (from_pool, _) = cache.pool.get("seq".to_string(), 10).await;
+        assert!(from_pool.iter().all(|r| r.begin >= 210));
+    }
+
+    #[actix_web::test]
+    async fn thread_local_limits_leave_buffers_alone() {
+        let cache = new(200, Default::default());
+        cache.put("seq".to_string(), Range::new(0, 10_000)).await;
+        cache.get("seq".to_string(), 10).await;
+
+        let local = thread_local::new(CacheLimits { max_sequences: Some(1), ..Default::default() });
+        local.put("a".to_string(), Range::new(0, 10)).await;
+        local.put("b".to_string(), Range::new(0, 10)).await;
+        assert_eq!(vec![("a".to_string(), Range::new(0, 10))], local.drain_evicted());
+
+        assert!(cache.drain_evicted().is_empty());
+        let (from_buffer, _) = cache.get("seq".to_string(), 10).await;
+        assert!(from_buffer.iter().all(|r| r.end <= 210));
+    }
+}
@@ -0,0 +1,79 @@
+/*
+    Process-wide log of invalidated sequences.
+
+    Caches can't be cleared from outside (thread-local ones are only reachable from their threads),
+    so instead every invalidation bumps an epoch. A cache compares the epoch with the last one it has
+    seen before each operation and drops ranges of sequences invalidated since then.
+ */
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use crate::cache::cache_map::CacheMap;
+
+// caches that fell further behind than this just drop everything
+const MAX_LOG_LEN: usize = 1024;
+
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+static LOG: Mutex<Log> = Mutex::new(Log { entries: VecDeque::new() });
+
+struct Log {
+    // (epoch, sequence)
+    entries: VecDeque<(u64, String)>,
+}
+
+
+pub fn invalidate(key: &str) {
+    let mut log = LOG.lock().unwrap();
+
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    log.entries.push_back((epoch, key.to_string()));
+
+    if log.entries.len() > MAX_LOG_LEN {
+        log.entries.pop_front();
+    }
+}
+
+pub fn epoch() -> u64 {
+    EPOCH.load(Ordering::SeqCst)
+}
+
+/// Ranges read from etcd before a sequence was invalidated must not be served.
+/// Locks the log only if anything was invalidated after `seen_epoch`. True if the log doesn't reach back that far
+pub fn invalidated_since(seen_epoch: u64, key: &str) -> bool {
+    match since(seen_epoch) {
+        (_, Some(keys)) => keys.iter().any(|k| k == key),
+        (_, None) => true,
+    }
+}
+
+/// Drops ranges of sequences invalidated after `seen_epoch`, returns the current epoch
+pub fn apply(seen_epoch: u64, map: &mut CacheMap) -> u64 {
+    let (current, invalidated) = since(seen_epoch);
+
+    match invalidated {
+        Some(keys) => keys.iter().for_each(|key| map.remove(key)),
+        None => map.clear(),
+    }
+
+    current
+}
+
+/// Sequences invalidated after `seen_epoch` and the current epoch.
+/// None if the log doesn't reach back to seen epoch, so there is no telling what was invalidated
+pub fn since(seen_epoch: u64) -> (u64, Option<Vec<String>>) {
+    let current = epoch();
+    if current == seen_epoch {
+        return (current, Some(vec![]));
+    }
+
+    let log = LOG.lock().unwrap();
+
+    let invalidated = match log.entries.front() {
+        Some((oldest, _)) if *oldest <= seen_epoch + 1 =>
+            Some(log.entries.iter().filter(|(epoch, _)| *epoch > seen_epoch).map(|(_, key)| key.clone()).collect()),
+        _ => None,
+    };
+
+    (log.entries.back().map(|(epoch, _)| *epoch).unwrap_or(current), invalidated)
+}
@@ -1,45 +1,170 @@
+use std::time::Duration;
+use opentelemetry::{Context, KeyValue};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use crate::config::{CacheProps, CacheStrategy};
 use crate::range::Range;
+use crate::cache::cache_map::CacheLimits;
+use crate::telemetry;
 
 mod common;
 mod thread_local;
+mod hybrid;
 mod cache_map;
+mod invalidation;
+pub mod cursor;
 
+/// The least cache.max_sequences, the common cache splits it between its shards
+pub const MIN_MAX_SEQUENCES: usize = common::SHARDS;
+
+
+pub fn new(props: &CacheProps) -> CacheClient {
+    new_with_strategy(props, props.strategy)
+}
+
+fn new_with_strategy(props: &CacheProps, strategy: CacheStrategy) -> CacheClient {
+    let limits = CacheLimits {
+        max_sequences: props.max_sequences,
+        idle_ttl: props.idle_ttl_secs.map(Duration::from_secs),
+        max_fragments: props.max_fragments,
+    };
+
+    match strategy {
+        CacheStrategy::Common => CacheClient::Common(common::new(limits)),
+        CacheStrategy::ThreadLocal => CacheClient::ThreadLocal(thread_local::new(limits)),
+        CacheStrategy::Hybrid => CacheClient::Hybrid(hybrid::new(props.local_buffer_size, limits)),
+    }
+}
+
+/// A cache of every strategy, so that sequence policies may pick one
+pub fn new_all(props: &CacheProps) -> Caches {
+    Caches {
+        default: props.strategy,
+        common: new_with_strategy(props, CacheStrategy::Common),
+        thread_local: new_with_strategy(props, CacheStrategy::ThreadLocal),
+        hybrid: new_with_strategy(props, CacheStrategy::Hybrid),
+    }
+}
+
+// caches without limits
 
 pub fn new_common() -> CacheClient {
-    CacheClient::Common(common::new())
+    CacheClient::Common(common::new(CacheLimits::default()))
 }
 
 pub fn new_thread_local() -> CacheClient {
-    CacheClient::ThreadLocal(thread_local::new())
+    CacheClient::ThreadLocal(thread_local::new(CacheLimits::default()))
+}
+
+pub fn new_hybrid(local_buffer_size: u64) -> CacheClient {
+    CacheClient::Hybrid(hybrid::new(local_buffer_size, CacheLimits::default()))
+}
+
+
+#[derive(Clone)]
+pub struct Caches {
+    // used by sequences whose policy doesn't name a strategy
+    default: CacheStrategy,
+
+    common: CacheClient,
+    thread_local: CacheClient,
+    hybrid: CacheClient,
+}
+
+impl Caches {
+    pub fn get(&self, strategy: Option<CacheStrategy>) -> &CacheClient {
+        match strategy.unwrap_or(self.default) {
+            CacheStrategy::Common => &self.common,
+            CacheStrategy::ThreadLocal => &self.thread_local,
+            CacheStrategy::Hybrid => &self.hybrid,
+        }
+    }
+
+    pub fn drain_evicted(&self) -> Vec<(String, Range)> {
+        [&self.common, &self.thread_local, &self.hybrid].into_iter().flat_map(|c| c.drain_evicted()).collect()
+    }
 }
 
 
 #[derive(Clone)]
 pub enum CacheClient{
-    Common(common::CacheClient),
-    ThreadLocal(thread_local::Cache)
+    Common(common::Cache),
+    ThreadLocal(thread_local::Cache),
+    Hybrid(hybrid::Cache),
 }
 
 
 impl CacheClient {
     pub async fn put(&self, key: String, value: Range) {
+        let cx = self.start_span("cache put", &key, value.len());
+
         match self {
             CacheClient::Common(c) => c.put(key, value).await,
             CacheClient::ThreadLocal(tl) => tl.put(key, value).await,
+            CacheClient::Hybrid(h) => h.put(key, value).await,
         }
+
+        telemetry::end::<(), ()>(&cx, &Ok(()));
     }
 
     pub async fn get(&self, key: String, range_size: u64) -> (Vec<Range>, u64) {
-        match self {
+        let cx = self.start_span("cache get", &key, range_size);
+
+        let (ranges, needed) = match self {
             CacheClient::Common(c) => c.get(key, range_size).await,
             CacheClient::ThreadLocal(tl) => tl.get(key, range_size).await,
+            CacheClient::Hybrid(h) => h.get(key, range_size).await,
+        };
+
+        cx.span().set_attribute(KeyValue::new(telemetry::CACHE_HIT, needed == 0));
+        cx.span().set_attribute(KeyValue::new(telemetry::IDS_MISSING, needed as i64));
+        telemetry::end::<(), ()>(&cx, &Ok(()));
+
+        (ranges, needed)
+    }
+
+    fn start_span(&self, name: &'static str, key: &str, range_size: u64) -> Context {
+        let strategy = match self {
+            CacheClient::Common(_) => "common",
+            CacheClient::ThreadLocal(_) => "thread_local",
+            CacheClient::Hybrid(_) => "hybrid",
+        };
+
+        telemetry::start_span(name, SpanKind::Internal, vec![
+            KeyValue::new(telemetry::SEQUENCE, key.to_string()),
+            KeyValue::new(telemetry::RANGE_SIZE, range_size as i64),
+            KeyValue::new("id_gen.cache_strategy", strategy),
+        ])
+    }
+
+    /// Ranges evicted to keep the cache within its limits. They are neither served nor
+    /// cached anymore, so the caller may return them to etcd
+    pub fn drain_evicted(&self) -> Vec<(String, Range)> {
+        match self {
+            CacheClient::Common(c) => c.drain_evicted(),
+            CacheClient::ThreadLocal(tl) => tl.drain_evicted(),
+            CacheClient::Hybrid(h) => h.drain_evicted(),
         }
     }
 
+    /// Drops cached ranges of the sequence in every cache of the process
+    pub fn invalidate(&self, key: &str) {
+        invalidation::invalidate(key)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        invalidation::epoch()
+    }
+
+    /// Whether the sequence was invalidated after the epoch
+    pub fn invalidated_since(&self, epoch: u64, key: &str) -> bool {
+        invalidation::invalidated_since(epoch, key)
+    }
+
     pub async fn stop(&self) {
         match self {
             CacheClient::Common(c) => c.stop().await,
             CacheClient::ThreadLocal(tl) => tl.stop().await,
+            CacheClient::Hybrid(h) => h.stop().await,
         }
     }
 }
\ No newline at end of file
@@ -4,32 +4,63 @@
  */
 
 
-use std::cell::RefCell;
-use crate::cache;
-use crate::cache::cache_map::{self, CacheMap};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::thread::LocalKey;
+use crate::cache::cache_map::{self, CacheLimits, CacheMap};
+use crate::cache::invalidation;
 use crate::range::Range;
 
 thread_local! {
-    static MAP: RefCell<CacheMap> = RefCell::new(CacheMap::new());
+    static MAP: LocalMap = LocalMap::default();
 }
 
-pub fn new() -> Cache {
-    Cache
+/// Map of a thread and the last invalidation epoch applied to it
+#[derive(Default)]
+pub struct LocalMap {
+    map: RefCell<CacheMap>,
+    seen_epoch: Cell<u64>,
+}
+
+pub fn new(limits: CacheLimits) -> Cache {
+    new_in(&MAP, limits)
+}
+
+/// A cache in a map of its own, limits of a map apply to all its ranges
+pub fn new_in(map: &'static LocalKey<LocalMap>, limits: CacheLimits) -> Cache {
+    Cache { map, limits: Arc::new(limits) }
 }
 
 #[derive(Clone)]
-pub struct Cache;
+pub struct Cache {
+    map: &'static LocalKey<LocalMap>,
+    limits: Arc<CacheLimits>,
+}
 
 impl Cache{
     pub async fn put(&self, key: String, value: Range) {
-        MAP.with(|m| cache_map::store_range(key, value, &mut m.borrow_mut()));
+        self.with_map(|m| cache_map::store_range(key, value, m, &self.limits));
     }
 
     pub async fn get(&self, key: String, range_size: u64) -> (Vec<Range>, u64){
-        MAP.with(|m| cache_map::get_range(key, range_size, &mut m.borrow_mut()))
+        self.with_map(|m| cache_map::get_range(key, range_size, m, &self.limits))
+    }
+
+    /// Ranges evicted from this thread's map
+    pub fn drain_evicted(&self) -> Vec<(String, Range)> {
+        self.with_map(|m| m.drain_evicted())
     }
 
     pub async fn stop(&self) {
         // nop
     }
-}
\ No newline at end of file
+
+    fn with_map<T>(&self, f: impl FnOnce(&mut CacheMap) -> T) -> T {
+        self.map.with(|local| {
+            let mut m = local.map.borrow_mut();
+            local.seen_epoch.set(invalidation::apply(local.seen_epoch.get(), &mut m));
+
+            f(&mut m)
+        })
+    }
+}
@@ -1,8 +1,13 @@
+use std::collections::HashMap;
 use std::env::VarError;
 use std::fs::File;
 use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::string::ToString;
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
+use crate::overrides::Overrides;
+use crate::cache;
 
 
 const CFG_PATH_ENV_KEY : &str = "ID_GEN_CFG_PATH";
@@ -10,56 +15,577 @@ const CFG_DEFAULT_PATH: &str = "configs/default/";
 const CFG_PROPS_FILE: &str = "properties.yaml";
 const CFG_LOG_FILE: &str = "logs.yaml";
 
+// properties whose values are never printed
+const SECRET_PROPS: [&str; 1] = ["key"];
 
-#[derive(Deserialize, Clone)]
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Properties{
     pub etcd_addr: String,
     pub etcd_fetch_range_size: u64,
     pub client_range_max_size: u64,
+
+    // ids a worker takes at once to serve single id requests
+    #[serde(default = "default_next_id_batch")]
+    pub next_id_batch: u64,
+
+    // how long sequence policies are cached, changes made through other instances take up to this long to apply
+    #[serde(default = "default_policy_ttl_secs")]
+    pub policy_ttl_secs: u64,
+
+    #[serde(default)]
+    pub key_prefix: String,
+
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantProps>,
+
+    // if absent, any client may do anything
+    #[serde(default)]
+    pub auth: Option<AuthProps>,
+
+    #[serde(default)]
+    pub rate_limits: RateLimitsProps,
+
+    // if absent, every fetch from etcd takes etcd_fetch_range_size ids
+    #[serde(default)]
+    pub adaptive_fetch: Option<AdaptiveFetchProps>,
+
+    #[serde(default)]
+    pub cache: CacheProps,
+
+    // if absent, cached ranges are lost on restart
+    #[serde(default)]
+    pub persistence: Option<PersistenceProps>,
+
+    // the first rule whose pattern matches a sequence applies
+    #[serde(default)]
+    pub interleave: Vec<InterleaveProps>,
+
+    // if absent, only HTTP API is served
+    #[serde(default)]
+    pub grpc: Option<GrpcProps>,
+
+    // if absent, no instance runs background maintenance
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceProps>,
+
+    #[serde(default)]
+    pub audit: AuditProps,
+
+    // if absent, spans are not exported, trace context of requests is still passed on to etcd
+    #[serde(default)]
+    pub tracing: Option<TracingProps>,
+
+    #[serde(default)]
+    pub logging: LoggingProps,
+
+    // how often this file is checked for changes, 0 turns checking off. SIGHUP reloads it anyway
+    #[serde(default = "default_reload_check_secs")]
+    pub reload_check_secs: u64,
+}
+
+fn default_reload_check_secs() -> u64 {
+    10
+}
+
+fn default_next_id_batch() -> u64 {
+    10
+}
+
+fn default_policy_ttl_secs() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TenantProps{
+    pub max_sequences: u64,
+    pub client_range_max_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuthProps{
+    pub api_keys: Vec<ApiKeyProps>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKeyProps{
+    pub name: String,
+    pub key: String,
+    pub grants: Vec<GrantProps>,
+}
+
+// permissions on sequences whose names match the pattern ('*' matches anything)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GrantProps{
+    pub pattern: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission{
+    // fetch ranges
+    Read,
+    // create, delete, reset sequences
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RateLimitsProps{
+    // applies to sequences that don't match any of the rules below. No default means no limit
+    #[serde(default)]
+    pub default: Option<RateLimitProps>,
+
+    #[serde(default)]
+    pub sequences: Vec<SeqRateLimitProps>,
+
+    // anonymous clients are told apart by the address they connect from. Only connections from these
+    // proxies are taken to be on behalf of the address in their Forwarded or X-Forwarded-For header
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SeqRateLimitProps{
+    pub pattern: String,
+
+    #[serde(flatten)]
+    pub limit: RateLimitProps,
+}
+
+// limits per client and sequence
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateLimitProps{
+    pub requests_per_sec: u64,
+    pub ids_per_sec: u64,
+}
+
+// fetch size of a sequence covers target_supply_secs of its recent demand, within the bounds
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AdaptiveFetchProps{
+    pub target_supply_secs: u64,
+    pub min_fetch_size: u64,
+    pub max_fetch_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CacheProps{
+    #[serde(default)]
+    pub strategy: CacheStrategy,
+
+    // ids a worker may hold aside from the shared pool, hybrid strategy only
+    #[serde(default = "default_local_buffer_size")]
+    pub local_buffer_size: u64,
+
+    // limits of a cache, none by default. Sequences beyond max_sequences are evicted least recently used first
+    #[serde(default)]
+    pub max_sequences: Option<usize>,
+    #[serde(default)]
+    pub idle_ttl_secs: Option<u64>,
+    // ranges of a sequence that are not adjacent, the smallest ones are evicted beyond this
+    #[serde(default)]
+    pub max_fragments: Option<usize>,
+
+    // give evicted ranges back if nobody has taken ids from the sequence since, otherwise they are lost
+    #[serde(default)]
+    pub return_evicted_to_etcd: bool,
+}
+
+impl Default for CacheProps {
+    fn default() -> Self {
+        Self {
+            strategy: CacheStrategy::default(),
+            local_buffer_size: default_local_buffer_size(),
+            max_sequences: None,
+            idle_ttl_secs: None,
+            max_fragments: None,
+            return_evicted_to_etcd: false,
+        }
+    }
+}
+
+fn default_local_buffer_size() -> u64 {
+    200
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStrategy{
+    // one map shared by all workers
+    Common,
+    // a map per worker thread, each fetching from etcd on its own
+    #[default]
+    ThreadLocal,
+    // small per worker buffers refilled from a shared map
+    Hybrid,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PersistenceProps{
+    // journal file, its directory must exist
+    pub path: String,
+
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+
+    #[serde(default = "default_fsync_interval_ms")]
+    pub fsync_interval_ms: u64,
+}
+
+fn default_fsync_interval_ms() -> u64 {
+    1000
+}
+
+// when journal writes are flushed to disk. Ids served but not synced may be served again after a crash
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy{
+    // on every write
+    Always,
+    // at most once per fsync_interval_ms
+    #[default]
+    Interval,
+    // left to the OS
+    Never,
+}
+
+// this instance hands out only its share (index of count) of ids of matching sequences
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InterleaveProps{
+    pub pattern: String,
+
+    #[serde(default)]
+    pub mode: InterleaveMode,
+    pub index: u64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InterleaveMode{
+    // ids congruent to index modulo count
+    #[default]
+    Modulo,
+    // ids whose high bits equal to index
+    HighBits,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GrpcProps{
+    pub addr: SocketAddr,
+}
+
+// levels, appenders and encoders are set in logs.yaml
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoggingProps{
+    // hot paths log at debug level only once per this many calls
+    #[serde(default = "default_debug_sample_every")]
+    pub debug_sample_every: u64,
+}
+
+impl Default for LoggingProps {
+    fn default() -> Self {
+        Self { debug_sample_every: default_debug_sample_every() }
+    }
+}
+
+fn default_debug_sample_every() -> u64 {
+    1000
+}
+
+// spans are exported over OTLP/gRPC, e.g. to an OpenTelemetry collector
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TracingProps{
+    pub otlp_endpoint: String,
+
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+
+    // fraction of traces started here that are recorded, callers that sent trace context decide for theirs
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_service_name() -> String {
+    "ngamahi-id-gen".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+// administrative operations are always logged to the audit log4rs target, see logs.yaml
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AuditProps{
+    // also keep entries in etcd under meta/audit/, which GET /admin/audit serves
+    #[serde(default)]
+    pub store_in_etcd: bool,
+}
+
+// background jobs that run on one instance at a time, elected through an etcd lease
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MaintenanceProps{
+    // shown as the leader to other instances, hostname and pid if absent
+    #[serde(default)]
+    pub instance_id: Option<String>,
+
+    // how long the leader keeps leadership after it stops answering
+    #[serde(default = "default_lease_ttl_secs")]
+    pub lease_ttl_secs: u64,
+
+    // how often usage of sequences is logged, 0 turns the report off
+    #[serde(default = "default_usage_report_secs")]
+    pub usage_report_secs: u64,
+
+    // how often sequences with a capacity are sampled and checked, 0 turns checks off
+    #[serde(default = "default_capacity_check_secs")]
+    pub capacity_check_secs: u64,
+
+    // how far back consumption rate of a sequence is computed from
+    #[serde(default = "default_usage_history_secs")]
+    pub usage_history_secs: u64,
+
+    // used fractions of capacity warned about once a sequence crosses them
+    #[serde(default = "default_capacity_warn_ratios")]
+    pub capacity_warn_ratios: Vec<f64>,
+
+    // sequences forecast to run out within this time are warned about
+    #[serde(default = "default_exhaustion_warn_secs")]
+    pub exhaustion_warn_secs: u64,
+
+    // how often ids cached by instances that are gone are given back to etcd. Every instance records
+    // its cached ranges in etcd then and can't serve them while etcd is down. 0 turns it off
+    #[serde(default)]
+    pub reservation_gc_secs: u64,
+}
+
+fn default_lease_ttl_secs() -> u64 {
+    10
+}
+
+fn default_usage_report_secs() -> u64 {
+    300
+}
+
+fn default_capacity_check_secs() -> u64 {
+    60
+}
+
+fn default_usage_history_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_capacity_warn_ratios() -> Vec<f64> {
+    vec![0.5, 0.75, 0.9]
+}
+
+fn default_exhaustion_warn_secs() -> u64 {
+    30 * 24 * 3600
 }
 
 pub struct Configs{
     pub props: Properties,
+
+    // properties as written in the file with overrides applied, to tell what changed on reload
+    pub raw_props: serde_yaml::Value,
+    pub props_path: String,
+    pub overrides: Overrides,
     pub logs_cfg_path: String,
+    // where configs were read from and why, logged once logging is set up
+    pub source: String,
 }
 
 
-pub fn read_configs() -> Result<Configs, Error> {
-    let cfg_path = match std::env::var(&CFG_PATH_ENV_KEY) {
-        Ok(path) => {
-            println!("Env var {} is set, configs will be read from {}", &CFG_PATH_ENV_KEY, &path);
-            path
+impl Configs {
+    /// Effective properties with defaults filled in and secrets hidden
+    pub fn redacted_yaml(&self) -> String {
+        let mut props = serde_yaml::to_value(&self.props).unwrap();
+        redact(&mut props);
+        serde_yaml::to_string(&props).unwrap()
+    }
+}
+
+/// Whether the property at the path, e.g. auth.api_keys.0.key, is a secret or holds one
+pub fn is_secret(path: &str, value: &serde_yaml::Value) -> bool {
+    path.rsplit('.').next().is_some_and(|name| SECRET_PROPS.contains(&name)) || holds_secret(value)
+}
+
+fn holds_secret(value: &serde_yaml::Value) -> bool {
+    match value {
+        serde_yaml::Value::Mapping(map) => map.iter()
+            .any(|(key, value)| key.as_str().is_some_and(|k| SECRET_PROPS.contains(&k)) || holds_secret(value)),
+        serde_yaml::Value::Sequence(items) => items.iter().any(holds_secret),
+        _ => false,
+    }
+}
+
+fn redact(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                if key.as_str().is_some_and(|k| SECRET_PROPS.contains(&k)) {
+                    *value = serde_yaml::Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
         }
-        Err(VarError::NotPresent) => {
-            println!("Env var {} is not set so DEFAULT configs will be used", &CFG_PATH_ENV_KEY);
-            CFG_DEFAULT_PATH.to_string()
+        serde_yaml::Value::Sequence(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+
+/// Reads configs from `cfg_dir`, or from the directory in ID_GEN_CFG_PATH env var, or from the default one
+pub fn read_configs(cfg_dir: Option<String>, overrides: Overrides) -> Result<Configs, Error> {
+    let (cfg_path, source) = match cfg_dir {
+        Some(path) => {
+            let source = format!("Configs are read from {}", &path);
+            (path, source)
         }
-        Err(VarError::NotUnicode(_)) => {
-            println!("Bad env var {} (bad symbols). Must be unicode.", &CFG_PATH_ENV_KEY);
-            panic!()
+        None => match std::env::var(CFG_PATH_ENV_KEY) {
+            Ok(path) => {
+                let source = format!("Env var {} is set, configs are read from {}", &CFG_PATH_ENV_KEY, &path);
+                (path, source)
+            }
+            Err(VarError::NotPresent) => {
+                let source = format!("Env var {} is not set so DEFAULT configs are used", &CFG_PATH_ENV_KEY);
+                (CFG_DEFAULT_PATH.to_string(), source)
+            }
+            Err(VarError::NotUnicode(_)) =>
+                return Err(Error::Override(format!("Env var {} is not valid unicode", CFG_PATH_ENV_KEY))),
         }
     };
 
-    let cfg_file = File::open(cfg_path.clone() + CFG_PROPS_FILE)?;
+    let props_path = Path::new(&cfg_path).join(CFG_PROPS_FILE).to_string_lossy().to_string();
+    let (props, raw_props) = read_properties(&props_path, &overrides)?;
+
+    Ok(Configs{
+        props,
+        raw_props,
+        props_path,
+        overrides,
+        logs_cfg_path: Path::new(&cfg_path).join(CFG_LOG_FILE).to_string_lossy().to_string(),
+        source,
+    })
+}
+
+/// Reads properties, applies overrides and validates the result. Also returns it as plain YAML
+pub fn read_properties(path: &str, overrides: &Overrides) -> Result<(Properties, serde_yaml::Value), Error> {
+    let cfg_file = File::open(path)?;
     let cfg_reader = BufReader::new(cfg_file);
 
-    let props = serde_yaml::from_reader::<BufReader<File>, Properties>(cfg_reader)?;
+    let mut raw_props = serde_yaml::from_reader::<BufReader<File>, serde_yaml::Value>(cfg_reader)?;
+    overrides.apply(&mut raw_props)?;
+
+    let props = serde_yaml::from_value::<Properties>(raw_props.clone())?;
+
+    validate(&props)?;
 
+    Ok((props, raw_props))
+}
+
+fn validate(props: &Properties) -> Result<(), Error> {
     if props.client_range_max_size >= props.etcd_fetch_range_size {
         return Err(Error::Validation("Bad configs. client_range_max_size must be less than etcd_fetch_range_size".to_string()))
     }
 
-    Ok(Configs{
-        props,
-        logs_cfg_path: cfg_path + CFG_LOG_FILE,
-    })
+    if props.next_id_batch == 0 || props.next_id_batch > props.client_range_max_size {
+        return Err(Error::Validation("Bad configs. next_id_batch must be greater than 0 and not greater than client_range_max_size".to_string()))
+    }
+
+    if props.maintenance.as_ref().is_some_and(|m| m.lease_ttl_secs < 3) {
+        return Err(Error::Validation("Bad configs. maintenance.lease_ttl_secs must be at least 3".to_string()))
+    }
+
+    // ranges resumed from the journal may have been given back meanwhile
+    if props.persistence.is_some() && props.maintenance.as_ref().is_some_and(|m| m.reservation_gc_secs > 0) {
+        return Err(Error::Validation("Bad configs. maintenance.reservation_gc_secs can't be used with persistence".to_string()))
+    }
+
+    if props.logging.debug_sample_every == 0 {
+        return Err(Error::Validation("Bad configs. logging.debug_sample_every must be greater than 0".to_string()))
+    }
+
+    if props.tracing.as_ref().is_some_and(|t| !(0.0..=1.0).contains(&t.sample_ratio)) {
+        return Err(Error::Validation("Bad configs. tracing.sample_ratio must be between 0 and 1".to_string()))
+    }
+
+    if props.maintenance.as_ref().is_some_and(|m| m.capacity_warn_ratios.iter().any(|r| !(0.0..=1.0).contains(r))) {
+        return Err(Error::Validation("Bad configs. maintenance.capacity_warn_ratios must be between 0 and 1".to_string()))
+    }
+
+    for (name, tenant) in &props.tenants {
+        if name.is_empty() || name.contains('/') {
+            return Err(Error::Validation(format!("Bad configs. Tenant name '{}' must be non-empty and must not contain '/'", name)))
+        }
+
+        if tenant.client_range_max_size >= props.etcd_fetch_range_size {
+            return Err(Error::Validation(format!("Bad configs. client_range_max_size of tenant '{}' must be less than etcd_fetch_range_size", name)))
+        }
+    }
+
+    if let Some(auth) = &props.auth {
+        for (i, api_key) in auth.api_keys.iter().enumerate() {
+            if api_key.key.is_empty() {
+                return Err(Error::Validation(format!("Bad configs. API key '{}' is empty", api_key.name)))
+            }
+
+            if auth.api_keys[..i].iter().any(|k| k.key == api_key.key || k.name == api_key.name) {
+                return Err(Error::Validation(format!("Bad configs. API key '{}' is defined twice", api_key.name)))
+            }
+        }
+    }
+
+    if let Some(adaptive) = &props.adaptive_fetch {
+        let max_client_range = props.tenants.values()
+            .map(|t| t.client_range_max_size)
+            .chain([props.client_range_max_size])
+            .max()
+            .unwrap_or(0);
+
+        if adaptive.min_fetch_size <= max_client_range {
+            return Err(Error::Validation("Bad configs. adaptive_fetch.min_fetch_size must be greater than any client_range_max_size".to_string()))
+        }
+
+        if adaptive.max_fetch_size < adaptive.min_fetch_size {
+            return Err(Error::Validation("Bad configs. adaptive_fetch.max_fetch_size must not be less than min_fetch_size".to_string()))
+        }
+    }
+
+    if props.cache.max_fragments == Some(0) {
+        return Err(Error::Validation("Bad configs. cache.max_fragments must be greater than 0".to_string()))
+    }
+
+    if props.cache.max_sequences.is_some_and(|max| max < cache::MIN_MAX_SEQUENCES) {
+        return Err(Error::Validation(format!("Bad configs. cache.max_sequences must be at least {}", cache::MIN_MAX_SEQUENCES)))
+    }
+
+    for interleave in &props.interleave {
+        if interleave.count == 0 || interleave.index >= interleave.count {
+            return Err(Error::Validation(format!("Bad configs. Interleave index of '{}' must be less than count", interleave.pattern)))
+        }
+    }
+
+    let rate_limits = props.rate_limits.default.iter()
+        .chain(props.rate_limits.sequences.iter().map(|r| &r.limit));
+
+    for limit in rate_limits {
+        if limit.requests_per_sec == 0 || limit.ids_per_sec == 0 {
+            return Err(Error::Validation("Bad configs. Rate limits must be greater than 0".to_string()))
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Debug)]
 pub enum Error{
     IO(std::io::Error),
     Deserialization(serde_yaml::Error),
-    Validation(String)
+    Validation(String),
+    // bad env var or command-line flag
+    Override(String),
 }
 
 impl From<std::io::Error> for Error {
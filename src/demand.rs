@@ -0,0 +1,153 @@
+/*
+    Per sequence consumption rate and the etcd fetch size derived from it.
+
+    Rate is an exponentially decaying average of ids served per second, so a burst raises it
+    quickly and it fades out when a sequence goes quiet. Fetch size aims to cover
+    `target_supply_secs` of demand, so quiet sequences don't strand thousands of ids on restart
+    and hot ones don't go to etcd on every other request.
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use serde::Serialize;
+use crate::config::AdaptiveFetchProps;
+
+// how quickly the rate forgets old demand, in seconds
+const RATE_TIME_CONSTANT_SECS: f64 = 60.0;
+
+
+pub struct DemandTracker {
+    // fixed fetch size used when adaptive fetching is off, changes on config reload
+    fetch_size: AtomicU64,
+    adaptive: Option<AdaptiveFetchProps>,
+
+    seqs: Mutex<HashMap<String, SeqDemand>>,
+}
+
+struct SeqDemand {
+    // ids per second
+    rate: f64,
+    updated_at: Instant,
+
+    last_fetch_size: u64,
+    etcd_fetches: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DemandStats {
+    pub ids_per_sec: f64,
+    pub fetch_size: u64,
+    pub etcd_fetches: u64,
+}
+
+
+impl DemandTracker {
+    pub fn new(fetch_size: u64, adaptive: Option<AdaptiveFetchProps>) -> Self {
+        Self { fetch_size: AtomicU64::new(fetch_size), adaptive, seqs: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn set_fetch_size(&self, fetch_size: u64) {
+        self.fetch_size.store(fetch_size, Ordering::Relaxed);
+    }
+
+    /// Counts ids handed out to clients and the size of the range taken from etcd for them, if any.
+    /// Only served requests count, so names of sequences that don't exist never get in
+    pub fn record(&self, seq_name: &str, ids: u64, fetch_size: Option<u64>) {
+        let now = Instant::now();
+        let mut seqs = self.seqs.lock().unwrap();
+
+        let seq = seqs.entry(seq_name.to_string()).or_insert_with(|| SeqDemand {
+            rate: 0.0,
+            updated_at: now,
+            last_fetch_size: self.fetch_size.load(Ordering::Relaxed),
+            etcd_fetches: 0,
+        });
+
+        seq.rate = decayed(seq.rate, seq.updated_at, now) + ids as f64 / RATE_TIME_CONSTANT_SECS;
+        seq.updated_at = now;
+
+        if let Some(fetch_size) = fetch_size {
+            seq.last_fetch_size = fetch_size;
+            seq.etcd_fetches += 1;
+        }
+    }
+
+    /// Size of the next range to take from etcd, at least `min_size`
+    pub fn next_fetch_size(&self, seq_name: &str, min_size: u64) -> u64 {
+        let rate = self.seqs.lock().unwrap().get(seq_name).map(|s| decayed(s.rate, s.updated_at, Instant::now())).unwrap_or(0.0);
+        self.fetch_size_for(rate).max(min_size)
+    }
+
+    pub fn stats(&self, seq_name: &str) -> Option<DemandStats> {
+        let seqs = self.seqs.lock().unwrap();
+        seqs.get(seq_name).map(|s| s.stats(Instant::now()))
+    }
+
+    pub fn all_stats(&self) -> Vec<(String, DemandStats)> {
+        let now = Instant::now();
+        let seqs = self.seqs.lock().unwrap();
+
+        let mut stats: Vec<_> = seqs.iter().map(|(name, s)| (name.clone(), s.stats(now))).collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    pub fn forget(&self, seq_name: &str) {
+        self.seqs.lock().unwrap().remove(seq_name);
+    }
+
+    fn fetch_size_for(&self, rate: f64) -> u64 {
+        match &self.adaptive {
+            None => self.fetch_size.load(Ordering::Relaxed),
+            Some(adaptive) => ((rate * adaptive.target_supply_secs as f64).ceil() as u64)
+                .clamp(adaptive.min_fetch_size, adaptive.max_fetch_size),
+        }
+    }
+}
+
+impl SeqDemand {
+    fn stats(&self, now: Instant) -> DemandStats {
+        DemandStats {
+            ids_per_sec: decayed(self.rate, self.updated_at, now),
+            fetch_size: self.last_fetch_size,
+            etcd_fetches: self.etcd_fetches,
+        }
+    }
+}
+
+fn decayed(rate: f64, since: Instant, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(since).as_secs_f64();
+    rate * (-elapsed / RATE_TIME_CONSTANT_SECS).exp()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AdaptiveFetchProps;
+    use super::DemandTracker;
+
+    #[test]
+    fn fetch_size_follows_demand_within_bounds() {
+        let props = AdaptiveFetchProps { target_supply_secs: 60, min_fetch_size: 200, max_fetch_size: 10_000 };
+        let tracker = DemandTracker::new(5000, Some(props));
+
+        // unknown sequences start from the minimum, and only count once they are served
+        assert_eq!(200, tracker.next_fetch_size("quiet", 101));
+        assert!(tracker.stats("quiet").is_none());
+
+        // ~6000 ids within a minute are about 100 ids/s, i.e. 6000 ids per 60 s of supply
+        tracker.record("hot", 6000, None);
+        let fetch_size = tracker.next_fetch_size("hot", 101);
+        assert!((5990..=6000).contains(&fetch_size), "fetch size {}", fetch_size);
+
+        tracker.record("hot", 1_000_000, Some(fetch_size));
+        assert_eq!(10_000, tracker.next_fetch_size("hot", 101));
+        tracker.record("hot", 1, Some(10_000));
+
+        let stats = tracker.stats("hot").unwrap();
+        assert_eq!(10_000, stats.fetch_size);
+        assert_eq!(2, stats.etcd_fetches);
+    }
+}
@@ -0,0 +1,172 @@
+/*
+    Formats of range responses, picked by the Accept header.
+
+    Next to JSON, ranges are served as MessagePack, CBOR or a fixed-width little-endian layout,
+    which are much cheaper to produce and parse. Expanded ranges, i.e. explicit lists of ids,
+    are streamed in chunks, so a large list is never built in memory as a whole.
+ */
+
+use std::convert::Infallible;
+use actix_web::HttpRequest;
+use actix_web::http::header::{self, Header};
+use actix_web::web::Bytes;
+use ciborium_ll::{Encoder, Header as CborHeader};
+use futures::{stream, Stream};
+use crate::range::Range;
+
+pub use ngamahi_id_gen_types::BINARY_CONTENT_TYPE;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+// ids per streamed chunk
+const CHUNK_IDS: usize = 8192;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+    Binary,
+}
+
+impl Format {
+    /// The most preferred supported format of the Accept header, JSON without one.
+    /// None if the client accepts none of supported formats
+    pub fn negotiate(req: &HttpRequest) -> Option<Self> {
+        if !req.headers().contains_key(header::ACCEPT) {
+            return Some(Format::Json);
+        }
+
+        let accept = header::Accept::parse(req).ok()?;
+
+        accept.ranked().iter().find_map(|mime| match mime.essence_str() {
+            "*/*" | "application/*" | JSON_CONTENT_TYPE => Some(Format::Json),
+            MSGPACK_CONTENT_TYPE | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MsgPack),
+            CBOR_CONTENT_TYPE => Some(Format::Cbor),
+            BINARY_CONTENT_TYPE => Some(Format::Binary),
+            _ => None,
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => JSON_CONTENT_TYPE,
+            Format::MsgPack => MSGPACK_CONTENT_TYPE,
+            Format::Cbor => CBOR_CONTENT_TYPE,
+            Format::Binary => BINARY_CONTENT_TYPE,
+        }
+    }
+
+    pub fn encode_ranges(&self, ranges: &[Range]) -> Vec<u8> {
+        match self {
+            Format::Json => serde_json::to_vec(ranges).unwrap(),
+            Format::MsgPack => rmp_serde::to_vec_named(ranges).unwrap(),
+            Format::Cbor => {
+                let mut bytes = vec![];
+                ciborium::into_writer(ranges, &mut bytes).unwrap();
+                bytes
+            }
+            Format::Binary => ngamahi_id_gen_types::encode_binary(ranges),
+        }
+    }
+
+    /// Ids of the ranges as an array of numbers, or back to back little-endian u64 in binary format
+    pub fn stream_ids(self, ranges: Vec<Range>) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        let count: u64 = ranges.iter().map(Range::len).sum();
+        let mut ids = ranges.into_iter().flat_map(|r| r.ids());
+        let mut written = 0_u64;
+
+        let head = self.array_head(count);
+        let chunks = std::iter::from_fn(move || {
+            let mut chunk = Vec::with_capacity(CHUNK_IDS * 8);
+
+            for id in ids.by_ref().take(CHUNK_IDS) {
+                self.write_id(&mut chunk, id, written == 0);
+                written += 1;
+            }
+
+            (!chunk.is_empty()).then_some(chunk)
+        });
+        let tail = match self {
+            Format::Json => b"]".to_vec(),
+            _ => vec![],
+        };
+
+        let parts = std::iter::once(head).chain(chunks).chain(std::iter::once(tail));
+        stream::iter(parts.filter(|p| !p.is_empty()).map(|p| Ok(Bytes::from(p))))
+    }
+
+    fn array_head(&self, count: u64) -> Vec<u8> {
+        let mut head = vec![];
+
+        match self {
+            Format::Json => head.push(b'['),
+            // arrays longer than u32::MAX can't be told in MessagePack, responses are far below that
+            Format::MsgPack => { rmp::encode::write_array_len(&mut head, count as u32).unwrap(); }
+            Format::Cbor => Encoder::from(&mut head).push(CborHeader::Array(Some(count as usize))).unwrap(),
+            Format::Binary => {}
+        }
+
+        head
+    }
+
+    fn write_id(&self, out: &mut Vec<u8>, id: u64, first: bool) {
+        match self {
+            Format::Json => {
+                if !first {
+                    out.push(b',');
+                }
+                out.extend_from_slice(id.to_string().as_bytes());
+            }
+            Format::MsgPack => { rmp::encode::write_uint(out, id).unwrap(); }
+            Format::Cbor => Encoder::from(out).push(CborHeader::Positive(id)).unwrap(),
+            Format::Binary => out.extend_from_slice(&id.to_le_bytes()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use futures::executor::block_on_stream;
+    use crate::range::Range;
+    use super::{Format, BINARY_CONTENT_TYPE};
+
+    fn streamed(format: Format, ranges: Vec<Range>) -> Vec<u8> {
+        block_on_stream(format.stream_ids(ranges)).flat_map(|chunk| chunk.unwrap().to_vec()).collect()
+    }
+
+    #[test]
+    fn accept_header_picks_format() {
+        let negotiate = |accept: &str| Format::negotiate(&TestRequest::default().insert_header(("Accept", accept)).to_http_request());
+
+        assert_eq!(Some(Format::Json), Format::negotiate(&TestRequest::default().to_http_request()));
+        assert_eq!(Some(Format::Cbor), negotiate("application/cbor, application/json;q=0.5"));
+        assert_eq!(Some(Format::Binary), negotiate(BINARY_CONTENT_TYPE));
+        assert_eq!(Some(Format::Json), negotiate("text/html, */*;q=0.1"));
+        assert_eq!(None, negotiate("text/html"));
+    }
+
+    #[test]
+    fn expanded_ids_match_ranges() {
+        let ranges = vec![Range::new(1, 4), Range { begin: 10, end: 15, stride: 2 }];
+        let ids: Vec<u64> = vec![1, 2, 3, 10, 12, 14];
+
+        assert_eq!(serde_json::to_vec(&ids).unwrap(), streamed(Format::Json, ranges.clone()));
+        assert_eq!(rmp_serde::to_vec(&ids).unwrap(), streamed(Format::MsgPack, ranges.clone()));
+
+        let mut cbor = vec![];
+        ciborium::into_writer(&ids, &mut cbor).unwrap();
+        assert_eq!(cbor, streamed(Format::Cbor, ranges.clone()));
+
+        let binary: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        assert_eq!(binary, streamed(Format::Binary, ranges.clone()));
+
+        let encoded = Format::Binary.encode_ranges(&ranges);
+        assert_eq!(Some(ranges), ngamahi_id_gen_types::decode_binary(&encoded));
+    }
+}
@@ -1,22 +1,42 @@
-use crate::etcd_client::{EtcdErr, HttpClient};
-use crate::etcd_client::operations::{CreateSeqTx, EnlargeSeqTx, EnlargeTxErr, get_range};
+use crate::etcd_client::{CreateSeqTxErr, EtcdErr, GetRangeErr, HttpClient, ResetTxErr, SeqState, SeqWrite};
+use crate::etcd_client::operations::{count_keys, CompareAndPutTx, CreateSeqTx, delete_key, EnlargeSeqTx, EnlargeTxErr, get_range, get_seq, get_seqs, get_value, get_values, get_values_from, put_value, ResetSeqTx, CampaignTx, grant_lease, keep_lease_alive, revoke_lease};
+use crate::snapshot::{ImportOutcome, ImportPolicy};
 use crate::range::Range;
+use crate::telemetry;
+use opentelemetry::KeyValue;
+use opentelemetry::trace::SpanKind;
 
 
 #[derive(Clone)]
 pub struct EtcdClient {
     pub client: HttpClient,
     pub host_addr: String,
+
+    // prepended to every key this client reads or writes
+    pub key_prefix: String,
 }
 
 
 impl EtcdClient {
-    pub async fn next_range(&self, seq_name: String, range_size: u64) -> Result<Range, EtcdErr> {
+    /// Returns the range taken and etcd revision the sequence got with it
+    pub async fn next_range(&self, seq_name: String, range_size: u64) -> Result<(Range, u64), EtcdErr> {
+        let cx = telemetry::start_span("EtcdClient::next_range", SpanKind::Internal,
+            vec![KeyValue::new(telemetry::SEQUENCE, seq_name.clone()), KeyValue::new(telemetry::FETCH_SIZE, range_size as i64)]);
+
+        telemetry::in_span(cx, self.enlarge_seq(seq_name, range_size)).await
+    }
+
+    async fn enlarge_seq(&self, seq_name: String, range_size: u64) -> Result<(Range, u64), EtcdErr> {
+        let seq_name = self.key(seq_name);
 
         let mut old_value = get_range(seq_name.clone(), &self.client, self.host_addr.clone()).await?;
 
-        for _ in 0..5 { //todo: make a property
-            let new_value = old_value + range_size;
+        for retry in 0..5 { //todo: make a property
+            telemetry::record(KeyValue::new(telemetry::CAS_RETRIES, retry));
+
+            // a wrapped value would hand out ids from 0 again
+            let new_value = old_value.checked_add(range_size)
+                .ok_or_else(|| EtcdErr::SeqExhausted(format!("Sequence key '{}' is at {}, {} more ids don't fit in u64", seq_name, old_value, range_size)))?;
 
             let tx = EnlargeSeqTx::new(seq_name.clone(), old_value, new_value);
             let tx_result = tx.exec(self.host_addr.clone(), &self.client).await;
@@ -25,15 +45,230 @@ impl EtcdClient {
                 Err(EnlargeTxErr::StaleSequenceNum { new_num }) => old_value = new_num,
                 Err(other) => return Err(EtcdErr::EnlargeTxErr(other)),
 
-                Ok(_) => return Ok(Range { begin: old_value, end: new_value }),
+                Ok(revision) => return Ok((Range::new(old_value, new_value), revision)),
             }
         };
 
         Err(EtcdErr::OptimisticTxFailed)
     }
 
-    pub async fn create_seq(&self, seq_name: String) -> Result<(), EtcdErr> {
-        let tx = CreateSeqTx::new(seq_name);
+    pub async fn create_seq(&self, seq_name: String) -> Result<SeqWrite, EtcdErr> {
+        self.create_seq_with_value(seq_name, 0).await
+    }
+
+    /// Creates a sequence that starts from given value. Fails if the sequence exists
+    pub async fn create_seq_with_value(&self, seq_name: String, value: u64) -> Result<SeqWrite, EtcdErr> {
+        let tx = CreateSeqTx::new(self.key(seq_name), value);
+        let revision = tx.exec(self.host_addr.clone(), &self.client).await?;
+
+        Ok(SeqWrite { old_value: None, value: Some(value), revision: Some(revision) })
+    }
+
+    /// Number of sequences whose names start with given prefix
+    pub async fn count_seqs(&self, name_prefix: String) -> Result<u64, EtcdErr> {
+        Ok(count_keys(self.key(name_prefix), &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Sequences whose names start with given prefix and the etcd revision they were read at.
+    /// Returned names don't include key prefix of this client
+    pub async fn list_seqs(&self, name_prefix: String) -> Result<(u64, Vec<SeqState>), EtcdErr> {
+        let (revision, seqs) = get_seqs(self.key(name_prefix), &self.client, self.host_addr.clone()).await?;
+
+        let seqs = seqs.into_iter()
+            .filter_map(|mut seq| {
+                seq.name = seq.name.strip_prefix(&self.key_prefix)?.to_string();
+                Some(seq)
+            })
+            .collect();
+
+        Ok((revision, seqs))
+    }
+
+    pub async fn get_seq(&self, seq_name: String) -> Result<SeqState, EtcdErr> {
+        let mut seq = get_seq(self.key(seq_name.clone()), &self.client, self.host_addr.clone()).await?;
+        seq.name = seq_name;
+
+        Ok(seq)
+    }
+
+    /// Revision is None if there was no such sequence
+    pub async fn delete_seq(&self, seq_name: String) -> Result<SeqWrite, EtcdErr> {
+        let deleted = delete_key(self.key(seq_name), &self.client, self.host_addr.clone()).await?;
+
+        Ok(match deleted {
+            Some(deleted) => SeqWrite {
+                old_value: deleted.prev_value.and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?))),
+                value: None,
+                revision: Some(deleted.revision),
+            },
+            None => SeqWrite::default(),
+        })
+    }
+
+    /// Sets sequence value if it currently equals to the expected one
+    pub async fn reset_seq(&self, seq_name: String, expected_value: u64, new_value: u64) -> Result<SeqWrite, EtcdErr> {
+        let tx = ResetSeqTx::new(self.key(seq_name), expected_value, new_value);
+        let revision = tx.exec(self.host_addr.clone(), &self.client).await?;
+
+        Ok(SeqWrite { old_value: Some(expected_value), value: Some(new_value), revision: Some(revision) })
+    }
+
+    /// Moves sequence value to at least `min`, values above it are left as is.
+    /// Revision is None if the value was left as is
+    pub async fn advance_seq(&self, seq_name: String, min: u64) -> Result<SeqWrite, EtcdErr> {
+        let key = self.key(seq_name);
+
+        let mut current = get_range(key.clone(), &self.client, self.host_addr.clone()).await?;
+
+        for _ in 0..5 { //todo: make a property
+            if current >= min {
+                return Ok(SeqWrite { old_value: Some(current), value: Some(current), revision: None });
+            }
+
+            match ResetSeqTx::new(key.clone(), current, min).exec(self.host_addr.clone(), &self.client).await {
+                Ok(revision) => return Ok(SeqWrite { old_value: Some(current), value: Some(min), revision: Some(revision) }),
+                Err(ResetTxErr::UnexpectedValue { actual }) => current = actual,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(EtcdErr::OptimisticTxFailed)
+    }
+
+    /// Gives back a range if it is the last one taken from the sequence.
+    /// Returns false if other ranges were taken since
+    pub async fn return_range(&self, seq_name: String, range: &Range) -> Result<bool, EtcdErr> {
+        let tx = ResetSeqTx::new(self.key(seq_name), range.end, range.begin);
+
+        match tx.exec(self.host_addr.clone(), &self.client).await {
+            Ok(_) => Ok(true),
+            Err(ResetTxErr::UnexpectedValue { .. }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes sequence value according to the policy, creating the sequence if needed.
+    /// Every write is a compare-and-set against the value read just before, so concurrent
+    /// allocations are never overwritten unnoticed
+    /// Returns etcd revision of the write along with the outcome, None if the sequence was skipped
+    pub async fn import_seq(&self, seq_name: String, value: u64, policy: ImportPolicy) -> Result<(ImportOutcome, Option<u64>), EtcdErr> {
+        let key = self.key(seq_name);
+
+        for _ in 0..5 { //todo: make a property
+            let current = match get_range(key.clone(), &self.client, self.host_addr.clone()).await {
+                Ok(current) => Some(current),
+                Err(GetRangeErr::NoSuchSeq(_)) => None,
+                Err(err) => return Err(err.into()),
+            };
+
+            let current = match current {
+                Some(current) => current,
+                None => match CreateSeqTx::new(key.clone(), value).exec(self.host_addr.clone(), &self.client).await {
+                    Ok(revision) => return Ok((ImportOutcome::Created, Some(revision))),
+                    // someone has just created it, try again with its value
+                    Err(CreateSeqTxErr::SeqAlreadyExists { .. }) => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            };
+
+            let must_update = match policy {
+                ImportPolicy::Skip => false,
+                ImportPolicy::Overwrite => current != value,
+                ImportPolicy::TakeMax => current < value,
+            };
+
+            if !must_update {
+                return Ok((ImportOutcome::Skipped { current_value: current }, None));
+            }
+
+            match ResetSeqTx::new(key.clone(), current, value).exec(self.host_addr.clone(), &self.client).await {
+                Ok(revision) => return Ok((ImportOutcome::Updated { old_value: current }, Some(revision))),
+                Err(ResetTxErr::UnexpectedValue { .. }) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(EtcdErr::OptimisticTxFailed)
+    }
+
+    /// Value stored under meta/, where everything that is not a sequence lives
+    pub async fn get_meta(&self, name: &str) -> Result<Option<Vec<u8>>, EtcdErr> {
+        Ok(get_value(self.meta_key(name), &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Values stored under meta/ whose names start with given prefix, names are returned without the prefix
+    pub async fn list_meta(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>, EtcdErr> {
+        let key_prefix = self.meta_key(prefix);
+        let values = get_values(key_prefix.clone(), &self.client, self.host_addr.clone()).await?;
+
+        Ok(values.into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&key_prefix)?.to_string(), value)))
+            .collect())
+    }
+
+    /// Like list_meta, but only names that sort after `after`, at most `limit` of them
+    pub async fn list_meta_after(&self, prefix: &str, after: &str, limit: u64) -> Result<Vec<(String, Vec<u8>)>, EtcdErr> {
+        let key_prefix = self.meta_key(prefix);
+        // the least key greater than the one of `after`
+        let from = if after.is_empty() { key_prefix.clone() } else { format!("{}{}\0", key_prefix, after) };
+
+        let values = get_values_from(from, key_prefix.clone(), Some(limit), &self.client, self.host_addr.clone()).await?;
+
+        Ok(values.into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&key_prefix)?.to_string(), value)))
+            .collect())
+    }
+
+    /// Returns etcd revision of the write
+    pub async fn put_meta(&self, name: &str, value: &[u8]) -> Result<u64, EtcdErr> {
+        Ok(put_value(self.meta_key(name), value, None, &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Like put_meta, but the value is deleted when the lease expires
+    pub async fn put_meta_with_lease(&self, name: &str, value: &[u8], lease_id: &str) -> Result<u64, EtcdErr> {
+        Ok(put_value(self.meta_key(name), value, Some(lease_id.to_string()), &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Replaces a meta value, or deletes it if there is no new one, if it still equals to the old one.
+    /// Returns false otherwise, e.g. if it is gone
+    pub async fn compare_and_put_meta(&self, name: &str, old_value: &[u8], new_value: Option<&[u8]>) -> Result<bool, EtcdErr> {
+        let tx = CompareAndPutTx::new(self.meta_key(name), old_value, new_value);
         Ok(tx.exec(self.host_addr.clone(), &self.client).await?)
     }
+
+    /// Returns etcd revision of the deletion, None if there was no such key
+    pub async fn delete_meta(&self, name: &str) -> Result<Option<u64>, EtcdErr> {
+        let deleted = delete_key(self.meta_key(name), &self.client, self.host_addr.clone()).await?;
+        Ok(deleted.map(|d| d.revision))
+    }
+
+    /// Returns id of a lease that expires unless kept alive within `ttl_secs`
+    pub async fn grant_lease(&self, ttl_secs: u64) -> Result<String, EtcdErr> {
+        Ok(grant_lease(ttl_secs, &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Returns false if the lease has already expired
+    pub async fn keep_lease_alive(&self, lease_id: &str) -> Result<bool, EtcdErr> {
+        Ok(keep_lease_alive(lease_id.to_string(), &self.client, self.host_addr.clone()).await? > 0)
+    }
+
+    pub async fn revoke_lease(&self, lease_id: &str) -> Result<(), EtcdErr> {
+        Ok(revoke_lease(lease_id.to_string(), &self.client, self.host_addr.clone()).await?)
+    }
+
+    /// Puts the candidate to the meta key with the lease unless the key is there already.
+    /// Returns None if the candidate got the key, otherwise the one who holds it
+    pub async fn campaign(&self, name: &str, candidate: &str, lease_id: &str) -> Result<Option<String>, EtcdErr> {
+        let tx = CampaignTx::new(self.meta_key(name), candidate, lease_id.to_string());
+        Ok(tx.exec(self.host_addr.clone(), &self.client).await?)
+    }
+
+    // sequence names never contain '/' outside of tenants/, so these keys are never taken for sequences
+    fn meta_key(&self, name: &str) -> String {
+        format!("{}meta/{}", self.key_prefix, name)
+    }
+
+    fn key(&self, seq_name: String) -> String {
+        self.key_prefix.clone() + &seq_name
+    }
 }
\ No newline at end of file
@@ -1,56 +1,43 @@
-use std::sync::Arc;
-use awc::error::JsonPayloadError;
+use opentelemetry::KeyValue;
+use opentelemetry::trace::SpanKind;
 use serde::de::DeserializeOwned;
 use crate::etcd_client::operations::{DeserializeErr, EtcdInteropErr};
+use crate::telemetry;
 
-#[cfg(not(test))]
 pub type HttpClient = awc::Client;
 
-#[cfg(not(test))]
+// listings of all sequences or meta keys are far bigger than single key responses
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
 pub async fn make_request<TResp>(body: String, url: String, client: &HttpClient) -> Result<TResp, EtcdInteropErr>
     where
         TResp: DeserializeOwned
 {
-    let req = client.post(url).insert_header(("User-Agent", "id-gen/1.0"));
-
-    let mut res = req.send_body(body).await?;
-    Ok(res.json::<TResp>().limit(2000).await.map_err(|e| DeserializeErr::JsonPayload(e))?)
-}
+    // named by the etcd API called, e.g. "etcd kv/txn"
+    let api = url.split_once("/v3/").map_or("", |(_, api)| api);
+    let cx = telemetry::start_span(format!("etcd {}", api), SpanKind::Client, vec![KeyValue::new("url.full", url.clone())]);
 
+    let mut req = client.post(url).insert_header(("User-Agent", "id-gen/1.0"));
+    telemetry::inject(&cx, req.headers_mut());
 
-// mocking http client for tests
-#[cfg(test)]
-#[derive(Clone)]
-pub struct MockClient{
-    pub must_fail: Arc<Box<dyn Fn(String, String) -> Option<EtcdInteropErr>>>,
-    pub get_response: Arc<Box<dyn Fn(String, String) -> String>>,
-}
-
-#[cfg(test)]
-pub type HttpClient = MockClient;
+    let result = async {
+        let mut res = req.send_body(body).await?;
 
-#[cfg(test)]
-pub async fn make_request<TResp>(body: String, url: String, client: &HttpClient) -> Result<TResp, EtcdInteropErr>
-    where
-        TResp: DeserializeOwned
-{
-    if let Some(err) = (client.must_fail)(body.clone(), url.clone()) {
-        return Err(err);
-    };
+        // errors of the gateway are JSON too, and may fit responses whose fields are all optional
+        if !res.status().is_success() {
+            let body = res.body().await.map(|b| String::from_utf8_lossy(&b).into_owned()).unwrap_or_default();
+            return Err(EtcdInteropErr::StatusErr { status: res.status().as_u16(), body });
+        }
 
-    let response = serde_json::from_str((client.get_response)(body, url).as_str())
-        .map_err(|e| EtcdInteropErr::DeserializationErr(DeserializeErr::JsonPayload(JsonPayloadError::Deserialize(e))))?;
+        Ok(res.json::<TResp>().limit(MAX_RESPONSE_SIZE).await.map_err(DeserializeErr::JsonPayload)?)
+    }.await;
 
-    Ok(response)
+    telemetry::end(&cx, &result);
+    result
 }
 
+
 // factory function
-#[cfg(not(test))]
 pub fn new_http_client(client: awc::Client) -> HttpClient {
     client
 }
-
-#[cfg(test)]
-pub fn new_http_client(client: MockClient) -> HttpClient {
-    client
-}
\ No newline at end of file
@@ -5,32 +5,58 @@ mod client;
 mod http_client;
 
 use serde::{Deserialize, Serialize};
-use base64::{Engine as _, engine::{self, general_purpose}, alphabet};
-use operations::get_range;
 pub use client::EtcdClient;
-use crate::etcd_client::operations::{CreateSeqTx, CreateSeqTxErr, EnlargeSeqTx, EnlargeTxErr, GetRangeErr};
-use crate::Range;
+pub use operations::{CreateSeqTxErr, EnlargeTxErr, EtcdInteropErr, GetRangeErr, ResetTxErr};
 
 
 pub type HttpClient = http_client::HttpClient;
 pub use http_client::new_http_client;
 
-pub fn new_etcd_client(client: HttpClient, host: String) -> EtcdClient {
+pub fn new_etcd_client(client: HttpClient, host: String, key_prefix: String) -> EtcdClient {
     EtcdClient{
         client,
         host_addr: host,
+        key_prefix,
     }
 }
 
 
 
 
+/// Sequence as it is stored in etcd
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SeqState {
+    pub name: String,
+    pub value: u64,
+    pub create_revision: u64,
+    pub mod_revision: u64,
+    pub version: u64,
+}
+
+
+/// A write to a sequence and the etcd revision it was made at
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SeqWrite {
+    // None if the sequence didn't exist before
+    pub old_value: Option<u64>,
+    // None if the sequence was deleted
+    pub value: Option<u64>,
+    // None if nothing was written, e.g. the sequence was already there or it didn't exist
+    pub revision: Option<u64>,
+}
+
+
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum EtcdErr {
     OptimisticTxFailed,
     EnlargeTxErr(EnlargeTxErr),
     CreateSeqTxErr(CreateSeqTxErr),
     NoSuchRangeErr(GetRangeErr),
+    ResetTxErr(ResetTxErr),
+    EtcdInteropErr(EtcdInteropErr),
+    // the sequence can't grow without overflowing u64
+    SeqExhausted(String),
 }
 
 impl From<CreateSeqTxErr> for EtcdErr {
@@ -39,40 +65,20 @@ impl From<CreateSeqTxErr> for EtcdErr {
     }
 }
 
+impl From<ResetTxErr> for EtcdErr {
+    fn from(value: ResetTxErr) -> Self {
+        Self::ResetTxErr(value)
+    }
+}
+
+impl From<EtcdInteropErr> for EtcdErr {
+    fn from(value: EtcdInteropErr) -> Self {
+        Self::EtcdInteropErr(value)
+    }
+}
+
 impl From<GetRangeErr> for EtcdErr {
     fn from(value: GetRangeErr) -> Self {
         Self::NoSuchRangeErr(value)
     }
 }
-
-//
-// #[cfg(test)]
-// mod tests{
-//     use std::collections::HashMap;
-//     use std::sync::{Arc, Mutex};
-//     use crate::etcd_client::http_client::MockClient;
-//
-//
-//     #[test]
-//     pub fn do_test() {
-//         let a: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
-//
-//         let seq_name = "some-seq";
-//
-//         let must_fail = |seq_id, host| None;
-//         let process_req = |seq_id, host| {
-//             match seq_id.as_str() {
-//                 seq if seq == seq_name =>
-//             }
-//         };
-//
-//         let client = MockClient{
-//             must_fail: Arc::new(Box::new(must_fail)),
-//             get_response: Arc::new(Box::new(process_req)),
-//         };
-//     }
-// }
-//
-//
-
-
@@ -1,25 +1,23 @@
-use std::io::Read;
 use awc::error::{JsonPayloadError, SendRequestError};
-use base64::{Engine as _, engine::{self, general_purpose}, alphabet, DecodeError};
-use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose, DecodeError};
 use serde::de::DeserializeOwned;
 use serde_json::Error;
 use crate::etcd_client::http_client::make_request;
-use crate::etcd_client::HttpClient;
-use crate::etcd_client::req_types::{CompareResult, CompareTarget, Comparison, OperationRequest, RequestPut, RequestRange, Target, Transaction};
-use crate::etcd_client::resp_types::{RangeResponse, TxResp};
+use crate::etcd_client::{HttpClient, SeqState};
+use crate::etcd_client::req_types::{CompareResult, CompareTarget, Comparison, LeaseGrantRequest, LeaseRequest, OperationRequest, RequestDeleteRange, RequestPut, RequestRange, Target, Transaction};
+use crate::etcd_client::resp_types::{DeleteRangeResponse, Header, LeaseKeepAliveResponse, LeaseResponse, RangeResponse, RangeResult, ResponsePut, TxResp};
 
 
 /// Get current value of given sequence
 pub async fn get_range(seq_id: String, client: &HttpClient, host: String) -> Result<u64, GetRangeErr> {
     let url = host + "/v3/kv/range";
     let encoded_seq_name = general_purpose::STANDARD.encode(seq_id.as_str());
-    let body = RequestRange { key: encoded_seq_name };
+    let body = RequestRange::single(encoded_seq_name);
     let body = serde_json::to_string(&body).unwrap();
 
     let response = make_request::<RangeResponse>(body, url, client).await?;
 
-    let mut range_op_result = response.kvs.ok_or_else(|| GetRangeErr::NoSuchSeq(seq_id))?;
+    let range_op_result = response.kvs.ok_or_else(|| GetRangeErr::NoSuchSeq(seq_id))?;
 
     let next_range_start = match range_op_result.into_iter().next() {
         Some(range) => range.value,
@@ -32,12 +30,186 @@ pub async fn get_range(seq_id: String, client: &HttpClient, host: String) -> Res
             DeserializeErr::Common("Etcd didn't sent back value".to_string())))?;
 
     Ok(num_from_base64(next_range_start.as_str())
-        .map_err(|e| EtcdInteropErr::from(e))?)
+        .map_err(EtcdInteropErr::from)?)
+}
+
+/// Count keys that start with given prefix
+pub async fn count_keys(prefix: String, client: &HttpClient, host: String) -> Result<u64, GetRangeErr> {
+    let url = host + "/v3/kv/range";
+    let body = RequestRange {
+        key: general_purpose::STANDARD.encode(prefix.as_bytes()),
+        range_end: Some(general_purpose::STANDARD.encode(prefix_range_end(prefix.as_bytes()))),
+        count_only: Some(true),
+        limit: None,
+    };
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<RangeResponse>(body, url, client).await?;
+
+    // etcd omits zero values, so missing count means there are no such keys
+    match response.count {
+        None => Ok(0),
+        Some(count) => count.parse::<u64>()
+            .map_err(|_| EtcdInteropErr::DeserializationErr(
+                DeserializeErr::Common(format!("Couldn't parse keys count '{}'", count))).into())
+    }
+}
+
+/// All keys that start with given prefix along with etcd revision they were read at.
+/// Keys whose values are not sequence numbers are skipped
+pub async fn get_seqs(prefix: String, client: &HttpClient, host: String) -> Result<(u64, Vec<SeqState>), GetRangeErr> {
+    let url = host + "/v3/kv/range";
+    let body = RequestRange {
+        key: general_purpose::STANDARD.encode(prefix.as_bytes()),
+        range_end: Some(general_purpose::STANDARD.encode(prefix_range_end(prefix.as_bytes()))),
+        count_only: None,
+        limit: None,
+    };
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<RangeResponse>(body, url, client).await?;
+    let revision = parse_num(response.header.revision.as_deref().unwrap_or("0"))?;
+
+    let seqs = response.kvs.unwrap_or_default().into_iter()
+        .filter_map(|kv| seq_state(kv).ok())
+        .collect();
+
+    Ok((revision, seqs))
+}
+
+/// Current value of given sequence along with its revisions
+pub async fn get_seq(seq_id: String, client: &HttpClient, host: String) -> Result<SeqState, GetRangeErr> {
+    let url = host + "/v3/kv/range";
+    let body = RequestRange::single(general_purpose::STANDARD.encode(seq_id.as_bytes()));
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<RangeResponse>(body, url, client).await?;
+
+    let kv = response.kvs.unwrap_or_default().into_iter().next()
+        .ok_or_else(|| GetRangeErr::NoSuchSeq(seq_id))?;
+
+    Ok(seq_state(kv)?)
+}
+
+/// Raw value of given key, None if there is no such key
+pub async fn get_value(key: String, client: &HttpClient, host: String) -> Result<Option<Vec<u8>>, EtcdInteropErr> {
+    let url = host + "/v3/kv/range";
+    let body = RequestRange::single(general_purpose::STANDARD.encode(key.as_bytes()));
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<RangeResponse>(body, url, client).await?;
+
+    match response.kvs.unwrap_or_default().into_iter().next() {
+        None => Ok(None),
+        // etcd omits empty values
+        Some(kv) => Ok(Some(general_purpose::STANDARD.decode(kv.value.unwrap_or_default()).map_err(Base64DecodeErr::from)?)),
+    }
+}
+
+/// Raw values of all keys that start with given prefix
+pub async fn get_values(prefix: String, client: &HttpClient, host: String) -> Result<Vec<(String, Vec<u8>)>, EtcdInteropErr> {
+    get_values_from(prefix.clone(), prefix, None, client, host).await
+}
+
+/// Raw values of keys that start with given prefix and are not less than `from`, at most `limit` of them
+pub async fn get_values_from(from: String, prefix: String, limit: Option<u64>, client: &HttpClient, host: String) -> Result<Vec<(String, Vec<u8>)>, EtcdInteropErr> {
+    let url = host + "/v3/kv/range";
+    let body = RequestRange {
+        key: general_purpose::STANDARD.encode(from.as_bytes()),
+        range_end: Some(general_purpose::STANDARD.encode(prefix_range_end(prefix.as_bytes()))),
+        count_only: None,
+        limit,
+    };
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<RangeResponse>(body, url, client).await?;
+
+    let mut values = vec![];
+    for kv in response.kvs.unwrap_or_default() {
+        let key = general_purpose::STANDARD.decode(kv.key.unwrap_or_default()).map_err(Base64DecodeErr::from)?;
+        let value = general_purpose::STANDARD.decode(kv.value.unwrap_or_default()).map_err(Base64DecodeErr::from)?;
+
+        values.push((String::from_utf8_lossy(&key).into_owned(), value));
+    }
+
+    Ok(values)
+}
+
+/// Returns etcd revision of the write. A key put with a lease is deleted when the lease expires
+pub async fn put_value(key: String, value: &[u8], lease: Option<String>, client: &HttpClient, host: String) -> Result<u64, EtcdInteropErr> {
+    let url = host + "/v3/kv/put";
+    let body = RequestPut {
+        key: general_purpose::STANDARD.encode(key.as_bytes()),
+        value: general_purpose::STANDARD.encode(value),
+        lease,
+    };
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<ResponsePut>(body, url, client).await?;
+    Ok(header_revision(&response.header)?)
+}
+
+/// Returns None if there was no such key
+pub async fn delete_key(key: String, client: &HttpClient, host: String) -> Result<Option<DeletedKey>, EtcdInteropErr> {
+    let url = host + "/v3/kv/deleterange";
+    let body = RequestDeleteRange { key: general_purpose::STANDARD.encode(key.as_bytes()), prev_kv: Some(true) };
+    let body = serde_json::to_string(&body).unwrap();
+
+    let response = make_request::<DeleteRangeResponse>(body, url, client).await?;
+
+    // etcd omits zero values
+    if parse_num(response.deleted.as_deref().unwrap_or("0"))? == 0 {
+        return Ok(None);
+    }
+
+    let prev_value = response.prev_kvs.unwrap_or_default().into_iter().next()
+        .and_then(|kv| kv.value)
+        .map(|value| general_purpose::STANDARD.decode(value))
+        .transpose()
+        .map_err(Base64DecodeErr::from)?;
+
+    Ok(Some(DeletedKey { prev_value, revision: header_revision(&response.header)? }))
+}
+
+pub struct DeletedKey {
+    pub prev_value: Option<Vec<u8>>,
+    // etcd revision of the deletion
+    pub revision: u64,
 }
 
 
 // ===========| Transactions |=============
 
+/// Returns id of a new lease
+pub async fn grant_lease(ttl_secs: u64, client: &HttpClient, host: String) -> Result<String, EtcdInteropErr> {
+    let url = host + "/v3/lease/grant";
+    let body = serde_json::to_string(&LeaseGrantRequest { ttl: ttl_secs.to_string() }).unwrap();
+
+    let response = make_request::<LeaseResponse>(body, url, client).await?;
+    response.id.ok_or_else(|| DeserializeErr::Common("Etcd didn't send back lease id".to_string()).into())
+}
+
+/// Returns remaining TTL of the lease, 0 if it has expired
+pub async fn keep_lease_alive(lease_id: String, client: &HttpClient, host: String) -> Result<u64, EtcdInteropErr> {
+    let url = host + "/v3/lease/keepalive";
+    let body = serde_json::to_string(&LeaseRequest { id: lease_id }).unwrap();
+
+    let response = make_request::<LeaseKeepAliveResponse>(body, url, client).await?;
+    let ttl = response.result.and_then(|r| r.ttl).unwrap_or_default();
+
+    Ok(if ttl.is_empty() { 0 } else { parse_num(&ttl)? })
+}
+
+/// Keys attached to the lease are deleted along with it
+pub async fn revoke_lease(lease_id: String, client: &HttpClient, host: String) -> Result<(), EtcdInteropErr> {
+    let url = host + "/v3/lease/revoke";
+    let body = serde_json::to_string(&LeaseRequest { id: lease_id }).unwrap();
+
+    make_request::<serde_json::Value>(body, url, client).await?;
+    Ok(())
+}
+
+
 pub struct EnlargeSeqTx {
     tx: Transaction,
 }
@@ -46,29 +218,88 @@ pub struct CreateSeqTx {
     tx: Transaction,
 }
 
+pub struct ResetSeqTx {
+    tx: Transaction,
+}
+
+pub struct CampaignTx {
+    tx: Transaction,
+}
+
+pub struct CompareAndPutTx {
+    tx: Transaction,
+}
+
 
 impl EnlargeSeqTx {
-    pub async fn exec(self, mut host: String, client: &HttpClient) -> Result<(), EnlargeTxErr> {
+    /// Returns etcd revision of the enlargement
+    pub async fn exec(self, host: String, client: &HttpClient) -> Result<u64, EnlargeTxErr> {
         let response = execute_tx::<TxResp>(&self.tx, host, client).await?;
 
-        return if let Some(true) = response.succeeded {
-            Ok(())
+        if let Some(true) = response.succeeded {
+            Ok(header_revision(&response.header)?)
         } else {
             Err(EnlargeTxErr::StaleSequenceNum { new_num: unwrap_seq_value(response)? })
-        };
+        }
     }
 }
 
 
 impl CreateSeqTx {
-    pub async fn exec(self, mut host: String, client: &HttpClient) -> Result<(), CreateSeqTxErr> {
+    /// Returns etcd revision of the creation
+    pub async fn exec(self, host: String, client: &HttpClient) -> Result<u64, CreateSeqTxErr> {
         let response = execute_tx::<TxResp>(&self.tx, host, client).await?;
 
-        return if let Some(true) = response.succeeded {
-            Ok(())
+        if let Some(true) = response.succeeded {
+            Ok(header_revision(&response.header)?)
         } else {
             Err(CreateSeqTxErr::SeqAlreadyExists { seq_value: unwrap_seq_value(response)? })
-        };
+        }
+    }
+}
+
+
+impl ResetSeqTx {
+    /// Returns etcd revision of the reset
+    pub async fn exec(self, host: String, client: &HttpClient) -> Result<u64, ResetTxErr> {
+        let response = execute_tx::<TxResp>(&self.tx, host, client).await?;
+
+        if let Some(true) = response.succeeded {
+            Ok(header_revision(&response.header)?)
+        } else {
+            Err(ResetTxErr::UnexpectedValue { actual: unwrap_seq_value(response)? })
+        }
+    }
+}
+
+
+impl CampaignTx {
+    /// Returns None if the candidate took the key, otherwise the one who holds it
+    pub async fn exec(self, host: String, client: &HttpClient) -> Result<Option<String>, EtcdInteropErr> {
+        let response = execute_tx::<TxResp>(&self.tx, host, client).await?;
+
+        if let Some(true) = response.succeeded {
+            return Ok(None);
+        }
+
+        let holder = response.responses.into_iter().next()
+            .and_then(|r| r.response_range)
+            .and_then(|r| r.kvs)
+            .and_then(|kvs| kvs.into_iter().next())
+            .and_then(|kv| kv.value)
+            .unwrap_or_default();
+        let holder = general_purpose::STANDARD.decode(holder).map_err(Base64DecodeErr::from)?;
+
+        Ok(Some(String::from_utf8_lossy(&holder).into_owned()))
+    }
+}
+
+
+impl CompareAndPutTx {
+    /// Returns false if the key holds another value or is gone
+    pub async fn exec(self, host: String, client: &HttpClient) -> Result<bool, EtcdInteropErr> {
+        let response = execute_tx::<TxResp>(&self.tx, host, client).await?;
+        Ok(response.succeeded == Some(true))
     }
 }
 
@@ -77,29 +308,73 @@ impl CreateSeqTx {
 
 impl EnlargeSeqTx {
     pub fn new(sequence_name: String, old_value: u64, new_value: u64) -> Self {
+        Self { tx: compare_and_set(sequence_name, old_value, new_value) }
+    }
+}
+
+
+impl ResetSeqTx {
+    pub fn new(sequence_name: String, expected_value: u64, new_value: u64) -> Self {
+        Self { tx: compare_and_set(sequence_name, expected_value, new_value) }
+    }
+}
+
+
+// sets new value if the current one equals to old value, otherwise reads the current one
+fn compare_and_set(sequence_name: String, old_value: u64, new_value: u64) -> Transaction {
+    let key = general_purpose::STANDARD.encode(sequence_name.as_bytes());
+    let old_value = general_purpose::STANDARD.encode(old_value.to_be_bytes());
+    let new_value = general_purpose::STANDARD.encode(new_value.to_be_bytes());
+
+    Transaction {
+        compare: vec![
+            Comparison {
+                key: key.clone(),
+                target_value: Target::Value(old_value),
+                target: CompareTarget::Value,
+                result: CompareResult::Equal,
+            }],
+
+        success: vec![
+            OperationRequest::Put(
+                RequestPut { key: key.clone(), value: new_value, lease: None }
+            )
+        ],
+
+        failure: vec![
+            OperationRequest::Range(
+                RequestRange::single(key)
+            )
+        ],
+    }
+}
+
+
+impl CreateSeqTx {
+    pub fn new(sequence_name: String, value: u64) -> Self {
         let key = general_purpose::STANDARD.encode(sequence_name.as_bytes());
-        let old_value = general_purpose::STANDARD.encode(old_value.to_be_bytes());
-        let new_value = general_purpose::STANDARD.encode(new_value.to_be_bytes());
+        let new_value = general_purpose::STANDARD.encode(value.to_be_bytes());
 
         Self {
             tx: Transaction {
                 compare: vec![
+                    // check key version. If it does exist, its version is greater than 0
                     Comparison {
                         key: key.clone(),
-                        target_value: Target::Value(old_value),
-                        target: CompareTarget::VALUE,
-                        result: CompareResult::EQUAL,
+                        target_value: Target::Version(0_u64),
+                        target: CompareTarget::Version,
+                        result: CompareResult::Equal,
                     }],
 
                 success: vec![
                     OperationRequest::Put(
-                        RequestPut { key: key.clone(), value: new_value }
+                        RequestPut { key: key.clone(), value: new_value, lease: None }
                     )
                 ],
 
                 failure: vec![
                     OperationRequest::Range(
-                        RequestRange { key }
+                        RequestRange::single(key)
                     )
                 ],
             }
@@ -108,31 +383,30 @@ impl EnlargeSeqTx {
 }
 
 
-impl CreateSeqTx {
-    pub fn new(sequence_name: String) -> Self {
-        let key = general_purpose::STANDARD.encode(sequence_name.as_bytes());
-        let new_value = general_purpose::STANDARD.encode(0_u64.to_be_bytes());
+impl CampaignTx {
+    // the key is put only if there is none, i.e. its holder's lease has expired or was revoked
+    pub fn new(key: String, candidate: &str, lease_id: String) -> Self {
+        let key = general_purpose::STANDARD.encode(key.as_bytes());
 
         Self {
             tx: Transaction {
                 compare: vec![
-                    // check key version. If it does exist, its version is greater than 0
                     Comparison {
                         key: key.clone(),
-                        target_value: Target::Version(0_u64),
-                        target: CompareTarget::VERSION,
-                        result: CompareResult::EQUAL,
+                        target_value: Target::CreateRevision(0_u64),
+                        target: CompareTarget::Create,
+                        result: CompareResult::Equal,
                     }],
 
                 success: vec![
                     OperationRequest::Put(
-                        RequestPut { key: key.clone(), value: new_value }
+                        RequestPut { key: key.clone(), value: general_purpose::STANDARD.encode(candidate), lease: Some(lease_id) }
                     )
                 ],
 
                 failure: vec![
                     OperationRequest::Range(
-                        RequestRange { key }
+                        RequestRange::single(key)
                     )
                 ],
             }
@@ -141,10 +415,57 @@ impl CreateSeqTx {
 }
 
 
+impl CompareAndPutTx {
+    // puts the new value, or deletes the key if there is none, only if the key holds the old value
+    pub fn new(key: String, old_value: &[u8], new_value: Option<&[u8]>) -> Self {
+        let key = general_purpose::STANDARD.encode(key.as_bytes());
+
+        let write = match new_value {
+            Some(value) => OperationRequest::Put(
+                RequestPut { key: key.clone(), value: general_purpose::STANDARD.encode(value), lease: None }
+            ),
+            None => OperationRequest::DeleteRange(
+                RequestDeleteRange { key: key.clone(), prev_kv: None }
+            ),
+        };
+
+        Self {
+            tx: Transaction {
+                compare: vec![
+                    Comparison {
+                        key,
+                        target_value: Target::Value(general_purpose::STANDARD.encode(old_value)),
+                        target: CompareTarget::Value,
+                        result: CompareResult::Equal,
+                    }],
+
+                success: vec![write],
+                failure: vec![],
+            }
+        }
+    }
+}
+
+
 // =========| Utils |==============
 
-fn unwrap_seq_value(mut res: TxResp) -> Result<u64, RangeRespParsingErr> {
-    let response = res.responses.into_iter().nth(0);
+// the smallest key that is greater than all keys with given prefix, as etcd expects in range_end
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+
+    // prefix is empty or consists of 0xff only, so range must include all keys
+    vec![0]
+}
+
+fn unwrap_seq_value(res: TxResp) -> Result<u64, RangeRespParsingErr> {
+    let response = res.responses.into_iter().next();
     let response = response.ok_or_else(||
         RangeRespParsingErr::Common("Couldn't get value of sequence. \
                     No operation responses from etcd.".to_string())
@@ -155,15 +476,15 @@ fn unwrap_seq_value(mut res: TxResp) -> Result<u64, RangeRespParsingErr> {
                     No response of range operation from etcd.".to_string())
     )?;
 
-    return unwrap_range_response(response);
+    unwrap_range_response(response)
 }
 
 fn unwrap_range_response(rang_resp: RangeResponse) -> Result<u64, RangeRespParsingErr> {
-    let value = rang_resp.kvs.into_iter().nth(0);
+    let value = rang_resp.kvs.into_iter().next();
     let value = value.ok_or_else(||
         RangeRespParsingErr::Common("Couldn't parse range op response: no such key".to_string()))?;
 
-    let value = value.into_iter().nth(0).ok_or_else(||
+    let value = value.into_iter().next().ok_or_else(||
         RangeRespParsingErr::Common("Couldn't parse range op response: no such key".to_string()))?;
 
     let value = value.value.ok_or_else(||
@@ -171,13 +492,40 @@ fn unwrap_range_response(rang_resp: RangeResponse) -> Result<u64, RangeRespParsi
 
     let value = num_from_base64(value.as_str())?;
 
-    return Ok(value);
+    Ok(value)
+}
+
+fn seq_state(kv: RangeResult) -> Result<SeqState, RangeRespParsingErr> {
+    let key = kv.key.ok_or_else(|| RangeRespParsingErr::Common("Etcd didn't send back key".to_string()))?;
+    let key = general_purpose::STANDARD.decode(key).map_err(Base64DecodeErr::from)?;
+    let name = String::from_utf8(key)
+        .map_err(|_| RangeRespParsingErr::Common("Key is not a valid utf-8 string".to_string()))?;
+
+    let value = kv.value.ok_or_else(|| RangeRespParsingErr::Common("Etcd didn't send back value".to_string()))?;
+
+    Ok(SeqState {
+        name,
+        value: num_from_base64(value.as_str())?,
+        create_revision: parse_num(kv.create_revision.as_deref().unwrap_or("0"))?,
+        mod_revision: parse_num(kv.mod_revision.as_deref().unwrap_or("0"))?,
+        version: parse_num(kv.version.as_deref().unwrap_or("0"))?,
+    })
+}
+
+fn header_revision(header: &Header) -> Result<u64, RangeRespParsingErr> {
+    parse_num(header.revision.as_deref().unwrap_or("0"))
+}
+
+// etcd sends 64-bit numbers as strings
+fn parse_num(num: &str) -> Result<u64, RangeRespParsingErr> {
+    num.parse::<u64>()
+        .map_err(|_| RangeRespParsingErr::Common(format!("Couldn't parse number '{}'", num)))
 }
 
 fn num_from_base64(encoded: &str) -> Result<u64, Base64DecodeErr> {
     let next_range_start = general_purpose::STANDARD.decode(encoded)?;
     let bytes: [u8; std::mem::size_of::<u64>()] = next_range_start.try_into()
-        .map_err(|e| Base64DecodeErr::NumFromBytesErr("Couldn't parse u64 from bytes".to_string()))?;
+        .map_err(|_| Base64DecodeErr::NumFromBytesErr("Couldn't parse u64 from bytes".to_string()))?;
 
     Ok(u64::from_be_bytes(bytes))
 }
@@ -187,7 +535,7 @@ async fn execute_tx<TResp>(tx: &Transaction, mut host: String, client: &HttpClie
     where
         TResp: DeserializeOwned
 {
-    let tx = serde_json::to_string(tx).map_err(|e| EtcdInteropErr::SerializationErr(e))?;
+    let tx = serde_json::to_string(tx).map_err(EtcdInteropErr::SerializationErr)?;
     host.push_str("/v3/kv/txn");
 
     make_request::<TResp>(tx, host, client).await
@@ -197,44 +545,60 @@ async fn execute_tx<TResp>(tx: &Transaction, mut host: String, client: &HttpClie
 // =========| ERRORS |=========
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum EnlargeTxErr {
     StaleSequenceNum { new_num: u64 },
     EtcdInteropError(EtcdInteropErr),
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum CreateSeqTxErr {
     SeqAlreadyExists { seq_value: u64 },
     EtcdInteropError(EtcdInteropErr),
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
+pub enum ResetTxErr {
+    UnexpectedValue { actual: u64 },
+    EtcdInteropError(EtcdInteropErr),
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
 pub enum GetRangeErr {
     NoSuchSeq(String),
     EtcdInteropError(EtcdInteropErr),
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum RangeRespParsingErr {
     Common(String),
     Base64(Base64DecodeErr),
 }
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum EtcdInteropErr {
     SendReqErr(SendRequestError),
     SerializationErr(Error),
     DeserializationErr(DeserializeErr),
     Base64DecodeErr(Base64DecodeErr),
+    // etcd answered with an error, e.g. 503 when it has no leader
+    StatusErr { status: u16, body: String },
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum DeserializeErr {
     Common(String),
     JsonPayload(JsonPayloadError),
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum Base64DecodeErr {
     DecodeErr(DecodeError),
     NumFromBytesErr(String),
@@ -296,6 +660,24 @@ impl From<EtcdInteropErr> for CreateSeqTxErr {
     }
 }
 
+impl From<RangeRespParsingErr> for ResetTxErr {
+    fn from(value: RangeRespParsingErr) -> Self {
+        Self::EtcdInteropError(value.into())
+    }
+}
+
+impl From<EtcdInteropErr> for ResetTxErr {
+    fn from(value: EtcdInteropErr) -> Self {
+        Self::EtcdInteropError(value)
+    }
+}
+
+impl From<RangeRespParsingErr> for GetRangeErr {
+    fn from(value: RangeRespParsingErr) -> Self {
+        Self::EtcdInteropError(value.into())
+    }
+}
+
 impl From<EtcdInteropErr> for GetRangeErr {
     fn from(value: EtcdInteropErr) -> Self {
         Self::EtcdInteropError(value)
@@ -312,4 +694,27 @@ impl From<DecodeError> for Base64DecodeErr {
     fn from(value: DecodeError) -> Self {
         Self::DecodeErr(value)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefix_range_end, CampaignTx};
+
+    #[test]
+    fn prefix_range_end_increments_last_byte() {
+        assert_eq!(b"tenants/a0".to_vec(), prefix_range_end(b"tenants/a/"));
+        assert_eq!(vec![b'b'], prefix_range_end(&[b'a', 0xff]));
+        assert_eq!(vec![0], prefix_range_end(b""));
+    }
+
+    #[test]
+    fn campaign_puts_key_with_lease_only_if_absent() {
+        let tx = serde_json::to_value(&CampaignTx::new("meta/leader".to_string(), "a", "7".to_string()).tx).unwrap();
+
+        assert_eq!(serde_json::json!({
+            "compare": [{ "key": "bWV0YS9sZWFkZXI=", "result": "EQUAL", "create_revision": 0, "target": "CREATE" }],
+            "success": [{ "requestPut": { "key": "bWV0YS9sZWFkZXI=", "value": "YQ==", "lease": "7" } }],
+            "failure": [{ "requestRange": { "key": "bWV0YS9sZWFkZXI=" } }],
+        }), tx);
+    }
+}
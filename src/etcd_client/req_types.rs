@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use crate::etcd_client;
 
 //==========|  TRANSACTION  |============
 
@@ -23,20 +22,22 @@ pub(in crate::etcd_client) struct Comparison {
 
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub(in crate::etcd_client) enum CompareResult {
-    EQUAL,
-    GREATER,
-    LESS,
+    Equal,
+    Greater,
+    Less,
     NotEqual,
 }
 
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub(in crate::etcd_client) enum CompareTarget {
-    VERSION,
-    CREATE,
-    MOD,
-    VALUE,
+    Version,
+    Create,
+    Mod,
+    Value,
 }
 
 
@@ -64,6 +65,9 @@ pub(in crate::etcd_client) enum OperationRequest {
 
     #[serde(rename = "requestRange")]
     Range(RequestRange),
+
+    #[serde(rename = "requestDeleteRange")]
+    DeleteRange(RequestDeleteRange),
 }
 
 
@@ -74,6 +78,33 @@ pub(in crate::etcd_client) enum OperationRequest {
 #[derive(Serialize, Deserialize)]
 pub(in crate::etcd_client) struct RequestRange {
     pub key: String,
+
+    // base64 encoded, makes etcd return all keys in [key, range_end)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range_end: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count_only: Option<bool>,
+
+    // at most this many keys are returned, in key order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+impl RequestRange {
+    pub fn single(key: String) -> Self {
+        Self { key, range_end: None, count_only: None, limit: None }
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct RequestDeleteRange {
+    pub key: String,
+
+    // makes etcd return deleted key-values
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_kv: Option<bool>,
 }
 
 
@@ -81,4 +112,24 @@ pub(in crate::etcd_client) struct RequestRange {
 pub(in crate::etcd_client) struct RequestPut {
     pub key: String,
     pub value: String,
+
+    // the key is deleted when the lease expires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lease: Option<String>,
+}
+
+
+//==========|  LEASES  |============
+
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct LeaseGrantRequest {
+    #[serde(rename = "TTL")]
+    pub ttl: String,
+}
+
+// used by keepalive and revoke
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct LeaseRequest {
+    #[serde(rename = "ID")]
+    pub id: String,
 }
@@ -30,6 +30,17 @@ pub(in crate::etcd_client) struct RangeResult {
 
 
 
+//============|  DELETE  |===============
+
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct DeleteRangeResponse {
+    pub header: Header,
+    pub deleted: Option<String>,
+    pub prev_kvs: Option<Vec<RangeResult>>,
+}
+
+
+
 //============|  PUT  |==================
 
 #[derive(Serialize, Deserialize)]
@@ -37,6 +48,23 @@ pub(in crate::etcd_client) struct ResponsePut {
     pub header: Header,
 }
 
+//============|  LEASES  |===============
+
+// etcd omits zero fields, so an expired lease comes back without TTL
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct LeaseResponse {
+    #[serde(rename = "ID")]
+    pub id: Option<String>,
+    #[serde(rename = "TTL")]
+    pub ttl: Option<String>,
+}
+
+// keepalive is a stream in etcd, the gateway wraps its only message into result
+#[derive(Serialize, Deserialize)]
+pub(in crate::etcd_client) struct LeaseKeepAliveResponse {
+    pub result: Option<LeaseResponse>,
+}
+
 //=======================================
 
 
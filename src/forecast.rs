@@ -0,0 +1,240 @@
+/*
+    Forecasts of when sequences run out of ids.
+
+    A sequence declares its capacity in its policy, e.g. i32 if its ids go to a 32-bit column.
+    The maintenance leader samples positions of such sequences into a history kept in etcd at
+    meta/history/{seq}, so any instance can tell the consumption rate. The rate covers ids
+    taken from etcd, i.e. cached ones too, which is what counts against the capacity anyway.
+
+    Forecasts the leader computes on every sample are kept in memory for metrics, so scrapes
+    don't go to etcd. Other instances don't export them.
+ */
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::etcd_client::{EtcdClient, EtcdErr};
+use crate::interleave::Interleave;
+use crate::range::Range;
+
+pub const HISTORY_KEY_PREFIX: &str = "history/";
+
+// samples kept per sequence, spread evenly over the history window
+const MAX_SAMPLES: u64 = 48;
+
+
+/// The greatest id a sequence may hand out, by type of the column ids go to or as a number
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Capacity {
+    Type(IntType),
+    Max(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IntType {
+    I16,
+    I32,
+    U32,
+    I64,
+    U64,
+}
+
+impl Capacity {
+    pub fn max_id(&self) -> u64 {
+        match self {
+            Capacity::Type(IntType::I16) => i16::MAX as u64,
+            Capacity::Type(IntType::I32) => i32::MAX as u64,
+            Capacity::Type(IntType::U32) => u32::MAX as u64,
+            Capacity::Type(IntType::I64) => i64::MAX as u64,
+            Capacity::Type(IntType::U64) => u64::MAX,
+            Capacity::Max(max) => *max,
+        }
+    }
+}
+
+
+/// Positions of a sequence over time, stored in etcd
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct UsageHistory {
+    pub capacity: u64,
+    // (unix seconds, position)
+    pub samples: Vec<(u64, u64)>,
+}
+
+impl UsageHistory {
+    /// Adds a sample and drops ones older than the window. The latest sample replaces
+    /// the previous one if that is too close to the one before, so samples stay spread
+    pub fn record(&mut self, at: u64, position: u64, window_secs: u64) {
+        // the sequence was reset or recreated, the rate before doesn't tell anything
+        if self.samples.last().is_some_and(|(_, last)| *last > position) {
+            self.samples.clear();
+        }
+
+        let spacing = window_secs / MAX_SAMPLES;
+        let len = self.samples.len();
+
+        if len >= 2 && at.saturating_sub(self.samples[len - 2].0) < spacing {
+            self.samples[len - 1] = (at, position);
+        } else {
+            self.samples.push((at, position));
+        }
+
+        while self.samples.len() > 2 && self.samples[0].0 + window_secs < at {
+            self.samples.remove(0);
+        }
+    }
+
+    /// Ids per second between the oldest and the latest sample
+    pub fn rate(&self) -> Option<f64> {
+        let (first, last) = (self.samples.first()?, self.samples.last()?);
+        let secs = last.0.checked_sub(first.0).filter(|secs| *secs > 0)?;
+
+        Some(last.1.saturating_sub(first.1) as f64 / secs as f64)
+    }
+
+    fn span_secs(&self) -> u64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some(first), Some(last)) => last.0.saturating_sub(first.0),
+            _ => 0,
+        }
+    }
+}
+
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Forecast {
+    pub capacity: u64,
+    // the first id not taken from etcd yet
+    pub position: u64,
+    pub used_ratio: f64,
+    // None until the history has two samples
+    pub ids_per_sec: Option<f64>,
+    // None if the sequence doesn't grow
+    pub exhaustion_secs: Option<u64>,
+    // how far back the rate looks
+    pub history_secs: u64,
+}
+
+impl Forecast {
+    pub fn new(capacity: u64, position: u64, history: &UsageHistory) -> Self {
+        let ids_per_sec = history.rate();
+        let left = capacity.saturating_sub(position);
+
+        Self {
+            capacity,
+            position,
+            used_ratio: (position as f64 / capacity.max(1) as f64).min(1.0),
+            ids_per_sec,
+            exhaustion_secs: ids_per_sec.filter(|rate| *rate > 0.0).map(|rate| (left as f64 / rate) as u64),
+            history_secs: history.span_secs(),
+        }
+    }
+}
+
+
+/// Forecasts of sequences with a capacity as the capacity check computed them last.
+/// They are dropped once older than `max_age`, e.g. when the instance is no longer the leader
+pub struct LatestForecasts {
+    max_age: Duration,
+    latest: Mutex<Option<Published>>,
+}
+
+struct Published {
+    computed_at: Instant,
+    forecasts: Vec<(String, Forecast)>,
+}
+
+impl LatestForecasts {
+    pub fn new(max_age: Duration) -> Self {
+        Self { max_age, latest: Mutex::new(None) }
+    }
+
+    pub fn publish(&self, forecasts: Vec<(String, Forecast)>) {
+        *self.latest.lock().unwrap() = Some(Published { computed_at: Instant::now(), forecasts });
+    }
+
+    pub fn get(&self) -> Vec<(String, Forecast)> {
+        match self.latest.lock().unwrap().as_ref() {
+            Some(published) if published.computed_at.elapsed() < self.max_age => published.forecasts.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+
+/// The first id of the sequence value, u64::MAX if the value maps to no id
+pub fn position(interleave: Option<&Interleave>, value: u64) -> u64 {
+    match interleave {
+        Some(interleave) => interleave.to_ids(&Range::new(value, value)).map_or(u64::MAX, |ids| ids.begin),
+        None => value,
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Histories of all sequences that have one
+pub async fn load_histories(etcd: &EtcdClient) -> Result<Vec<(String, UsageHistory)>, EtcdErr> {
+    let values = etcd.list_meta(HISTORY_KEY_PREFIX).await?;
+
+    Ok(values.into_iter()
+        .filter_map(|(seq_name, value)| match serde_json::from_slice(&value) {
+            Ok(history) => Some((seq_name, history)),
+            Err(err) => {
+                warn!("Usage history of sequence '{}' in etcd is malformed and is ignored: {}", seq_name, err);
+                None
+            }
+        })
+        .collect())
+}
+
+pub async fn load_history(etcd: &EtcdClient, seq_name: &str) -> Result<UsageHistory, EtcdErr> {
+    let value = etcd.get_meta(&history_key(seq_name)).await?;
+    Ok(value.and_then(|v| serde_json::from_slice(&v).ok()).unwrap_or_default())
+}
+
+pub async fn store_history(etcd: &EtcdClient, seq_name: &str, history: &UsageHistory) -> Result<(), EtcdErr> {
+    etcd.put_meta(&history_key(seq_name), &serde_json::to_vec(history).unwrap()).await?;
+    Ok(())
+}
+
+pub async fn delete_history(etcd: &EtcdClient, seq_name: &str) -> Result<(), EtcdErr> {
+    etcd.delete_meta(&history_key(seq_name)).await?;
+    Ok(())
+}
+
+fn history_key(seq_name: &str) -> String {
+    format!("{}{}", HISTORY_KEY_PREFIX, seq_name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Capacity, Forecast, UsageHistory};
+
+    #[test]
+    fn exhaustion_is_forecast_from_history() {
+        let capacity: Capacity = serde_json::from_str("\"i32\"").unwrap();
+        assert_eq!(i32::MAX as u64, capacity.max_id());
+        assert_eq!(Capacity::Max(1000), serde_json::from_str("1000").unwrap());
+
+        let mut history = UsageHistory::default();
+        history.record(1000, 100, 4800);
+        // too close to the previous sample, so it replaces the latest one
+        history.record(1010, 150, 4800);
+        history.record(1050, 200, 4800);
+        assert_eq!(vec![(1000, 100), (1050, 200)], history.samples);
+
+        let forecast = Forecast::new(1200, 200, &history);
+        assert_eq!(Some(2.0), forecast.ids_per_sec);
+        assert_eq!(Some(500), forecast.exhaustion_secs);
+
+        // a reset starts the history over
+        history.record(1200, 10, 4800);
+        assert_eq!(None, history.rate());
+    }
+}
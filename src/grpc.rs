@@ -0,0 +1,501 @@
+/*
+    gRPC API, served alongside the HTTP one and backed by the same RangeProvider.
+
+    Tonic needs Send futures, while RangeProvider talks to etcd via awc and stays on the thread it
+    was created on. So gRPC gets its own actix System thread: tonic handlers check the caller and
+    pass the work as jobs through a channel to a task that runs them on that thread.
+ */
+
+// tonic's Status is large, but it is what every handler returns anyway
+#![allow(clippy::result_large_err)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
+use futures::future::LocalBoxFuture;
+use futures::Stream;
+use log::error;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tonic::metadata::{KeyRef, MetadataMap};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{FutureExt, TraceContextExt};
+use tonic::transport::Server;
+use crate::AppData;
+use crate::audit::{AuditEntry, Operation};
+use crate::auth::{resolve_caller, Caller};
+use crate::config::{AuthProps, GrpcProps, Permission};
+use crate::etcd_client::{CreateSeqTxErr, EtcdErr, GetRangeErr};
+use crate::range::{self, RangeProviderErr};
+use crate::rate_limit::{client_identity_of, RateLimiter};
+use crate::telemetry;
+use crate::logging;
+
+pub mod proto {
+    tonic::include_proto!("ngamahi.idgen.v1");
+}
+
+use proto::id_gen_server::{IdGen, IdGenServer};
+use proto::stream_ranges_request::Kind;
+use proto::{BatchNextRangeRequest, BatchNextRangeResponse, CreateSequenceRequest, CreateSequenceResponse,
+            NextRangeRequest, NextRangeResponse, StreamRangesRequest};
+
+type Job = Box<dyn FnOnce(AppData) -> LocalBoxFuture<'static, ()> + Send>;
+
+
+/// Serves gRPC on its own thread. `make_data` runs on that thread
+pub fn start(props: &GrpcProps, make_data: impl FnOnce() -> AppData + Send + 'static) -> std::io::Result<()> {
+    let addr = props.addr;
+
+    thread::Builder::new().name("grpc".to_string()).spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let data = make_data();
+            let (jobs, mut jobs_rx) = mpsc::unbounded_channel::<Job>();
+
+            let service = IdGenService {
+                jobs,
+                auth: data.auth.clone().map(Arc::new),
+                rate_limiter: data.rate_limiter.clone(),
+            };
+
+            actix_web::rt::spawn(async move {
+                while let Some(job) = jobs_rx.recv().await {
+                    actix_web::rt::spawn(job(data.clone()));
+                }
+            });
+
+            if let Err(err) = Server::builder().add_service(IdGenServer::new(service)).serve(addr).await {
+                error!("gRPC server stopped: {}", err);
+            }
+        })
+    })?;
+
+    Ok(())
+}
+
+
+#[derive(Clone)]
+struct IdGenService {
+    jobs: mpsc::UnboundedSender<Job>,
+    auth: Option<Arc<AuthProps>>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl IdGenService {
+    // runs `f` on the gRPC thread, where RangeProvider lives
+    async fn run<T, F, Fut>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(AppData) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        // spans of the job are children of the request's one and its logs carry the request's id
+        let cx = Context::current();
+        let request_id = logging::current_request_id().unwrap_or_default();
+
+        let job: Job = Box::new(move |data| Box::pin(logging::with_request_id(request_id, async move {
+            let _ = reply.send(f(data).await);
+        }.with_context(cx))));
+
+        self.jobs.send(job).map_err(|_| Status::unavailable("Server is shutting down"))?;
+        result.await.map_err(|_| Status::internal("Request was dropped"))
+    }
+
+    fn caller<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let key = request.metadata().get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        resolve_caller(self.auth.as_deref(), key).ok_or_else(|| Status::unauthenticated("Missing or unknown API key"))
+    }
+
+    async fn allocate(&self, caller: &Caller, client: &str, req: NextRangeRequest) -> Result<Vec<proto::Range>, Status> {
+        let seq_name = seq_name(&req.tenant, &req.sequence)?;
+        check_permission(caller, &seq_name, Permission::Read)?;
+
+        if let Err(wait) = self.rate_limiter.check(client, &seq_name, req.size) {
+            let mut status = Status::resource_exhausted(format!("Rate limit for sequence '{}' exceeded", seq_name));
+            status.metadata_mut().insert("retry-after", wait.as_secs_f64().ceil().to_string().parse().unwrap());
+            return Err(status);
+        }
+
+        self.run(move |data| async move {
+            let ranges = match req.tenant.as_str() {
+                "" => data.seq_provider.get_next_range(req.sequence, req.size).await,
+                tenant => data.seq_provider.get_next_tenant_range(tenant.to_string(), req.sequence, req.size).await,
+            };
+
+            ranges.map(|r| r.into_iter().map(proto::Range::from).collect()).map_err(to_status)
+        }).await?
+    }
+}
+
+
+#[tonic::async_trait]
+impl IdGen for IdGenService {
+    async fn create_sequence(&self, request: Request<CreateSequenceRequest>) -> Result<Response<CreateSequenceResponse>, Status> {
+        let call = start_call(&request, "CreateSequence");
+        call.serve(self.create_sequence_traced(request)).await
+    }
+
+    async fn next_range(&self, request: Request<NextRangeRequest>) -> Result<Response<NextRangeResponse>, Status> {
+        let call = start_call(&request, "NextRange");
+        call.serve(self.next_range_traced(request)).await
+    }
+
+    async fn batch_next_range(&self, request: Request<BatchNextRangeRequest>) -> Result<Response<BatchNextRangeResponse>, Status> {
+        let call = start_call(&request, "BatchNextRange");
+        call.serve(self.batch_next_range_traced(request)).await
+    }
+
+    type StreamRangesStream = Pin<Box<dyn Stream<Item = Result<NextRangeResponse, Status>> + Send>>;
+
+    async fn stream_ranges(&self, request: Request<Streaming<StreamRangesRequest>>) -> Result<Response<Self::StreamRangesStream>, Status> {
+        let call = start_call(&request, "StreamRanges");
+        call.serve(self.stream_ranges_traced(request)).await
+    }
+}
+
+
+impl IdGenService {
+    async fn create_sequence_traced(&self, request: Request<CreateSequenceRequest>) -> Result<Response<CreateSequenceResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let source_ip = request.remote_addr().map(|a| a.ip());
+        let req = request.into_inner();
+
+        let seq_name = seq_name(&req.tenant, &req.sequence)?;
+        check_permission(&caller, &seq_name, Permission::Admin)?;
+
+        let entry = AuditEntry::new(Operation::Create, &seq_name, &caller, source_ip);
+
+        self.run(move |data| async move {
+            let write = match req.tenant.as_str() {
+                "" => data.seq_provider.create_sequence(req.sequence).await,
+                tenant => data.seq_provider.create_tenant_sequence(tenant.to_string(), req.sequence).await,
+            }.map_err(to_status)?;
+
+            data.audit.record(entry.with_write(write)).await;
+            Ok::<_, Status>(())
+        }).await??;
+
+        Ok(Response::new(CreateSequenceResponse {}))
+    }
+
+    async fn next_range_traced(&self, request: Request<NextRangeRequest>) -> Result<Response<NextRangeResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let client = client_id(&caller, &request);
+        let req = request.into_inner();
+
+        let sequence = req.sequence.clone();
+        let ranges = self.allocate(&caller, &client, req).await?;
+
+        Ok(Response::new(NextRangeResponse { sequence, ranges, error: String::new() }))
+    }
+
+    async fn batch_next_range_traced(&self, request: Request<BatchNextRangeRequest>) -> Result<Response<BatchNextRangeResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let client = client_id(&caller, &request);
+
+        let mut responses = vec![];
+
+        for req in request.into_inner().requests {
+            let sequence = req.sequence.clone();
+
+            let response = match self.allocate(&caller, &client, req).await {
+                Ok(ranges) => NextRangeResponse { sequence, ranges, error: String::new() },
+                Err(status) => NextRangeResponse { sequence, ranges: vec![], error: status.message().to_string() },
+            };
+
+            responses.push(response);
+        }
+
+        Ok(Response::new(BatchNextRangeResponse { responses }))
+    }
+
+    async fn stream_ranges_traced(&self, request: Request<Streaming<StreamRangesRequest>>) -> Result<Response<<Self as IdGen>::StreamRangesStream>, Status> {
+        let caller = self.caller(&request)?;
+        let client = client_id(&caller, &request);
+        let mut inbound = request.into_inner();
+
+        let start = match inbound.next().await {
+            Some(Ok(StreamRangesRequest { kind: Some(Kind::Start(start)) })) => start,
+            Some(Err(status)) => return Err(status),
+            _ => return Err(Status::invalid_argument("The first message of a stream must be 'start'")),
+        };
+
+        let (responses, responses_rx) = mpsc::channel(1);
+        let service = self.clone();
+
+        // the stream ends when the client stops sending acks or a request fails.
+        // Tonic serves connections on tokio tasks outside of actix's local set, so this can't be spawned locally
+        tokio::spawn(async move {
+            let mut next = Some(start);
+
+            while let Some(req) = next.take() {
+                let sequence = req.sequence.clone();
+                let response = service.allocate(&caller, &client, req.clone()).await
+                    .map(|ranges| NextRangeResponse { sequence, ranges, error: String::new() });
+
+                let failed = response.is_err();
+                if responses.send(response).await.is_err() || failed {
+                    break;
+                }
+
+                next = match inbound.next().await {
+                    Some(Ok(StreamRangesRequest { kind: Some(Kind::Ack(_)) })) => Some(req),
+                    Some(Ok(_)) => {
+                        let _ = responses.send(Err(Status::invalid_argument("Only acks may follow 'start'"))).await;
+                        None
+                    }
+                    _ => None,
+                };
+            }
+        }.with_context(Context::current()));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(responses_rx))))
+    }
+}
+
+
+impl From<range::Range> for proto::Range {
+    fn from(r: range::Range) -> Self {
+        Self { begin: r.begin, end: r.end, stride: r.stride }
+    }
+}
+
+fn seq_name(tenant: &str, seq: &str) -> Result<String, Status> {
+    let tenant = Some(tenant).filter(|t| !t.is_empty());
+    range::checked_seq_name(tenant, seq).map_err(to_status)
+}
+
+fn check_permission(caller: &Caller, seq_name: &str, permission: Permission) -> Result<(), Status> {
+    if caller.is_allowed(seq_name, permission) {
+        return Ok(());
+    }
+
+    Err(Status::permission_denied(
+        format!("Key '{}' has no {:?} permission for sequence '{}'", caller.name(), permission, seq_name)))
+}
+
+// a call gets a server span, continuing the trace context the client sent in metadata, and a request id
+struct Call {
+    cx: Context,
+    request_id: String,
+}
+
+fn start_call<T>(request: &Request<T>, method: &'static str) -> Call {
+    let attributes = vec![KeyValue::new("rpc.system", "grpc"), KeyValue::new("rpc.method", method)];
+    let cx = telemetry::continue_trace(&MetadataExtractor(request.metadata()), format!("IdGen/{}", method), attributes);
+
+    let sent = request.metadata().get(logging::REQUEST_ID_HEADER).and_then(|v| v.to_str().ok());
+    let request_id = logging::request_id(sent, &cx);
+    cx.span().set_attribute(KeyValue::new(telemetry::REQUEST_ID, request_id.clone()));
+
+    Call { cx, request_id }
+}
+
+impl Call {
+    async fn serve<T>(self, handler: impl Future<Output = Result<Response<T>, Status>>) -> Result<Response<T>, Status> {
+        let mut response = logging::with_request_id(self.request_id.clone(), telemetry::in_span(self.cx, handler)).await?;
+
+        if let Ok(value) = self.request_id.parse() {
+            response.metadata_mut().insert(logging::REQUEST_ID_HEADER, value);
+        }
+
+        Ok(response)
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys()
+            .filter_map(|k| match k {
+                KeyRef::Ascii(k) => Some(k.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn client_id<T>(caller: &Caller, request: &Request<T>) -> String {
+    client_identity_of(caller, request.remote_addr().map(|a| a.ip().to_string()).as_deref())
+}
+
+fn to_status(err: RangeProviderErr) -> Status {
+    match err {
+        RangeProviderErr::Validation(msg) => Status::invalid_argument(msg),
+        RangeProviderErr::NoSuchTenant(tenant) => Status::not_found(format!("No such tenant '{}'", tenant)),
+        RangeProviderErr::TenantLimit(msg) => Status::resource_exhausted(msg),
+        RangeProviderErr::Etcd(EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(seq))) =>
+            Status::not_found(format!("No such sequence '{}'", seq)),
+        RangeProviderErr::Etcd(EtcdErr::CreateSeqTxErr(CreateSeqTxErr::SeqAlreadyExists { .. })) =>
+            Status::already_exists("Sequence already exists"),
+        RangeProviderErr::SeqChanged(seq) => Status::aborted(format!("Sequence '{}' changed meanwhile, retry", seq)),
+        err => Status::internal(format!("{:?}", err)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Duration;
+    use tokio_stream::StreamExt;
+    use tonic::{Code, Request, Status};
+    use tonic::transport::Channel;
+    use crate::config::Properties;
+    use crate::demand::DemandTracker;
+    use crate::forecast::LatestForecasts;
+    use crate::fake_etcd::FakeEtcd;
+    use crate::policy::PolicyStore;
+    use crate::rate_limit::RateLimiter;
+    use crate::{cache, etcd_client, get_app_data, Shared};
+    use super::proto::id_gen_client::IdGenClient;
+    use super::proto::stream_ranges_request::Kind;
+    use super::proto::{Ack, BatchNextRangeRequest, CreateSequenceRequest, NextRangeRequest, Range, StreamRangesRequest};
+
+    const OPS_KEY: &str = "ops-key";
+    const READER_KEY: &str = "reader-key";
+
+    // serves gRPC on a free port the way main does, returns a client of it
+    async fn start(etcd: &FakeEtcd) -> IdGenClient<Channel> {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let props: Properties = serde_yaml::from_str(&format!(r#"
+            etcd_addr: "{}"
+            etcd_fetch_range_size: 100
+            client_range_max_size: 100
+            key_prefix: "ids/"
+            grpc: {{ addr: "127.0.0.1:{}" }}
+            tenants:
+              acme: {{ max_sequences: 10, client_range_max_size: 100 }}
+            auth:
+              api_keys:
+                - name: ops
+                  key: {}
+                  grants: [ {{ pattern: "*", permissions: [ read, admin ] }} ]
+                - name: reader
+                  key: {}
+                  grants: [ {{ pattern: "orders", permissions: [ read ] }} ]
+        "#, etcd.url(), port, OPS_KEY, READER_KEY)).unwrap();
+
+        let policies = Arc::new(PolicyStore::new(Duration::from_secs(60)));
+        let shared = Shared {
+            caches: cache::new_all(&props.cache),
+            rate_limiter: Arc::new(RateLimiter::new(props.rate_limits.clone(), policies.clone())),
+            demand: Arc::new(DemandTracker::new(props.etcd_fetch_range_size, None)),
+            journal: None,
+            reservations: None,
+            max_client_range_size: Arc::new(AtomicU64::new(props.client_range_max_size)),
+            policies,
+            forecasts: Arc::new(LatestForecasts::new(Duration::ZERO)),
+        };
+
+        let grpc_props = props.grpc.clone().unwrap();
+        super::start(&grpc_props, move || get_app_data(props, shared, etcd_client::new_http_client(awc::Client::default()))).unwrap();
+
+        // the server comes up on its own thread
+        for _ in 0..100 {
+            if let Ok(client) = IdGenClient::connect(format!("http://127.0.0.1:{}", port)).await {
+                return client;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("gRPC server didn't start");
+    }
+
+    fn with_key<T>(message: T, key: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert("authorization", format!("Bearer {}", key).parse().unwrap());
+        request
+    }
+
+    fn create(sequence: &str, tenant: &str) -> CreateSequenceRequest {
+        CreateSequenceRequest { sequence: sequence.to_string(), tenant: tenant.to_string() }
+    }
+
+    fn next(sequence: &str, tenant: &str, size: u64) -> NextRangeRequest {
+        NextRangeRequest { sequence: sequence.to_string(), tenant: tenant.to_string(), size }
+    }
+
+    fn range(begin: u64, end: u64) -> Vec<Range> {
+        vec![Range { begin, end, stride: 1 }]
+    }
+
+    #[actix_web::test]
+    async fn creates_sequences_and_allocates_ranges() {
+        let etcd = FakeEtcd::start();
+        let mut client = start(&etcd).await;
+
+        client.create_sequence(with_key(create("orders", ""), OPS_KEY)).await.unwrap();
+        client.create_sequence(with_key(create("invoices", "acme"), OPS_KEY)).await.unwrap();
+        assert_eq!(Some(0), etcd.get_u64("ids/tenants/acme/invoices"));
+
+        let response = client.next_range(with_key(next("orders", "", 10), OPS_KEY)).await.unwrap().into_inner();
+        assert_eq!(range(0, 10), response.ranges);
+
+        let tenant = client.next_range(with_key(next("invoices", "acme", 5), OPS_KEY)).await.unwrap().into_inner();
+        assert_eq!(range(0, 5), tenant.ranges);
+
+        // a failed request of a batch doesn't fail the others
+        let batch = BatchNextRangeRequest { requests: vec![next("orders", "", 5), next("missing", "", 5), next("orders", "", 5)] };
+        let batch = client.batch_next_range(with_key(batch, OPS_KEY)).await.unwrap().into_inner().responses;
+        assert_eq!(range(10, 15), batch[0].ranges);
+        assert!(batch[1].error.starts_with("No such sequence"), "{}", batch[1].error);
+        assert_eq!(range(15, 20), batch[2].ranges);
+
+        // every ack asks for the next ranges of the size the stream started with
+        let requests = [Kind::Start(next("orders", "", 5)), Kind::Ack(Ack {}), Kind::Ack(Ack {})]
+            .map(|kind| StreamRangesRequest { kind: Some(kind) });
+        let streamed: Vec<_> = client.stream_ranges(with_key(tokio_stream::iter(requests), OPS_KEY)).await.unwrap()
+            .into_inner()
+            .map(|response| response.unwrap().ranges)
+            .collect().await;
+        assert_eq!(vec![range(20, 25), range(25, 30), range(30, 35)], streamed);
+    }
+
+    fn code<T>(result: Result<T, Status>) -> Code {
+        result.map(|_| ()).unwrap_err().code()
+    }
+
+    #[actix_web::test]
+    async fn failures_map_to_grpc_codes() {
+        let etcd = FakeEtcd::start();
+        let mut client = start(&etcd).await;
+        client.create_sequence(with_key(create("orders", ""), OPS_KEY)).await.unwrap();
+
+        assert_eq!(Code::Unauthenticated, code(client.next_range(next("orders", "", 1)).await));
+        assert_eq!(Code::Unauthenticated, code(client.next_range(with_key(next("orders", "", 1), "wrong-key")).await));
+
+        // the reader may only read orders
+        assert!(client.next_range(with_key(next("orders", "", 1), READER_KEY)).await.is_ok());
+        assert_eq!(Code::PermissionDenied, code(client.create_sequence(with_key(create("other", ""), READER_KEY)).await));
+        assert_eq!(Code::PermissionDenied, code(client.next_range(with_key(next("other", "", 1), READER_KEY)).await));
+
+        assert_eq!(Code::AlreadyExists, code(client.create_sequence(with_key(create("orders", ""), OPS_KEY)).await));
+        assert_eq!(Code::NotFound, code(client.next_range(with_key(next("missing", "", 1), OPS_KEY)).await));
+        assert_eq!(Code::NotFound, code(client.next_range(with_key(next("orders", "nobody", 1), OPS_KEY)).await));
+
+        // names must not reach other tenants' sequences or meta keys
+        assert_eq!(Code::InvalidArgument, code(client.create_sequence(with_key(create("meta/leader", ""), OPS_KEY)).await));
+        assert_eq!(Code::InvalidArgument, code(client.next_range(with_key(next("orders", "acme/x", 1), OPS_KEY)).await));
+        assert_eq!(None, etcd.get("ids/meta/leader"));
+
+        // a stream must start with 'start'
+        let ack = tokio_stream::iter([StreamRangesRequest { kind: Some(Kind::Ack(Ack {})) }]);
+        assert_eq!(Code::InvalidArgument, code(client.stream_ranges(with_key(ack, OPS_KEY)).await));
+    }
+}
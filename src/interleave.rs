@@ -0,0 +1,100 @@
+/*
+    Ids of interleaved sequences, for deployments whose regions allocate from separate etcd clusters.
+
+    Etcd keeps the sequence value as usual, and every region maps the values it allocates to its own
+    subset of ids: either ids congruent to its index modulo region count (ranges get a stride), or its
+    own partition of the id space selected by high bits.
+ */
+
+use crate::config::{InterleaveMode, InterleaveProps};
+use crate::range::Range;
+
+
+#[derive(Clone, Debug)]
+pub struct Interleave {
+    mode: InterleaveMode,
+    index: u64,
+    count: u64,
+}
+
+impl Interleave {
+    pub fn new(props: &InterleaveProps) -> Self {
+        Self { mode: props.mode, index: props.index, count: props.count }
+    }
+
+    /// Ids of a range of sequence values, None if they don't fit in u64
+    pub fn to_ids(&self, values: &Range) -> Option<Range> {
+        match self.mode {
+            InterleaveMode::Modulo => Some(Range {
+                begin: values.begin.checked_mul(self.count)?.checked_add(self.index)?,
+                end: values.end.checked_mul(self.count)?.checked_add(self.index)?,
+                stride: self.count,
+            }),
+
+            InterleaveMode::HighBits => {
+                if self.partition_size().is_some_and(|size| values.end > size) {
+                    return None;
+                }
+
+                // the end of the last partition is past u64::MAX
+                Some(Range::new(self.partition_begin() + values.begin, self.partition_begin().checked_add(values.end)?))
+            }
+        }
+    }
+
+    /// The least sequence value whose id is not less than `id`
+    pub fn value_for_id(&self, id: u64) -> u64 {
+        match self.mode {
+            InterleaveMode::Modulo => id.saturating_sub(self.index).div_ceil(self.count),
+            InterleaveMode::HighBits => id.saturating_sub(self.partition_begin()),
+        }
+    }
+
+    // ids of a partition, None if the whole id space is one partition
+    fn partition_size(&self) -> Option<u64> {
+        match self.count {
+            1 => None,
+            count => Some(1 << (64 - (count - 1).ilog2() - 1)),
+        }
+    }
+
+    fn partition_begin(&self) -> u64 {
+        self.partition_size().map_or(0, |size| self.index * size)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{InterleaveMode, InterleaveProps};
+    use crate::range::Range;
+    use super::Interleave;
+
+    fn interleave(mode: InterleaveMode, index: u64, count: u64) -> Interleave {
+        Interleave::new(&InterleaveProps { pattern: "*".to_string(), mode, index, count })
+    }
+
+    #[test]
+    fn regions_get_disjoint_ids() {
+        let values = Range::new(10, 20);
+
+        let first = interleave(InterleaveMode::Modulo, 0, 2).to_ids(&values).unwrap();
+        let second = interleave(InterleaveMode::Modulo, 1, 2).to_ids(&values).unwrap();
+        assert_eq!((20..40).step_by(2).collect::<Vec<_>>(), first.ids().collect::<Vec<_>>());
+        assert_eq!((21..41).step_by(2).collect::<Vec<_>>(), second.ids().collect::<Vec<_>>());
+
+        // 3 regions take 2 high bits
+        let third = interleave(InterleaveMode::HighBits, 2, 3);
+        assert_eq!(Some(Range::new((2 << 62) + 10, (2 << 62) + 20)), third.to_ids(&values));
+        assert_eq!(None, third.to_ids(&Range::new(0, (1 << 62) + 1)));
+        assert_eq!(5, third.value_for_id((2 << 62) + 5));
+    }
+
+    #[test]
+    fn last_partition_ends_below_u64_max() {
+        let last = interleave(InterleaveMode::HighBits, 1, 2);
+
+        assert_eq!(Some(Range::new(u64::MAX - 1, u64::MAX)), last.to_ids(&Range::new((1 << 63) - 2, (1 << 63) - 1)));
+        assert_eq!(None, last.to_ids(&Range::new((1 << 63) - 1, 1 << 63)));
+    }
+}
@@ -0,0 +1,362 @@
+/*
+    On-disk journal of cached ranges, lets an instance resume serving them after a restart.
+
+    Every range taken from etcd and every range served to clients is appended as an NDJSON
+    record. Replaying the journal gives ids that were fetched but not served yet. A fetch record
+    keeps the etcd mod revision of the sequence it left, ranges are resumed only if the sequence
+    is still at that revision. Etcd can't tell a reset followed by an advance past the journaled
+    value from fetches of other instances, so ranges of a sequence written by anyone else meanwhile
+    are given up too.
+
+    Records are written by a thread of their own. Requests queued while it writes go in the next
+    batch with one write and at most one fsync, a request waits only for the batch of its records.
+
+    The journal is rewritten with only unserved ranges on start and then every COMPACT_EVERY records.
+ */
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use crate::config::{FsyncPolicy, PersistenceProps};
+use crate::etcd_client::{EtcdClient, EtcdErr, GetRangeErr};
+use crate::range::Range;
+
+const COMPACT_EVERY: u64 = 100_000;
+
+
+pub struct Journal {
+    appends: Sender<Append>,
+
+    // unserved ranges found on start, handed over to cache on first request of their sequence
+    restored: Mutex<HashMap<String, Vec<Range>>>,
+}
+
+struct Append {
+    record: Record,
+    // absent if nobody waits for the record to be written
+    written: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+// owned by the writer thread
+struct Writer {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    fsync_interval: Duration,
+    file: File,
+    seqs: HashMap<String, JournaledSeq>,
+    records: u64,
+    last_sync: Instant,
+}
+
+#[derive(Default)]
+struct JournaledSeq {
+    unserved: Vec<Range>,
+
+    // etcd mod revision of the sequence right after the last fetch
+    mod_revision: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    // records of older journals have no revision, their ranges are not resumed
+    Fetched { seq: String, range: Range, #[serde(default)] mod_revision: u64 },
+    Served { seq: String, ranges: Vec<Range> },
+    Dropped { seq: String },
+}
+
+
+impl Journal {
+    /// Replays the journal and keeps unserved ranges of sequences nobody else wrote in etcd since
+    pub async fn open(props: &PersistenceProps, etcd: &EtcdClient) -> Result<Self, JournalErr> {
+        let path = PathBuf::from(&props.path);
+
+        let mut seqs = HashMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => apply(&mut seqs, record),
+                    // the last line may be cut short by a crash
+                    Err(err) => warn!("Skipping bad journal record '{}': {}", line, err),
+                }
+            }
+        }
+
+        let mut restored = HashMap::new();
+
+        for (seq_name, seq) in seqs.iter_mut() {
+            let current = match etcd.get_seq(seq_name.clone()).await {
+                Ok(current) => current.mod_revision,
+                Err(EtcdErr::NoSuchRangeErr(GetRangeErr::NoSuchSeq(_))) => 0,
+                Err(err) => return Err(JournalErr::Etcd(err)),
+            };
+
+            if current != seq.mod_revision {
+                warn!("Sequence '{}' is at revision {} in etcd but was at {} when its ranges were journaled, \
+                       they won't be resumed", seq_name, current, seq.mod_revision);
+                seq.unserved.clear();
+                continue;
+            }
+
+            info!("Resuming {} ranges of sequence '{}'", seq.unserved.len(), seq_name);
+            restored.insert(seq_name.clone(), seq.unserved.clone());
+        }
+
+        seqs.retain(|_, seq| !seq.unserved.is_empty());
+
+        let file = rewrite(&path, &seqs)?;
+        let writer = Writer {
+            path,
+            fsync: props.fsync,
+            fsync_interval: Duration::from_millis(props.fsync_interval_ms),
+            file,
+            seqs,
+            records: 0,
+            last_sync: Instant::now(),
+        };
+
+        let (appends, received) = mpsc::channel();
+        thread::Builder::new().name("journal".to_string()).spawn(move || writer.run(received))?;
+
+        Ok(Self { appends, restored: Mutex::new(restored) })
+    }
+
+    pub fn take_restored(&self, seq_name: &str) -> Option<Vec<Range>> {
+        self.restored.lock().unwrap().remove(seq_name)
+    }
+
+    /// Mod revision is the one etcd gave to the fetch of the range
+    pub async fn fetched(&self, seq_name: &str, range: &Range, mod_revision: u64) -> io::Result<()> {
+        self.write(Record::Fetched { seq: seq_name.to_string(), range: range.clone(), mod_revision }).await
+    }
+
+    pub async fn served(&self, seq_name: &str, ranges: &[Range]) -> io::Result<()> {
+        self.write(Record::Served { seq: seq_name.to_string(), ranges: ranges.to_vec() }).await
+    }
+
+    /// Ranges of the sequence must not be resumed, e.g. it was reset or deleted.
+    /// Nothing waits for the record, a sequence written meanwhile is caught by its revision on restart anyway
+    pub fn dropped(&self, seq_name: &str) -> io::Result<()> {
+        self.restored.lock().unwrap().remove(seq_name);
+        self.send(Append { record: Record::Dropped { seq: seq_name.to_string() }, written: None })
+    }
+
+    // resolves once the batch of the record is written, and synced if the policy says so
+    async fn write(&self, record: Record) -> io::Result<()> {
+        let (written, done) = oneshot::channel();
+        self.send(Append { record, written: Some(written) })?;
+
+        done.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+
+    fn send(&self, append: Append) -> io::Result<()> {
+        self.appends.send(append).map_err(|_| writer_gone())
+    }
+}
+
+
+impl Writer {
+    // ends when the journal is dropped
+    fn run(mut self, appends: Receiver<Append>) {
+        while let Ok(first) = appends.recv() {
+            let batch: Vec<Append> = std::iter::once(first).chain(appends.try_iter()).collect();
+            let (records, waiters): (Vec<_>, Vec<_>) = batch.into_iter().map(|a| (a.record, a.written)).unzip();
+
+            let result = self.write(records);
+            if let Err(err) = &result {
+                error!("Couldn't write {} journal records: {}", waiters.len(), err);
+            }
+
+            for written in waiters.into_iter().flatten() {
+                // the request may be gone already
+                let _ = written.send(result.as_ref().map(|_| ()).map_err(|err| io::Error::new(err.kind(), err.to_string())));
+            }
+        }
+    }
+
+    fn write(&mut self, records: Vec<Record>) -> io::Result<()> {
+        let mut lines = String::new();
+        for record in &records {
+            lines.push_str(&serde_json::to_string(record).unwrap());
+            lines.push('\n');
+        }
+
+        self.file.write_all(lines.as_bytes())?;
+
+        let must_sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval => self.last_sync.elapsed() >= self.fsync_interval,
+            FsyncPolicy::Never => false,
+        };
+
+        if must_sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        self.records += records.len() as u64;
+        for record in records {
+            apply(&mut self.seqs, record);
+        }
+
+        if self.records >= COMPACT_EVERY {
+            self.file = rewrite(&self.path, &self.seqs)?;
+            self.records = 0;
+        }
+
+        Ok(())
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "journal writer is gone")
+}
+
+
+fn apply(seqs: &mut HashMap<String, JournaledSeq>, record: Record) {
+    match record {
+        Record::Fetched { seq, range, mod_revision } => {
+            let seq = seqs.entry(seq).or_default();
+            seq.unserved.push(range);
+            seq.mod_revision = seq.mod_revision.max(mod_revision);
+        }
+
+        Record::Served { seq: seq_name, ranges } => {
+            if let Some(seq) = seqs.get_mut(&seq_name) {
+                for served in &ranges {
+                    seq.unserved = subtract(&seq.unserved, served);
+                }
+
+                if seq.unserved.is_empty() {
+                    seqs.remove(&seq_name);
+                }
+            }
+        }
+
+        Record::Dropped { seq } => {
+            seqs.remove(&seq);
+        }
+    }
+}
+
+fn subtract(ranges: &[Range], served: &Range) -> Vec<Range> {
+    let mut rest = Vec::with_capacity(ranges.len() + 1);
+
+    for r in ranges {
+        if r.end <= served.begin || served.end <= r.begin {
+            rest.push(r.clone());
+            continue;
+        }
+
+        if r.begin < served.begin {
+            rest.push(Range::new(r.begin, served.begin));
+        }
+
+        if served.end < r.end {
+            rest.push(Range::new(served.end, r.end));
+        }
+    }
+
+    rest
+}
+
+// replaces the journal with one fetch record per unserved range and opens it for appending
+fn rewrite(path: &PathBuf, seqs: &HashMap<String, JournaledSeq>) -> io::Result<File> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+
+    for (seq_name, seq) in seqs {
+        for range in &seq.unserved {
+            let record = Record::Fetched { seq: seq_name.clone(), range: range.clone(), mod_revision: seq.mod_revision };
+            writeln!(tmp, "{}", serde_json::to_string(&record).unwrap())?;
+        }
+    }
+
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum JournalErr {
+    Io(io::Error),
+    Etcd(EtcdErr),
+}
+
+impl From<io::Error> for JournalErr {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use crate::config::PersistenceProps;
+    use crate::etcd_client;
+    use crate::fake_etcd::FakeEtcd;
+    use crate::range::Range;
+    use super::{apply, Journal, Record};
+
+    #[test]
+    fn served_ranges_are_subtracted() {
+        let mut seqs = HashMap::new();
+
+        apply(&mut seqs, Record::Fetched { seq: "a".to_string(), range: Range::new(0, 100), mod_revision: 2 });
+        apply(&mut seqs, Record::Served { seq: "a".to_string(), ranges: vec![Range::new(0, 10), Range::new(50, 60)] });
+        assert_eq!(vec![Range::new(10, 50), Range::new(60, 100)], seqs["a"].unserved);
+
+        apply(&mut seqs, Record::Served { seq: "a".to_string(), ranges: vec![Range::new(10, 50), Range::new(60, 100)] });
+        assert!(!seqs.contains_key("a"));
+
+        apply(&mut seqs, Record::Fetched { seq: "b".to_string(), range: Range::new(0, 100), mod_revision: 2 });
+        apply(&mut seqs, Record::Dropped { seq: "b".to_string() });
+        assert!(seqs.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn ranges_are_resumed_only_if_nobody_wrote_their_sequence_since() {
+        let etcd = FakeEtcd::start();
+        let client = etcd_client::new_etcd_client(
+            etcd_client::new_http_client(awc::Client::default()), etcd.url(), "ids/".to_string());
+        etcd.put_u64("ids/orders", 100);
+        etcd.put_u64("ids/invoices", 100);
+
+        let path = std::env::temp_dir().join(format!("id-gen-journal-{}.ndjson", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let props: PersistenceProps = serde_yaml::from_str(&format!("path: {:?}", path)).unwrap();
+
+        let journal = Journal::open(&props, &client).await.unwrap();
+        for seq in ["orders", "invoices"] {
+            let (range, mod_revision) = client.next_range(seq.to_string(), 100).await.unwrap();
+            journal.fetched(seq, &range, mod_revision).await.unwrap();
+        }
+        journal.served("orders", &[Range::new(100, 110)]).await.unwrap();
+        drop(journal);
+
+        // reset below the journaled range and advanced past it, the value alone can't tell
+        etcd.put_u64("ids/invoices", 0);
+        etcd.put_u64("ids/invoices", 500);
+
+        let journal = Journal::open(&props, &client).await.unwrap();
+        assert_eq!(Some(vec![Range::new(110, 200)]), journal.take_restored("orders"));
+        assert_eq!(None, journal.take_restored("invoices"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
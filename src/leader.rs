@@ -0,0 +1,351 @@
+/*
+    Election of one instance that runs housekeeping jobs.
+
+    Every instance holds an etcd lease and keeps it alive. The leader is the instance whose id is at
+    meta/leader, put there with its lease by a transaction that succeeds only if the key is absent.
+    When the leader stops keeping its lease alive, etcd deletes the key and the next campaign of
+    another instance takes it over.
+
+    An instance that can't confirm its lease steps down at once and stops its jobs. Jobs also don't
+    start unless the lease was confirmed within its TTL, so runs of an old and a new leader don't overlap.
+
+    Every instance also puts its id to meta/instances/ with its lease, the leader tells instances
+    that are gone by the missing key (see reservation.rs).
+ */
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use actix_web::rt::task::JoinHandle;
+use futures::future::LocalBoxFuture;
+use log::{debug, info, warn};
+use crate::config::MaintenanceProps;
+use crate::etcd_client::EtcdClient;
+use crate::reservation::INSTANCES;
+
+const LEADER_KEY: &str = "leader";
+
+
+pub struct LeaderElection {
+    etcd: EtcdClient,
+    candidate: String,
+    lease_ttl: Duration,
+    state: RefCell<State>,
+}
+
+struct State {
+    lease: Option<String>,
+    leader: bool,
+    // no more campaigns after resigning
+    resigned: bool,
+    // when etcd last confirmed the lease is alive
+    confirmed_at: Instant,
+}
+
+/// Jobs that run periodically while this instance is the leader
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    running: RefCell<Vec<JoinHandle<()>>>,
+}
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    run: Rc<dyn Fn() -> LocalBoxFuture<'static, ()>>,
+}
+
+
+impl LeaderElection {
+    pub fn new(etcd: EtcdClient, props: &MaintenanceProps) -> Self {
+        Self {
+            etcd,
+            candidate: props.instance_id.clone().unwrap_or_else(default_instance_id),
+            lease_ttl: Duration::from_secs(props.lease_ttl_secs),
+            state: RefCell::new(State { lease: None, leader: false, resigned: false, confirmed_at: Instant::now() }),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        let state = self.state.borrow();
+        state.leader && state.confirmed_at.elapsed() < self.lease_ttl
+    }
+
+    /// Gives leadership up, e.g. on shutdown, so that another instance takes over without waiting for the lease to expire
+    pub async fn resign(&self, scheduler: &Scheduler) {
+        scheduler.stop();
+
+        let lease = {
+            let mut state = self.state.borrow_mut();
+            state.leader = false;
+            state.resigned = true;
+            state.lease.take()
+        };
+
+        if let Some(lease) = lease {
+            // the leader key goes away with the lease
+            if let Err(err) = self.etcd.revoke_lease(&lease).await {
+                warn!("Couldn't revoke lease of '{}', leadership passes on when it expires: {:?}", self.candidate, err);
+            }
+        }
+    }
+
+    // keeps the lease alive, or takes a new one, and campaigns unless already the leader
+    async fn step(self: &Rc<Self>, scheduler: &Rc<Scheduler>) {
+        if self.state.borrow().resigned {
+            return;
+        }
+
+        let lease = self.state.borrow().lease.clone();
+
+        match lease {
+            None => match self.etcd.grant_lease(self.lease_ttl.as_secs()).await {
+                Ok(lease) => {
+                    // without the key the instance looks gone, so the lease is taken again on the next step
+                    let instance_key = format!("{}{}", INSTANCES, self.candidate);
+                    if let Err(err) = self.etcd.put_meta_with_lease(&instance_key, b"", &lease).await {
+                        warn!("Couldn't register '{}' with its lease: {:?}", self.candidate, err);
+                        return;
+                    }
+
+                    let mut state = self.state.borrow_mut();
+                    state.lease = Some(lease);
+                    state.confirmed_at = Instant::now();
+                }
+                Err(err) => {
+                    warn!("Couldn't get a lease for leader election: {:?}", err);
+                    return;
+                }
+            }
+
+            Some(lease) => match self.etcd.keep_lease_alive(&lease).await {
+                Ok(true) => self.state.borrow_mut().confirmed_at = Instant::now(),
+                Ok(false) => {
+                    self.state.borrow_mut().lease = None;
+                    self.step_down(scheduler, "its lease expired");
+                    return;
+                }
+                // the lease may still be alive, so it is kept and confirmed again on the next step
+                Err(err) => {
+                    self.step_down(scheduler, &format!("its lease couldn't be kept alive: {:?}", err));
+                    return;
+                }
+            }
+        }
+
+        if self.state.borrow().leader {
+            return;
+        }
+
+        let lease = self.state.borrow().lease.clone().unwrap();
+
+        match self.etcd.campaign(LEADER_KEY, &self.candidate, &lease).await {
+            // the key is ours if we put it before stepping down and the lease has survived since
+            Ok(None) => self.take_lead(scheduler),
+            Ok(Some(holder)) if holder == self.candidate => self.take_lead(scheduler),
+            Ok(Some(holder)) => debug!("'{}' is the leader", holder),
+            Err(err) => warn!("Couldn't campaign for leadership: {:?}", err),
+        }
+    }
+
+    fn take_lead(self: &Rc<Self>, scheduler: &Rc<Scheduler>) {
+        self.state.borrow_mut().leader = true;
+        info!("'{}' is elected as the leader, starting {} jobs", self.candidate, scheduler.jobs.len());
+
+        scheduler.start(self.clone());
+    }
+
+    fn step_down(&self, scheduler: &Scheduler, reason: &str) {
+        let was_leader = std::mem::replace(&mut self.state.borrow_mut().leader, false);
+
+        if was_leader {
+            scheduler.stop();
+            warn!("'{}' is no longer the leader, {}", self.candidate, reason);
+        } else {
+            warn!("'{}' can't campaign for leadership, {}", self.candidate, reason);
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The job runs every `interval` while this instance is the leader, the first time an interval after election
+    pub fn register<F, Fut>(&mut self, name: &'static str, interval: Duration, job: F)
+        where
+            F: Fn() -> Fut + 'static,
+            Fut: Future<Output = ()> + 'static
+    {
+        self.jobs.push(Job { name, interval, run: Rc::new(move || Box::pin(job())) });
+    }
+
+    fn start(&self, election: Rc<LeaderElection>) {
+        let mut running = self.running.borrow_mut();
+
+        for job in &self.jobs {
+            let (name, interval, run, election) = (job.name, job.interval, job.run.clone(), election.clone());
+
+            running.push(actix_web::rt::spawn(async move {
+                loop {
+                    actix_web::rt::time::sleep(interval).await;
+
+                    if !election.is_leader() {
+                        continue;
+                    }
+
+                    debug!("Running job '{}'", name);
+                    run().await;
+                }
+            }));
+        }
+    }
+
+    // jobs are stopped wherever they are, so they must not leave anything half done in etcd
+    fn stop(&self) {
+        for job in self.running.borrow_mut().drain(..) {
+            job.abort();
+        }
+    }
+}
+
+
+/// Campaigns for leadership in background for as long as the process runs
+pub fn start(election: Rc<LeaderElection>, scheduler: Rc<Scheduler>) {
+    // a few steps fit into the lease TTL, so a slow etcd doesn't cost leadership
+    let step_interval = election.lease_ttl / 3;
+
+    actix_web::rt::spawn(async move {
+        loop {
+            election.step(&scheduler).await;
+            actix_web::rt::time::sleep(step_interval).await;
+        }
+    });
+}
+
+pub fn default_instance_id() -> String {
+    let host = std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    format!("{}-{}", host, std::process::id())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use crate::etcd_client;
+    use crate::fake_etcd::{FakeEtcd, Fault};
+    use super::{LeaderElection, Scheduler};
+
+    fn election(etcd: &FakeEtcd, instance_id: &str) -> Rc<LeaderElection> {
+        let client = etcd_client::new_etcd_client(
+            etcd_client::new_http_client(awc::Client::default()), etcd.url(), "ids/".to_string());
+        let props = serde_yaml::from_str(&format!("instance_id: {}", instance_id)).unwrap();
+
+        Rc::new(LeaderElection::new(client, &props))
+    }
+
+    // a job that counts its runs
+    fn scheduler(runs: &Rc<Cell<u32>>) -> Rc<Scheduler> {
+        let mut scheduler = Scheduler::new();
+        let runs = runs.clone();
+        scheduler.register("count", Duration::from_millis(10), move || {
+            let runs = runs.clone();
+            async move { runs.set(runs.get() + 1) }
+        });
+
+        Rc::new(scheduler)
+    }
+
+    fn leader_key(etcd: &FakeEtcd) -> Option<String> {
+        etcd.get("ids/meta/leader").map(|v| String::from_utf8(v).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn one_candidate_at_a_time_is_elected() {
+        let etcd = FakeEtcd::start();
+        let (a, b) = (election(&etcd, "a"), election(&etcd, "b"));
+        let scheduler = Rc::new(Scheduler::new());
+
+        a.step(&scheduler).await;
+        b.step(&scheduler).await;
+
+        assert!(a.is_leader());
+        assert!(!b.is_leader());
+        assert_eq!(Some("a".to_string()), leader_key(&etcd));
+
+        // the leader keeps its key on following steps, others keep losing
+        a.step(&scheduler).await;
+        b.step(&scheduler).await;
+        assert!(a.is_leader() && !b.is_leader());
+
+        // the key goes with the lease of the one who resigns, and the next campaign takes it
+        a.resign(&scheduler).await;
+        assert_eq!(None, leader_key(&etcd));
+
+        b.step(&scheduler).await;
+        a.step(&scheduler).await;
+        assert!(b.is_leader() && !a.is_leader());
+        assert_eq!(Some("b".to_string()), leader_key(&etcd));
+    }
+
+    #[actix_web::test]
+    async fn leader_steps_down_when_its_lease_cant_be_kept_alive() {
+        let etcd = FakeEtcd::start();
+        let a = election(&etcd, "a");
+        let runs = Rc::new(Cell::new(0));
+        let scheduler = scheduler(&runs);
+
+        a.step(&scheduler).await;
+        assert!(a.is_leader());
+
+        etcd.fail_next("lease/keepalive", Fault::Status(503));
+        a.step(&scheduler).await;
+
+        assert!(!a.is_leader());
+        assert!(scheduler.running.borrow().is_empty());
+
+        // the lease survived, so the key is still ours and leadership is taken back
+        a.step(&scheduler).await;
+        assert!(a.is_leader());
+        assert_eq!(1, etcd.requests("lease/grant"));
+    }
+
+    #[actix_web::test]
+    async fn jobs_run_on_the_leader_and_stop_when_its_lease_is_lost() {
+        let etcd = FakeEtcd::start();
+        let (a, b) = (election(&etcd, "a"), election(&etcd, "b"));
+        let (runs_a, runs_b) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let (scheduler_a, scheduler_b) = (scheduler(&runs_a), scheduler(&runs_b));
+
+        a.step(&scheduler_a).await;
+        b.step(&scheduler_b).await;
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(runs_a.get() > 0);
+        assert_eq!(0, runs_b.get());
+
+        // e.g. the leader was cut off from etcd for longer than the lease TTL
+        etcd.expire_leases();
+        a.step(&scheduler_a).await;
+        // the lease of b is gone too, it campaigns with a new one on the step after
+        b.step(&scheduler_b).await;
+        b.step(&scheduler_b).await;
+
+        let runs_of_a = runs_a.get();
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!a.is_leader() && b.is_leader());
+        assert_eq!(runs_of_a, runs_a.get());
+        assert!(runs_b.get() > 0);
+        assert_eq!(Some("b".to_string()), leader_key(&etcd));
+    }
+}
@@ -0,0 +1,98 @@
+pub mod etcd_client;
+pub mod cache;
+pub mod range;
+pub mod api_endpoints;
+pub mod config;
+pub mod auth;
+pub mod rate_limit;
+pub mod snapshot;
+pub mod demand;
+pub mod metrics;
+pub mod journal;
+pub mod reservation;
+pub mod interleave;
+pub mod grpc;
+pub mod encoding;
+pub mod reload;
+pub mod overrides;
+pub mod policy;
+pub mod leader;
+pub mod maintenance;
+pub mod forecast;
+pub mod audit;
+pub mod telemetry;
+pub mod logging;
+
+#[cfg(test)]
+#[path = "../tests/fake_etcd/mod.rs"]
+mod fake_etcd;
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use crate::etcd_client::HttpClient;
+use crate::config::{AuthProps, Properties};
+use crate::range::RangeProvider;
+use crate::cache::Caches;
+use crate::cache::cursor::IdCursors;
+use crate::rate_limit::RateLimiter;
+use crate::demand::DemandTracker;
+use crate::journal::Journal;
+use crate::reservation::Reservations;
+use crate::interleave::Interleave;
+use crate::policy::PolicyStore;
+use crate::audit::Auditor;
+use crate::forecast::LatestForecasts;
+
+
+/// State shared by all workers, created once in main
+#[derive(Clone)]
+pub struct Shared {
+    pub caches: Caches,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub demand: Arc<DemandTracker>,
+    pub journal: Option<Arc<Journal>>,
+    pub reservations: Option<Arc<Reservations>>,
+    // changes on config reload
+    pub max_client_range_size: Arc<AtomicU64>,
+    pub policies: Arc<PolicyStore>,
+    pub forecasts: Arc<LatestForecasts>,
+}
+
+
+pub fn get_app_data(props: Properties, shared: Shared, http_client: HttpClient) -> AppData {
+    let client = etcd_client::new_etcd_client(http_client, props.etcd_addr.clone(), props.key_prefix.clone());
+    let instance_id = props.maintenance.as_ref().and_then(|m| m.instance_id.clone()).unwrap_or_else(leader::default_instance_id);
+
+    AppData {
+        audit: Auditor::new(props.audit, instance_id, client.clone()),
+        seq_provider: RangeProvider {
+            etcd_client: client,
+            caches: shared.caches,
+            demand: shared.demand,
+            journal: shared.journal,
+            reservations: shared.reservations,
+            return_evicted: props.cache.return_evicted_to_etcd,
+            interleave: props.interleave.iter().map(|i| (i.pattern.clone(), Interleave::new(i))).collect(),
+            max_client_range_size: shared.max_client_range_size,
+            tenants: props.tenants,
+            cursors: Rc::new(IdCursors::new()),
+            next_id_batch: props.next_id_batch,
+            policies: shared.policies,
+        },
+        auth: props.auth,
+        rate_limiter: shared.rate_limiter,
+        forecasts: shared.forecasts,
+    }
+}
+
+
+
+#[derive(Clone)]
+pub struct AppData {
+    seq_provider: RangeProvider,
+    auth: Option<AuthProps>,
+    rate_limiter: Arc<RateLimiter>,
+    audit: Auditor,
+    forecasts: Arc<LatestForecasts>,
+}
@@ -0,0 +1,154 @@
+/*
+    Correlation of log lines with requests, and sampling of debug logs on hot paths.
+
+    Every HTTP and gRPC request gets an id, taken from its X-Request-Id header or generated, which
+    is sent back in X-Request-Id. While the request's future is polled the id is in the log4rs MDC
+    under 'request_id', so every line logged for the request carries it: as {X(request_id)} in
+    pattern encoders and in the 'mdc' field of the JSON encoder, see logs.yaml.
+    Workers run many requests on one thread, so the id is set for each poll and removed after it.
+
+    Generated ids are the trace id of the request's span if it has one, so logs and traces match.
+ */
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+use crate::telemetry;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MDC_KEY: &str = "request_id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// see debug_sampled!
+static DEBUG_SAMPLE_EVERY: AtomicU64 = AtomicU64::new(1000);
+
+
+/// Id of the request, in extensions of every HTTP request
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Takes the id the client sent if it is a sane one, generates one otherwise
+pub fn request_id(sent: Option<&str>, trace: &opentelemetry::Context) -> String {
+    let sent = sent.map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()));
+
+    if let Some(id) = sent {
+        return id.to_string();
+    }
+
+    let span_context = trace.span().span_context().clone();
+    let trace_id = match span_context.is_valid() {
+        true => span_context.trace_id(),
+        false => RandomIdGenerator::default().new_trace_id(),
+    };
+
+    trace_id.to_string()
+}
+
+/// Request id of the future being polled, if any
+pub fn current_request_id() -> Option<String> {
+    log_mdc::get(MDC_KEY, |id| id.map(str::to_string))
+}
+
+/// Runs the future with the request id in the MDC of every log line it writes
+pub fn with_request_id<F: Future>(request_id: String, future: F) -> WithRequestId<F> {
+    WithRequestId { request_id, future: Box::pin(future) }
+}
+
+pub struct WithRequestId<F> {
+    request_id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // futures of one request may be nested, the outer one gets its id back
+        let outer = current_request_id();
+        log_mdc::insert(MDC_KEY, self.request_id.as_str());
+
+        let poll = self.future.as_mut().poll(cx);
+
+        match outer {
+            Some(outer) => log_mdc::insert(MDC_KEY, outer),
+            None => log_mdc::remove(MDC_KEY),
+        };
+
+        poll
+    }
+}
+
+
+/// Assigns the request its id, see the top of this file
+pub async fn correlate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = request_id(req.headers().get(REQUEST_ID_HEADER).and_then(|h| h.to_str().ok()), &opentelemetry::Context::current());
+
+    telemetry::record(KeyValue::new(telemetry::REQUEST_ID, id.clone()));
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = with_request_id(id.clone(), next.call(req)).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    Ok(response)
+}
+
+
+/// How often debug_sampled! logs, 1 logs every time
+pub fn set_debug_sample_every(every: u64) {
+    DEBUG_SAMPLE_EVERY.store(every.max(1), Ordering::Relaxed);
+}
+
+pub fn debug_sample_every() -> u64 {
+    DEBUG_SAMPLE_EVERY.load(Ordering::Relaxed)
+}
+
+/// Debug log for hot paths: of every `logging.debug_sample_every` calls at the call site, only the first is logged
+#[macro_export]
+macro_rules! debug_sampled {
+    ($($arg:tt)+) => {{
+        static CALLS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        if log::log_enabled!(log::Level::Debug) {
+            let every = $crate::logging::debug_sample_every();
+            if CALLS.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % every == 0 {
+                log::debug!("{} (sampled, 1 of {} logged)", format_args!($($arg)+), every);
+            }
+        }
+    }};
+}
+
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::Context;
+    use super::{current_request_id, request_id, with_request_id};
+
+    #[actix_web::test]
+    async fn request_id_is_set_only_while_its_future_runs() {
+        assert_eq!("abc-1", request_id(Some(" abc-1 "), &Context::new()));
+        assert_eq!(32, request_id(Some("has spaces"), &Context::new()).len());
+        assert_eq!(32, request_id(None, &Context::new()).len());
+
+        let seen = with_request_id("outer".to_string(), async {
+            let inner = with_request_id("inner".to_string(), async { current_request_id() }).await;
+            (inner, current_request_id())
+        }).await;
+
+        assert_eq!((Some("inner".to_string()), Some("outer".to_string())), seen);
+        assert_eq!(None, current_request_id());
+    }
+}
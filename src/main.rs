@@ -1,74 +1,233 @@
-mod etcd_client;
-mod cache;
-mod range;
-mod api_endpoints;
-mod config;
 mod tests;
 
-use std::convert::Infallible;
-use std::future::Future;
-use actix_web::{App, get, HttpResponse, HttpServer, post, Responder, web};
-use actix_web::web::{BufMut, Data};
-use awc::error::{PayloadError, SendRequestError};
-use crate::etcd_client::{EtcdClient, EtcdErr, HttpClient};
-use actix_web::middleware::Logger;
-use log4rs;
-use log::{info, warn};
-use crate::config::Properties;
-use crate::range::{Range, RangeProvider};
-use crate::api_endpoints::{get_next_range, create_seq};
-use crate::cache::CacheClient;
-
-#[cfg(not(test))]
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use actix_web::{App, HttpServer};
+use clap::Parser;
+use actix_web::web::{self, Data};
+use actix_web::middleware::{from_fn, Logger};
+use ngamahi_id_gen::{auth, cache, config, etcd_client, get_app_data, grpc, logging, maintenance, telemetry, AppData, Shared};
+use log::info;
+use ngamahi_id_gen::config::Properties;
+use ngamahi_id_gen::overrides::Overrides;
+use ngamahi_id_gen::api_endpoints::{get_next_range, get_next_id, create_seq, get_next_tenant_range, create_tenant_seq, list_seqs, seq_info, delete_seq, advance_seq, reset_seq, export_seqs, import_seqs, get_audit, get_metrics, get_seq_policy, put_seq_policy, seq_forecast};
+use ngamahi_id_gen::rate_limit::RateLimiter;
+use ngamahi_id_gen::demand::DemandTracker;
+use ngamahi_id_gen::journal::{self, Journal};
+use ngamahi_id_gen::reservation::Reservations;
+use ngamahi_id_gen::reload::{self, Reloader};
+use ngamahi_id_gen::policy::PolicyStore;
+use ngamahi_id_gen::leader::{self, LeaderElection};
+use ngamahi_id_gen::forecast::LatestForecasts;
+use opentelemetry::trace::TraceError;
+#[cfg(test)]
+use ngamahi_id_gen::range::Range;
+
+// snapshots of all sequences may be big
+const IMPORT_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+// actix's default format followed by the request id
+const ACCESS_LOG_FORMAT: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
+
+
+/// Properties are read from properties.yaml, then from ID_GEN_* env vars (e.g. ID_GEN_RATE_LIMITS__DEFAULT__IDS_PER_SEC),
+/// then from flags, each overriding the previous. A property named NAME is read from a file given in ID_GEN_NAME_FILE
+#[derive(Parser)]
+#[command(name = "ngamahi-id-gen", about = "Id server backed by etcd")]
+struct Cli {
+    /// Directory with properties.yaml and logs.yaml, overrides ID_GEN_CFG_PATH
+    #[arg(long)]
+    config: Option<String>,
+
+    #[arg(long)]
+    etcd_addr: Option<String>,
+
+    #[arg(long)]
+    etcd_fetch_range_size: Option<u64>,
+
+    #[arg(long)]
+    client_range_max_size: Option<u64>,
+
+    #[arg(long)]
+    key_prefix: Option<String>,
+
+    /// Sets any property by its path in properties.yaml, e.g. --set rate_limits.default.ids_per_sec=100
+    #[arg(long, value_name = "PATH=VALUE")]
+    set: Vec<String>,
+
+    /// Sets a property to content of a file, e.g. --set-file auth.api_keys.0.key=/run/secrets/key
+    #[arg(long, value_name = "PATH=FILE")]
+    set_file: Vec<String>,
+
+    /// Prints effective properties with secrets redacted and exits
+    #[arg(long)]
+    print_config: bool,
+}
+
+impl Cli {
+    fn overrides(&self) -> Result<Overrides, config::Error> {
+        let mut overrides = Overrides::from_env()?;
+
+        for (path, value) in [("etcd_addr", &self.etcd_addr), ("key_prefix", &self.key_prefix)] {
+            if let Some(value) = value {
+                overrides.set_string(path, value);
+            }
+        }
+
+        for (path, value) in [("etcd_fetch_range_size", self.etcd_fetch_range_size), ("client_range_max_size", self.client_range_max_size)] {
+            if let Some(value) = value {
+                overrides.set(path, &value.to_string());
+            }
+        }
+
+        for assignment in &self.set {
+            overrides.set_assignment(assignment, false)?;
+        }
+
+        for assignment in &self.set_file {
+            overrides.set_assignment(assignment, true)?;
+        }
+
+        Ok(overrides)
+    }
+}
+
+
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
-    println!("Started");
+    let cli = Cli::parse();
+    let configs = config::read_configs(cli.config.clone(), cli.overrides()?)?;
+
+    if cli.print_config {
+        print!("{}", configs.redacted_yaml());
+        return Ok(());
+    }
 
-    let configs = config::read_configs()?;
     let logger_cfg = &configs.logs_cfg_path;
 
     log4rs::init_file(logger_cfg, Default::default())?;
+    info!(target: "startup", "{}", configs.source);
+    logging::set_debug_sample_every(configs.props.logging.debug_sample_every);
+    let telemetry = telemetry::init(configs.props.tracing.as_ref())?;
+
+    let policies = Arc::new(PolicyStore::new(Duration::from_secs(configs.props.policy_ttl_secs)));
+    let rate_limiter = Arc::new(RateLimiter::new(configs.props.rate_limits.clone(), policies.clone()));
+    let demand = Arc::new(DemandTracker::new(configs.props.etcd_fetch_range_size, configs.props.adaptive_fetch.clone()));
+
+    let journal = match &configs.props.persistence {
+        Some(persistence) => {
+            let etcd = etcd_client::new_etcd_client(
+                etcd_client::new_http_client(awc::Client::default()), configs.props.etcd_addr.clone(), configs.props.key_prefix.clone());
+
+            Some(Arc::new(Journal::open(persistence, &etcd).await?))
+        }
+        None => None,
+    };
 
-    let cache = cache::new_thread_local();
+    // client_range_max_size may change on reload, so workers share it
+    let max_client_range_size = Arc::new(AtomicU64::new(configs.props.client_range_max_size));
+
+    let reloader = Reloader::new(&configs, demand.clone(), rate_limiter.clone(), max_client_range_size.clone());
+    reload::watch(reloader, configs.props.reload_check_secs)?;
+
+    // published by the capacity check for metrics, outdated once it missed a run
+    let check_secs = configs.props.maintenance.as_ref().map_or(0, |m| m.capacity_check_secs);
+    let forecasts = Arc::new(LatestForecasts::new(Duration::from_secs(2 * check_secs)));
+
+    // housekeeping jobs run on the elected instance only
+    let maintenance = configs.props.maintenance.as_ref().map(|props| {
+        let etcd = etcd_client::new_etcd_client(
+            etcd_client::new_http_client(awc::Client::default()), configs.props.etcd_addr.clone(), configs.props.key_prefix.clone());
+
+        let election = Rc::new(LeaderElection::new(etcd.clone(), props));
+        let scheduler = Rc::new(maintenance::scheduler(props, &configs.props.interleave, etcd, forecasts.clone()));
+        leader::start(election.clone(), scheduler.clone());
+
+        (election, scheduler)
+    });
+
+    // the same id the instance is elected with, records of its reservations are collected once it is gone
+    let reservations = configs.props.maintenance.as_ref()
+        .filter(|props| props.reservation_gc_secs > 0)
+        .map(|props| Arc::new(Reservations::new(props.instance_id.clone().unwrap_or_else(leader::default_instance_id))));
+
+    let shared = Shared {
+        caches: cache::new_all(&configs.props.cache),
+        rate_limiter,
+        demand,
+        journal,
+        reservations,
+        max_client_range_size,
+        policies,
+        forecasts,
+    };
 
-    Ok(HttpServer::new(move || {
+    if let Some(grpc_props) = &configs.props.grpc {
+        let (props, shared) = (configs.props.clone(), shared.clone());
+
+        grpc::start(grpc_props, move || get_app_data_prod(props, shared))?;
+    }
+
+    let served = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
-            .app_data(Data::new(get_app_data_prod(configs.props.clone(), cache.clone())))
+            .wrap(from_fn(auth::authenticate))
+            .wrap(from_fn(logging::correlate))
+            .wrap(Logger::new(ACCESS_LOG_FORMAT))
+            .wrap(from_fn(telemetry::trace_request))
+            .app_data(Data::new(get_app_data_prod(configs.props.clone(), shared.clone())))
             .service(get_next_range)
+            .service(get_next_id)
             .service(create_seq)
+            .service(get_next_tenant_range)
+            .service(create_tenant_seq)
+            .service(list_seqs)
+            .service(get_metrics)
+            .service(seq_info)
+            .service(delete_seq)
+            .service(advance_seq)
+            .service(reset_seq)
+            .service(export_seqs)
+            .service(web::resource("/admin/import")
+                .app_data(web::PayloadConfig::new(IMPORT_MAX_SIZE))
+                .route(web::post().to(import_seqs)))
+            .service(get_audit)
+            .service(get_seq_policy)
+            .service(put_seq_policy)
+            .service(seq_forecast)
+            .service(web::scope("/tenants/{tenant}")
+                .service(seq_info)
+                .service(get_seq_policy)
+                .service(put_seq_policy)
+                .service(seq_forecast)
+                .service(delete_seq)
+                .service(advance_seq)
+                .service(reset_seq))
     })
         .bind(("0.0.0.0", 8080))?
-        .run()
-        .await?)
-}
+        .run();
 
+    info!(target: "startup", "Started");
+    let served = served.await;
 
-#[cfg(not(test))]
-fn get_app_data_prod(props: Properties, cache: CacheClient) -> AppData {
-    let http_client = etcd_client::new_http_client(awc::Client::default());
-
-    get_app_data(props, cache, http_client)
-}
-
-pub fn get_app_data(props: Properties, cache: CacheClient, http_client: HttpClient) -> AppData {
-    let client = etcd_client::new_etcd_client(http_client, (&props.etcd_addr).clone());
+    // lets another instance take over right away
+    if let Some((election, scheduler)) = maintenance {
+        election.resign(&scheduler).await;
+    }
 
-    AppData {
-        seq_provider: RangeProvider {
-            etcd_client: client,
-            cache,
-            etcd_fetch_size: props.etcd_fetch_range_size,
-            max_client_range_size: props.client_range_max_size
-        },
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
     }
+
+    Ok(served?)
 }
 
 
+fn get_app_data_prod(props: Properties, shared: Shared) -> AppData {
+    let http_client = etcd_client::new_http_client(awc::Client::default());
 
-#[derive(Clone)]
-pub struct AppData {
-    seq_provider: RangeProvider,
+    get_app_data(props, shared, http_client)
 }
 
 #[derive(Debug)]
@@ -76,6 +235,20 @@ pub enum Error{
     Io(std::io::Error),
     Config(config::Error),
     Anyhow(anyhow::Error),
+    Journal(journal::JournalErr),
+    Telemetry(TraceError),
+}
+
+impl From<TraceError> for Error {
+    fn from(value: TraceError) -> Self {
+        Self::Telemetry(value)
+    }
+}
+
+impl From<journal::JournalErr> for Error {
+    fn from(value: journal::JournalErr) -> Self {
+        Self::Journal(value)
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -98,20 +271,12 @@ impl From<anyhow::Error> for Error {
 
 
 
+#[cfg(test)]
 #[actix_web::test]
 pub async fn do_test() {
-    assert_eq!(2 + 2, 4);
-
-    println!("creating cache");
-
     let cache = cache::new_common();
 
-    let rng = Range {
-        begin: 0,
-        end: 100
-    };
-
-    println!("putting");
+    let rng = Range::new(0, 100);
 
     cache.put("some-seq".to_string(),rng ).await;
 
@@ -124,8 +289,6 @@ pub async fn do_test() {
     let range_from_cache = from_cache.0.first().unwrap();
     let needed = from_cache.1;
 
-    println!("Got range: {} to {}, needed {}", range_from_cache.begin, range_from_cache.end, needed);
-
     assert_eq!(0, range_from_cache.begin);
     assert_eq!(100, range_from_cache.end);
     assert_eq!(0, needed);
@@ -0,0 +1,348 @@
+/*
+    Housekeeping jobs, run by the elected leader only (see leader.rs).
+
+    The usage report logs how many ids every sequence handed out since the previous report,
+    i.e. how fast its value in etcd grows, which includes ids cached but not yet served.
+
+    The capacity check samples sequences that declare a capacity into their usage history
+    (see forecast.rs) and warns once a sequence crosses a ratio of its capacity or is forecast
+    to run out soon. A warning is repeated only when things get worse, or by a new leader.
+    Its forecasts are published for metrics.
+
+    The reservation GC gives back to etcd ids cached by instances that are gone (see reservation.rs).
+    It deletes the record of such an instance first and then returns the ids above its claimed mark,
+    which works only if nobody took ids of the sequence since. Otherwise the ids are skipped.
+ */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::auth::matches_pattern;
+use crate::config::{InterleaveProps, MaintenanceProps};
+use crate::etcd_client::EtcdClient;
+use crate::forecast::{self, Forecast, LatestForecasts};
+use crate::interleave::Interleave;
+use crate::leader::Scheduler;
+use crate::policy;
+use crate::range::{is_seq_name, Range};
+use crate::reservation::{self, Reservation, INSTANCES, RESERVATIONS};
+
+
+pub fn scheduler(props: &MaintenanceProps, interleave: &[InterleaveProps], etcd: EtcdClient, forecasts: Arc<LatestForecasts>) -> Scheduler {
+    let mut scheduler = Scheduler::new();
+
+    if props.usage_report_secs > 0 {
+        let report = Rc::new(UsageReport { etcd: etcd.clone(), previous: RefCell::new(None) });
+
+        scheduler.register("usage report", Duration::from_secs(props.usage_report_secs), move || {
+            let report = report.clone();
+            async move { report.run().await }
+        });
+    }
+
+    if props.reservation_gc_secs > 0 {
+        let gc = Rc::new(ReservationGc { etcd: etcd.clone() });
+
+        scheduler.register("reservation gc", Duration::from_secs(props.reservation_gc_secs), move || {
+            let gc = gc.clone();
+            async move { gc.run().await }
+        });
+    }
+
+    if props.capacity_check_secs > 0 {
+        let check = Rc::new(CapacityCheck {
+            etcd,
+            interleave: interleave.iter().map(|i| (i.pattern.clone(), Interleave::new(i))).collect(),
+            props: props.clone(),
+            warned: RefCell::new(HashMap::new()),
+            forecasts,
+        });
+
+        scheduler.register("capacity check", Duration::from_secs(props.capacity_check_secs), move || {
+            let check = check.clone();
+            async move { check.run().await }
+        });
+    }
+
+    scheduler
+}
+
+
+struct UsageReport {
+    etcd: EtcdClient,
+    // values of sequences as of the previous report
+    previous: RefCell<Option<(Instant, HashMap<String, u64>)>>,
+}
+
+impl UsageReport {
+    async fn run(&self) {
+        let seqs = match self.etcd.list_seqs("".to_string()).await {
+            Ok((_, seqs)) => seqs,
+            Err(err) => {
+                warn!("Couldn't list sequences for usage report: {:?}", err);
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let values: HashMap<String, u64> = seqs.into_iter()
+            .filter(|s| is_seq_name(&s.name))
+            .map(|s| (s.name, s.value))
+            .collect();
+
+        let previous = self.previous.replace(Some((now, values.clone())));
+        let (since, previous) = match previous {
+            Some(previous) => previous,
+            None => {
+                info!("Usage report: {} sequences, usage is reported from the next report on", values.len());
+                return;
+            }
+        };
+
+        let secs = now.duration_since(since).as_secs_f64();
+        let mut used: Vec<(&String, u64, u64)> = values.iter()
+            .map(|(name, value)| (name, *value, value.saturating_sub(previous.get(name).copied().unwrap_or(0))))
+            .filter(|(_, _, taken)| *taken > 0)
+            .collect();
+        used.sort_by_key(|(_, _, taken)| std::cmp::Reverse(*taken));
+
+        info!("Usage report: {} sequences, {} of them took ids in the last {:.0}s", values.len(), used.len(), secs);
+
+        for (name, value, taken) in used {
+            info!("Sequence '{}' is at {}, took {} ids ({:.1}/s)", name, value, taken, taken as f64 / secs);
+        }
+    }
+}
+
+
+struct ReservationGc {
+    etcd: EtcdClient,
+}
+
+impl ReservationGc {
+    async fn run(&self) {
+        let loaded = futures::try_join!(self.etcd.list_meta(INSTANCES), self.etcd.list_meta(RESERVATIONS));
+        let (instances, records) = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                warn!("Couldn't load reservations: {:?}", err);
+                return;
+            }
+        };
+
+        // an instance that has not registered yet looks gone as well, it loses its records then, not ids it served
+        let alive: HashSet<String> = instances.into_iter().map(|(instance, _)| instance).collect();
+
+        for (name, record) in records {
+            let instance = match reservation::record_instance(&name) {
+                Some(instance) if !alive.contains(instance) => instance,
+                _ => continue,
+            };
+
+            let reservation: Reservation = match serde_json::from_slice(&record) {
+                Ok(reservation) => reservation,
+                Err(err) => {
+                    warn!("Skipping bad reservation record '{}': {}", name, err);
+                    continue;
+                }
+            };
+
+            // once the record is gone, the instance can't claim more ids of it, if it is still around.
+            // A run stopped right after the delete leaves the ids skipped, never served twice
+            match self.etcd.compare_and_put_meta(&format!("{}{}", RESERVATIONS, name), &record, None).await {
+                Ok(true) => {}
+                // claimed meanwhile, the next run sees the new record
+                Ok(false) => continue,
+                Err(err) => {
+                    warn!("Couldn't delete reservation record '{}': {:?}", name, err);
+                    continue;
+                }
+            }
+
+            let unclaimed = Range::new(reservation.claimed, reservation.range.end);
+
+            match self.etcd.return_range(reservation.seq.clone(), &unclaimed).await {
+                Ok(true) => info!("Returned range {:?} of sequence '{}' left by '{}' to etcd", unclaimed, reservation.seq, instance),
+                Ok(false) => info!("Range {:?} of sequence '{}' left by '{}' is skipped, ids were taken since", unclaimed, reservation.seq, instance),
+                Err(err) => warn!("Couldn't return range {:?} of sequence '{}' left by '{}': {:?}", unclaimed, reservation.seq, instance, err),
+            }
+        }
+    }
+}
+
+
+struct CapacityCheck {
+    etcd: EtcdClient,
+    interleave: Vec<(String, Interleave)>,
+    props: MaintenanceProps,
+    // the worst state of every sequence warned about so far
+    warned: RefCell<HashMap<String, Warned>>,
+    forecasts: Arc<LatestForecasts>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Warned {
+    // number of warn ratios crossed
+    ratios_crossed: usize,
+    runs_out_soon: bool,
+}
+
+impl CapacityCheck {
+    async fn run(&self) {
+        let loaded = futures::try_join!(
+            self.etcd.list_seqs("".to_string()),
+            policy::load_all(&self.etcd),
+            forecast::load_histories(&self.etcd),
+        );
+        let ((_, seqs), policies, histories) = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                warn!("Couldn't load sequences for capacity check: {:?}", err);
+                return;
+            }
+        };
+
+        let values: HashMap<String, u64> = seqs.into_iter()
+            .filter(|s| is_seq_name(&s.name))
+            .map(|s| (s.name, s.value))
+            .collect();
+        let mut histories: HashMap<String, forecast::UsageHistory> = histories.into_iter().collect();
+        let now = forecast::now_secs();
+        let mut forecasts = vec![];
+
+        for (seq_name, capacity) in policies.into_iter().filter_map(|(name, p)| Some((name, p.capacity?))) {
+            let value = match values.get(&seq_name) {
+                Some(value) => *value,
+                None => continue,
+            };
+
+            let interleave = self.interleave.iter().find(|(pattern, _)| matches_pattern(pattern, &seq_name)).map(|(_, i)| i);
+            let position = forecast::position(interleave, value);
+
+            let mut history = histories.remove(&seq_name).unwrap_or_default();
+            history.capacity = capacity.max_id();
+            history.record(now, position, self.props.usage_history_secs);
+
+            if let Err(err) = forecast::store_history(&self.etcd, &seq_name, &history).await {
+                warn!("Couldn't store usage history of sequence '{}': {:?}", seq_name, err);
+            }
+
+            let forecast = Forecast::new(history.capacity, position, &history);
+            self.warn(&seq_name, &forecast);
+            forecasts.push((seq_name, forecast));
+        }
+        self.forecasts.publish(forecasts);
+
+        // histories left belong to sequences that no longer have a capacity
+        for seq_name in histories.keys() {
+            if let Err(err) = forecast::delete_history(&self.etcd, seq_name).await {
+                warn!("Couldn't delete usage history of sequence '{}': {:?}", seq_name, err);
+            }
+        }
+    }
+
+    fn warn(&self, seq_name: &str, forecast: &Forecast) {
+        let state = Warned {
+            ratios_crossed: self.props.capacity_warn_ratios.iter().filter(|r| forecast.used_ratio >= **r).count(),
+            runs_out_soon: forecast.exhaustion_secs.is_some_and(|secs| secs <= self.props.exhaustion_warn_secs),
+        };
+
+        let mut warned = self.warned.borrow_mut();
+        let before = warned.get(seq_name).copied().unwrap_or_default();
+
+        if state.ratios_crossed > before.ratios_crossed || (state.runs_out_soon && !before.runs_out_soon) {
+            warn!("Sequence '{}' used {:.1}% of its capacity {} (at {}), {}", seq_name, forecast.used_ratio * 100.0,
+                forecast.capacity, forecast.position, describe_exhaustion(forecast));
+        }
+
+        warned.insert(seq_name.to_string(), state);
+    }
+}
+
+fn describe_exhaustion(forecast: &Forecast) -> String {
+    match (forecast.exhaustion_secs, forecast.ids_per_sec) {
+        (Some(secs), Some(rate)) => format!("at {:.1} ids/s it runs out in {:.1} days", rate, secs as f64 / 86400.0),
+        _ => "it doesn't grow at the moment".to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Duration;
+    use crate::cache::{self, cursor::IdCursors};
+    use crate::config::CacheProps;
+    use crate::demand::DemandTracker;
+    use crate::etcd_client::{self, EtcdClient};
+    use crate::fake_etcd::FakeEtcd;
+    use crate::policy::PolicyStore;
+    use crate::range::{Range, RangeProvider, RangeProviderErr};
+    use crate::reservation::Reservations;
+    use super::ReservationGc;
+
+    fn client(etcd: &FakeEtcd) -> EtcdClient {
+        etcd_client::new_etcd_client(etcd_client::new_http_client(awc::Client::default()), etcd.url(), "ids/".to_string())
+    }
+
+    // a worker of the instance, ranges of 100 ids are taken from etcd
+    fn provider(etcd: &FakeEtcd, instance_id: &str) -> RangeProvider {
+        RangeProvider {
+            etcd_client: client(etcd),
+            caches: cache::new_all(&CacheProps::default()),
+            demand: Arc::new(DemandTracker::new(100, None)),
+            max_client_range_size: Arc::new(AtomicU64::new(50)),
+            journal: None,
+            reservations: Some(Arc::new(Reservations::new(instance_id.to_string()))),
+            return_evicted: false,
+            interleave: vec![],
+            tenants: HashMap::new(),
+            cursors: Rc::new(IdCursors::new()),
+            next_id_batch: 10,
+            policies: Arc::new(PolicyStore::new(Duration::from_secs(60))),
+        }
+    }
+
+    #[actix_web::test]
+    async fn unclaimed_ids_of_instances_that_are_gone_go_back_to_etcd() {
+        let etcd = FakeEtcd::start();
+        etcd.put_u64("ids/orders", 0);
+        etcd.put_u64("ids/invoices", 0);
+        // only 'alive' is registered
+        etcd.put("ids/meta/instances/alive", b"");
+
+        let (alive, gone) = (provider(&etcd, "alive"), provider(&etcd, "gone"));
+        let gc = ReservationGc { etcd: client(&etcd) };
+
+        // ids 10..100 are cached, the first step of them is claimed with the record
+        assert_eq!(vec![Range::new(0, 10)], alive.get_next_range("orders".to_string(), 10).await.unwrap());
+        assert_eq!(vec![Range::new(0, 10)], gone.get_next_range("invoices".to_string(), 10).await.unwrap());
+        assert_eq!(vec![Range::new(10, 15)], gone.get_next_range("invoices".to_string(), 5).await.unwrap());
+
+        gc.run().await;
+
+        assert_eq!(Some(100), etcd.get_u64("ids/orders"));
+        // ids above the claimed mark are back, those below it may have been served
+        assert_eq!(Some(21), etcd.get_u64("ids/invoices"));
+        let records = client(&etcd).list_meta("reservations/").await.unwrap();
+        assert!(records.len() == 1 && records[0].0.starts_with("alive/"));
+
+        // an instance that is still around can't claim them anymore and drops what it has cached
+        let collected = gone.get_next_range("invoices".to_string(), 10).await;
+        assert!(matches!(collected, Err(RangeProviderErr::SeqChanged(_))), "{:?}", collected);
+        assert_eq!(vec![Range::new(21, 31)], gone.get_next_range("invoices".to_string(), 10).await.unwrap());
+
+        // served ids are claimed before, so they stay out of what is returned
+        assert_eq!(vec![Range::new(10, 40)], alive.get_next_range("orders".to_string(), 30).await.unwrap());
+        client(&etcd).delete_meta("instances/alive").await.unwrap();
+        gc.run().await;
+
+        assert_eq!(Some(40), etcd.get_u64("ids/orders"));
+    }
+}
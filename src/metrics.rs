@@ -0,0 +1,33 @@
+/*
+    Metrics in Prometheus text exposition format, served at /metrics.
+ */
+
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+
+#[derive(Default)]
+pub struct MetricsText {
+    out: String,
+}
+
+impl MetricsText {
+    /// Writes one metric with a sample per sequence. `kind` is "gauge" or "counter"
+    pub fn per_sequence<'a>(&mut self, name: &str, kind: &str, help: &str, samples: impl IntoIterator<Item = (&'a str, f64)>) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+
+        for (seq_name, value) in samples {
+            writeln!(self.out, "{}{{sequence=\"{}\"}} {}", name, escape_label(seq_name), value).unwrap();
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
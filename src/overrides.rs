@@ -0,0 +1,228 @@
+/*
+    Properties set from environment variables and command-line flags over properties.yaml.
+
+    Precedence is defaults < file < env < flags. A property is named by its path in the file,
+    e.g. `rate_limits.default.ids_per_sec`, list items by their index, e.g. `auth.api_keys.0.key`.
+    In env vars the path is upper-cased and prefixed with ID_GEN_, with '__' between its parts:
+    ID_GEN_RATE_LIMITS__DEFAULT__IDS_PER_SEC. Values are YAML, so lists and maps can be given inline.
+    A value that overrides a string in the file stays a string, e.g. ID_GEN_KEY_PREFIX=2024 is "2024"
+    rather than a number. Properties missing from the file take it as YAML, so such strings need quotes.
+
+    Secrets may be read from files, e.g. mounted by an orchestrator: ID_GEN_AUTH__API_KEYS__0__KEY_FILE
+    or `--set-file auth.api_keys.0.key=/run/secrets/key` set the property to the file's content.
+ */
+
+use std::fs;
+use serde_yaml::{Mapping, Value};
+use crate::config::Error;
+
+const ENV_PREFIX: &str = "ID_GEN_";
+const ENV_PATH_SEPARATOR: &str = "__";
+const ENV_FILE_SUFFIX: &str = "_FILE";
+
+// env vars with the prefix that are not properties
+const NOT_PROPERTIES: [&str; 1] = ["ID_GEN_CFG_PATH"];
+
+
+/// Values applied over properties read from the file, later ones win
+#[derive(Clone, Default)]
+pub struct Overrides {
+    values: Vec<(String, OverrideValue)>,
+}
+
+#[derive(Clone)]
+enum OverrideValue {
+    // parsed as YAML, unless it overrides a string
+    Text(String),
+    // taken as is
+    String(String),
+}
+
+impl Overrides {
+    pub fn from_env() -> Result<Self, Error> {
+        let mut overrides = Self::default();
+
+        let mut vars: Vec<_> = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !NOT_PROPERTIES.contains(&name.as_str()))
+            .collect();
+
+        // env has no order of its own, this one at least doesn't change between runs
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, value) in vars {
+            let value = value.into_string()
+                .map_err(|_| Error::Override(format!("Env var {} is not valid unicode", name)))?;
+
+            let name = &name[ENV_PREFIX.len()..];
+            match name.strip_suffix(ENV_FILE_SUFFIX) {
+                Some(name) => overrides.set_from_file(&env_path(name), &value)?,
+                None => overrides.set(&env_path(name), &value),
+            }
+        }
+
+        Ok(overrides)
+    }
+
+    /// Sets the property to the value parsed as YAML, or as is if the property is a string
+    pub fn set(&mut self, path: &str, value: &str) {
+        self.values.push((path.to_string(), OverrideValue::Text(value.to_string())));
+    }
+
+    /// Sets the property to the value as is, e.g. so that "123" stays a string
+    pub fn set_string(&mut self, path: &str, value: &str) {
+        self.values.push((path.to_string(), OverrideValue::String(value.to_string())));
+    }
+
+    /// Sets the property to content of the file as a string, without the trailing newline
+    pub fn set_from_file(&mut self, path: &str, file: &str) -> Result<(), Error> {
+        let content = fs::read_to_string(file)
+            .map_err(|err| Error::Override(format!("Can't read '{}' for property {}: {}", file, path, err)))?;
+
+        self.set_string(path, content.trim_end_matches(['\r', '\n']));
+        Ok(())
+    }
+
+    /// Parses `path=value` of a command-line flag
+    pub fn set_assignment(&mut self, assignment: &str, from_file: bool) -> Result<(), Error> {
+        let (path, value) = assignment.split_once('=')
+            .ok_or_else(|| Error::Override(format!("Expected path=value, got '{}'", assignment)))?;
+
+        if from_file {
+            self.set_from_file(path.trim(), value)
+        } else {
+            self.set(path.trim(), value);
+            Ok(())
+        }
+    }
+
+    pub fn apply(&self, props: &mut Value) -> Result<(), Error> {
+        for (path, value) in &self.values {
+            let value = match value {
+                OverrideValue::Text(text) if get_at(props, path).is_some_and(Value::is_string) => Value::String(text.clone()),
+                OverrideValue::Text(text) => parse_value(text),
+                OverrideValue::String(string) => Value::String(string.clone()),
+            };
+
+            set_at(props, path, value)
+                .map_err(|err| Error::Override(format!("Can't set property {}: {}", path, err)))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn env_path(name: &str) -> String {
+    name.split(ENV_PATH_SEPARATOR).map(str::to_lowercase).collect::<Vec<_>>().join(".")
+}
+
+// an empty value is an empty string rather than YAML's null, anything that is not valid YAML is a string
+fn parse_value(value: &str) -> Value {
+    if value.is_empty() {
+        return Value::String(String::new());
+    }
+
+    serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn get_at<'a>(target: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(target, |value, head| match value {
+        Value::Sequence(items) => items.get(head.parse::<usize>().ok()?),
+        _ => value.get(head),
+    })
+}
+
+fn set_at(target: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+
+    let slot = match target {
+        Value::Sequence(items) => {
+            let len = items.len();
+            head.parse::<usize>().ok()
+                .and_then(|i| items.get_mut(i))
+                .ok_or_else(|| format!("'{}' is not an index of a list of {} items", head, len))?
+        }
+
+        _ => {
+            if !target.is_mapping() {
+                *target = Value::Mapping(Mapping::new());
+            }
+
+            let map = target.as_mapping_mut().unwrap();
+            let key = Value::String(head.to_string());
+
+            if !map.contains_key(&key) {
+                map.insert(key.clone(), Value::Null);
+            }
+
+            map.get_mut(&key).unwrap()
+        }
+    };
+
+    match rest {
+        Some(rest) => set_at(slot, rest, value),
+        None => {
+            *slot = value;
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+    use crate::config::read_properties;
+    use super::{env_path, Overrides};
+
+    #[test]
+    fn later_overrides_win_and_nested_paths_are_created() {
+        let mut props: Value = serde_yaml::from_str("
+            client_range_max_size: 100
+            key_prefix: ids/
+            auth: { api_keys: [ { name: a, key: old } ] }
+        ").unwrap();
+
+        let mut overrides = Overrides::default();
+        overrides.set(&env_path("CLIENT_RANGE_MAX_SIZE"), "200");
+        overrides.set_assignment("client_range_max_size=300", false).unwrap();
+        overrides.set(&env_path("RATE_LIMITS__DEFAULT__IDS_PER_SEC"), "50");
+        overrides.set("auth.api_keys.0.key", "123");
+        overrides.set_assignment("key_prefix=true", false).unwrap();
+        overrides.set("etcd_addr", "");
+        overrides.apply(&mut props).unwrap();
+
+        // values that override strings stay strings
+        let expected: Value = serde_yaml::from_str("
+            client_range_max_size: 300
+            key_prefix: 'true'
+            auth: { api_keys: [ { name: a, key: '123' } ] }
+            rate_limits: { default: { ids_per_sec: 50 } }
+            etcd_addr: ''
+        ").unwrap();
+        assert_eq!(expected, props);
+
+        overrides.set("auth.api_keys.1.key", "x");
+        assert!(overrides.apply(&mut props).is_err());
+    }
+
+    #[test]
+    fn numeric_api_key_from_env_is_read_as_string() {
+        let defaults = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/configs/default/properties.yaml")).unwrap();
+        let path = std::env::temp_dir().join(format!("id-gen-overrides-{}.yaml", std::process::id()));
+        std::fs::write(&path, defaults + "\nauth: { api_keys: [ { name: a, key: change-me, grants: [] } ] }\n").unwrap();
+
+        std::env::set_var("ID_GEN_AUTH__API_KEYS__0__KEY", "123456");
+        let overrides = Overrides::from_env();
+        std::env::remove_var("ID_GEN_AUTH__API_KEYS__0__KEY");
+
+        let read = read_properties(path.to_str().unwrap(), &overrides.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let (props, _) = read.unwrap();
+        assert_eq!("123456", props.auth.unwrap().api_keys[0].key);
+    }
+}
@@ -0,0 +1,214 @@
+/*
+    Per sequence overrides of global properties, stored in etcd as JSON at meta/policy/{seq}.
+
+    Policies are cached for `policy_ttl_secs`, so a change made through another instance
+    is picked up within that time. Rate limits are applied from the cached policy only,
+    the first requests of a sequence after start are limited by global properties.
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::config::{CacheStrategy, RateLimitProps};
+use crate::etcd_client::{EtcdClient, EtcdErr};
+use crate::forecast::Capacity;
+
+const POLICY_KEY_PREFIX: &str = "policy/";
+// outdated entries of sequences without a policy are dropped this often, any name may get cached
+const CLEANUP_EVERY_N_PUTS: u64 = 1000;
+
+
+/// Fields that are not set fall back to global properties
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SeqPolicy {
+    // ids taken from etcd at once, replaces etcd_fetch_range_size and adaptive fetching
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_size: Option<u64>,
+
+    // lowers the limit of the server or tenant, never raises it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_range_max_size: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_strategy: Option<CacheStrategy>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitProps>,
+
+    // the greatest id the sequence may reach, exhaustion is forecast only for sequences that have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<Capacity>,
+}
+
+impl SeqPolicy {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.fetch_size == Some(0) || self.client_range_max_size == Some(0) {
+            return Err("fetch_size and client_range_max_size must be greater than 0".to_string());
+        }
+
+        if let (Some(fetch_size), Some(max_size)) = (self.fetch_size, self.client_range_max_size) {
+            if max_size >= fetch_size {
+                return Err("client_range_max_size must be less than fetch_size".to_string());
+            }
+        }
+
+        if self.rate_limit.as_ref().is_some_and(|l| l.requests_per_sec == 0 || l.ids_per_sec == 0) {
+            return Err("Rate limits must be greater than 0".to_string());
+        }
+
+        if self.capacity.is_some_and(|c| c.max_id() == 0) {
+            return Err("Capacity must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+
+pub struct PolicyStore {
+    ttl: Duration,
+    policies: Mutex<Policies>,
+}
+
+#[derive(Default)]
+struct Policies {
+    cached: HashMap<String, CachedPolicy>,
+    puts: u64,
+}
+
+struct CachedPolicy {
+    // None if the sequence has no policy
+    policy: Option<SeqPolicy>,
+    loaded_at: Instant,
+}
+
+impl PolicyStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, policies: Mutex::new(Policies::default()) }
+    }
+
+    /// Cached policy of the sequence, loaded from etcd if it is missing or outdated
+    pub async fn get(&self, etcd: &EtcdClient, seq_name: &str) -> Result<Option<SeqPolicy>, EtcdErr> {
+        let stale = match self.policies.lock().unwrap().cached.get(seq_name) {
+            Some(cached) if cached.loaded_at.elapsed() < self.ttl => return Ok(cached.policy.clone()),
+            Some(cached) => Some(cached.policy.clone()),
+            None => None,
+        };
+
+        match load(etcd, seq_name).await {
+            Ok(policy) => {
+                self.put(seq_name, policy.clone());
+                Ok(policy)
+            }
+
+            // better an outdated policy than none, e.g. while etcd is unavailable
+            Err(err) => match stale {
+                Some(policy) => {
+                    warn!("Couldn't reload policy of sequence '{}', the cached one is used: {:?}", seq_name, err);
+                    Ok(policy)
+                }
+                None => Err(err),
+            }
+        }
+    }
+
+    /// Policy of the sequence if it is cached, regardless of its age
+    pub fn cached(&self, seq_name: &str) -> Option<SeqPolicy> {
+        self.policies.lock().unwrap().cached.get(seq_name).and_then(|c| c.policy.clone())
+    }
+
+    pub fn put(&self, seq_name: &str, policy: Option<SeqPolicy>) {
+        let mut policies = self.policies.lock().unwrap();
+
+        // policies are kept past their TTL to be used while etcd is unavailable, absent ones have nothing to offer
+        policies.puts += 1;
+        if policies.puts.is_multiple_of(CLEANUP_EVERY_N_PUTS) {
+            policies.cached.retain(|_, c| c.policy.is_some() || c.loaded_at.elapsed() < self.ttl);
+        }
+
+        policies.cached.insert(seq_name.to_string(), CachedPolicy { policy, loaded_at: Instant::now() });
+    }
+}
+
+
+async fn load(etcd: &EtcdClient, seq_name: &str) -> Result<Option<SeqPolicy>, EtcdErr> {
+    let value = match etcd.get_meta(&policy_key(seq_name)).await? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    match serde_json::from_slice(&value) {
+        Ok(policy) => Ok(Some(policy)),
+        Err(err) => {
+            warn!("Policy of sequence '{}' in etcd is malformed and is ignored: {}", seq_name, err);
+            Ok(None)
+        }
+    }
+}
+
+/// Policies of all sequences that have one, malformed ones are skipped
+pub async fn load_all(etcd: &EtcdClient) -> Result<Vec<(String, SeqPolicy)>, EtcdErr> {
+    let values = etcd.list_meta(POLICY_KEY_PREFIX).await?;
+
+    Ok(values.into_iter()
+        .filter_map(|(seq_name, value)| Some((seq_name, serde_json::from_slice(&value).ok()?)))
+        .collect())
+}
+
+/// Writes the policy to etcd, an empty one is removed. Returns etcd revision of the write,
+/// None if an empty policy was stored for a sequence without one
+pub async fn store(etcd: &EtcdClient, seq_name: &str, policy: &SeqPolicy) -> Result<Option<u64>, EtcdErr> {
+    if policy.is_empty() {
+        etcd.delete_meta(&policy_key(seq_name)).await
+    } else {
+        Ok(Some(etcd.put_meta(&policy_key(seq_name), &serde_json::to_vec(policy).unwrap()).await?))
+    }
+}
+
+fn policy_key(seq_name: &str) -> String {
+    format!("{}{}", POLICY_KEY_PREFIX, seq_name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::config::RateLimitProps;
+    use super::{PolicyStore, SeqPolicy, CLEANUP_EVERY_N_PUTS};
+
+    #[test]
+    fn policies_are_validated() {
+        let policy: SeqPolicy = serde_json::from_str(r#"{"fetch_size": 100000, "client_range_max_size": 10}"#).unwrap();
+        assert!(policy.validate().is_ok());
+
+        let policy = SeqPolicy { fetch_size: Some(10), client_range_max_size: Some(10), ..Default::default() };
+        assert!(policy.validate().is_err());
+
+        let policy = SeqPolicy { rate_limit: Some(RateLimitProps { requests_per_sec: 0, ids_per_sec: 1 }), ..Default::default() };
+        assert!(policy.validate().is_err());
+
+        assert!(serde_json::from_str::<SeqPolicy>(r#"{"fetch_sise": 1}"#).is_err());
+        assert!(SeqPolicy::default().is_empty());
+    }
+
+    #[test]
+    fn outdated_entries_without_a_policy_are_dropped() {
+        let store = PolicyStore::new(Duration::ZERO);
+
+        store.put("orders", Some(SeqPolicy { fetch_size: Some(10), ..Default::default() }));
+        for i in 1..CLEANUP_EVERY_N_PUTS {
+            store.put(&format!("missing-{}", i), None);
+        }
+
+        // the last put cleaned up before it went in
+        assert_eq!(2, store.policies.lock().unwrap().cached.len());
+        assert_eq!(Some(10), store.cached("orders").and_then(|p| p.fetch_size));
+    }
+}
@@ -1,54 +1,463 @@
-use serde::Serialize;
-use crate::cache::CacheClient;
-use crate::config::Properties;
-use crate::etcd_client::{EtcdClient, EtcdErr};
-
-#[derive(Clone, Debug, Serialize)]
-pub struct Range {
-    pub begin: u64,
-    pub end: u64,
-}
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::cache::{CacheClient, Caches};
+use crate::cache::cursor::IdCursors;
+use crate::config::TenantProps;
+use crate::demand::DemandTracker;
+use crate::journal::Journal;
+use crate::reservation::Reservations;
+use crate::interleave::Interleave;
+use crate::auth::matches_pattern;
+use log::{error, info, warn};
+use crate::etcd_client::{EtcdClient, EtcdErr, SeqState, SeqWrite};
+use crate::snapshot::{ImportOutcome, ImportPolicy};
+use crate::policy::{self, PolicyStore, SeqPolicy};
+use crate::forecast::{self, Forecast};
+use crate::telemetry;
+use crate::debug_sampled;
+use opentelemetry::KeyValue;
+use opentelemetry::trace::SpanKind;
+
+pub use ngamahi_id_gen_types::Range;
 
 #[derive(Clone)]
 pub struct RangeProvider {
     pub etcd_client: EtcdClient,
-    pub cache: CacheClient,
+    // one cache per strategy, sequence policies pick theirs
+    pub caches: Caches,
+
+    // decides how many ids to take from etcd at once, shared by all workers
+    pub demand: Arc<DemandTracker>,
+    // shared by all workers, changes on config reload
+    pub max_client_range_size: Arc<AtomicU64>,
+
+    // ranges are journaled to disk only if persistence is configured
+    pub journal: Option<Arc<Journal>>,
+
+    // cached ranges are recorded in etcd only if reservations are collected
+    pub reservations: Option<Arc<Reservations>>,
+
+    pub return_evicted: bool,
+
+    // (pattern, interleave) of sequences whose ids are shared with other regions
+    pub interleave: Vec<(String, Interleave)>,
+
+    pub tenants: HashMap<String, TenantProps>,
+
+    // single ids are served from these, each refill takes next_id_batch ids
+    pub cursors: Rc<IdCursors>,
+    pub next_id_batch: u64,
 
-    pub etcd_fetch_size: u64,
-    pub max_client_range_size: u64,
+    // per sequence overrides of properties, shared by all workers
+    pub policies: Arc<PolicyStore>,
 }
 
 
 impl RangeProvider {
     pub async fn get_next_range(&self, seq_id: String, range_size: u64) -> Result<Vec<Range>, RangeProviderErr> {
+        checked_seq_name(None, &seq_id)?;
+        let values = self.next_range(seq_id.clone(), range_size, self.max_client_range_size.load(Ordering::Relaxed)).await?;
+        self.to_ids(&seq_id, values)
+    }
+
+    pub async fn get_next_id(&self, seq_id: String) -> Result<u64, RangeProviderErr> {
+        checked_seq_name(None, &seq_id)?;
+
+        loop {
+            if let Some(id) = self.cursors.take(&seq_id) {
+                return Ok(id);
+            }
+
+            // a batch must not exceed the sequence's max client range size
+            let batch = match self.policy(&seq_id).await?.client_range_max_size {
+                Some(max_size) => self.next_id_batch.min(max_size),
+                None => self.next_id_batch,
+            };
+
+            // other requests of this worker may use the batch up meanwhile, hence the loop
+            let ranges = self.get_next_range(seq_id.clone(), batch).await?;
+            self.cursors.refill(&seq_id, ranges);
+        }
+    }
+
+    pub async fn get_next_tenant_range(&self, tenant: String, seq_id: String, range_size: u64) -> Result<Vec<Range>, RangeProviderErr> {
+        let max_size = self.tenant(&tenant)?.client_range_max_size;
+        let seq_name = checked_seq_name(Some(&tenant), &seq_id)?;
+
+        let values = self.next_range(seq_name.clone(), range_size, max_size).await?;
+        self.to_ids(&seq_name, values)
+    }
+
+    pub async fn create_sequence(&self, seq_id: String) -> Result<SeqWrite, RangeProviderErr> {
+        let seq_name = checked_seq_name(None, &seq_id)?;
+        Ok(self.etcd_client.create_seq(seq_name).await?)
+    }
+
+    // The limit is checked before creation and not within the same transaction,
+    // so concurrent creations may exceed it slightly
+    pub async fn create_tenant_sequence(&self, tenant: String, seq_id: String) -> Result<SeqWrite, RangeProviderErr> {
+        let max_sequences = self.tenant(&tenant)?.max_sequences;
+        let seq_name = checked_seq_name(Some(&tenant), &seq_id)?;
+
+        let seq_count = self.etcd_client.count_seqs(tenant_seq_name(&tenant, "")).await?;
+        if seq_count >= max_sequences {
+            return Err(RangeProviderErr::TenantLimit(
+                format!("Tenant '{}' already has {} sequences (max {})", &tenant, seq_count, max_sequences)))
+        }
+
+        Ok(self.etcd_client.create_seq(seq_name).await?)
+    }
+
+    /// Both global and tenant sequences, tenant ones are named tenants/{tenant}/{seq}
+    pub async fn list_sequences(&self) -> Result<(u64, Vec<SeqState>), EtcdErr> {
+        let (revision, seqs) = self.etcd_client.list_seqs("".to_string()).await?;
+        let seqs = seqs.into_iter().filter(|s| is_seq_name(&s.name)).collect();
+
+        Ok((revision, seqs))
+    }
+
+    pub async fn sequence_info(&self, seq_id: String) -> Result<SeqState, EtcdErr> {
+        self.etcd_client.get_seq(seq_id).await
+    }
+
+    /// Revision of the write is None if there was no such sequence
+    pub async fn delete_sequence(&self, seq_id: String) -> Result<SeqWrite, EtcdErr> {
+        let deleted = self.etcd_client.delete_seq(seq_id.clone()).await?;
+
+        // cached ids would clash with ids of a new sequence with the same name
+        self.invalidate(&seq_id);
+        self.demand.forget(&seq_id);
+
+        // a new sequence with the same name starts without a policy
+        if deleted.revision.is_some() {
+            policy::store(&self.etcd_client, &seq_id, &SeqPolicy::default()).await?;
+            forecast::delete_history(&self.etcd_client, &seq_id).await?;
+            self.policies.put(&seq_id, None);
+        }
+
+        Ok(deleted)
+    }
+
+    /// Makes sure the sequence won't hand out ids below `min`
+    pub async fn advance_sequence(&self, seq_id: String, min: u64) -> Result<SeqWrite, EtcdErr> {
+        let min = self.interleave_of(&seq_id).map_or(min, |i| i.value_for_id(min));
+        let values = self.etcd_client.advance_seq(seq_id.clone(), min).await?;
+
+        // cached ranges may hold ids below the new minimum
+        self.invalidate(&seq_id);
+
+        Ok(values)
+    }
+
+    pub async fn reset_sequence(&self, seq_id: String, expected_value: u64, new_value: u64) -> Result<SeqWrite, EtcdErr> {
+        let (expected_value, new_value) = match self.interleave_of(&seq_id) {
+            Some(i) => (i.value_for_id(expected_value), i.value_for_id(new_value)),
+            None => (expected_value, new_value),
+        };
+        let write = self.etcd_client.reset_seq(seq_id.clone(), expected_value, new_value).await?;
+
+        // cached ranges were allocated from the old value
+        self.invalidate(&seq_id);
+
+        Ok(write)
+    }
+
+    /// Returns etcd revision of the write along with the outcome
+    pub async fn import_sequence(&self, seq: &SeqState, policy: ImportPolicy) -> Result<(ImportOutcome, Option<u64>), EtcdErr> {
+        let (outcome, revision) = self.etcd_client.import_seq(seq.name.clone(), seq.value, policy).await?;
+
+        if let ImportOutcome::Updated { .. } = outcome {
+            self.invalidate(&seq.name);
+        }
+
+        Ok((outcome, revision))
+    }
+
+    pub async fn sequence_policy(&self, seq_id: String) -> Result<Option<SeqPolicy>, EtcdErr> {
+        self.policies.get(&self.etcd_client, &seq_id).await
+    }
+
+    /// Stores the policy of an existing sequence, an empty policy removes it
+    /// Returns the policy replaced and etcd revision of the write
+    pub async fn set_sequence_policy(&self, seq_id: String, new_policy: SeqPolicy) -> Result<(SeqPolicy, Option<u64>), RangeProviderErr> {
+        new_policy.validate().map_err(RangeProviderErr::Validation)?;
+
+        // fails if there is no such sequence
+        self.etcd_client.get_seq(seq_id.clone()).await?;
+
+        let old_policy = self.policy(&seq_id).await?;
+        let revision = policy::store(&self.etcd_client, &seq_id, &new_policy).await?;
+        self.policies.put(&seq_id, (!new_policy.is_empty()).then_some(new_policy.clone()));
+
+        // ranges cached by the old strategy would never be served, so workers drop them
+        if old_policy.cache_strategy != new_policy.cache_strategy {
+            self.invalidate(&seq_id);
+        }
+
+        Ok((old_policy, revision))
+    }
+
+    /// Sequences without a declared capacity are forecast against u64
+    pub async fn sequence_forecast(&self, seq_id: String) -> Result<Forecast, EtcdErr> {
+        let capacity = self.policy(&seq_id).await?.capacity.map_or(u64::MAX, |c| c.max_id());
+        let state = self.etcd_client.get_seq(seq_id.clone()).await?;
+        let history = forecast::load_history(&self.etcd_client, &seq_id).await?;
+
+        let position = forecast::position(self.interleave_of(&seq_id), state.value);
+        Ok(Forecast::new(capacity, position, &history))
+    }
+
+    async fn policy(&self, seq_id: &str) -> Result<SeqPolicy, EtcdErr> {
+        Ok(self.policies.get(&self.etcd_client, seq_id).await?.unwrap_or_default())
+    }
+
+    fn cache_of(&self, policy: &SeqPolicy) -> &CacheClient {
+        self.caches.get(policy.cache_strategy)
+    }
+
+    fn interleave_of(&self, seq_id: &str) -> Option<&Interleave> {
+        self.interleave.iter().find(|(pattern, _)| matches_pattern(pattern, seq_id)).map(|(_, i)| i)
+    }
+
+    // ranges of sequence values become ranges of ids only on their way to clients,
+    // values are what etcd, cache and journal work with
+    fn to_ids(&self, seq_id: &str, values: Vec<Range>) -> Result<Vec<Range>, RangeProviderErr> {
+        let interleave = match self.interleave_of(seq_id) {
+            Some(interleave) => interleave,
+            None => return Ok(values),
+        };
+
+        values.iter()
+            .map(|v| interleave.to_ids(v)
+                .ok_or_else(|| RangeProviderErr::Overflow(format!("Ids of sequence '{}' don't fit in u64", seq_id))))
+            .collect()
+    }
+
+    fn invalidate(&self, seq_id: &str) {
+        // invalidation reaches caches of every strategy
+        self.caches.get(None).invalidate(seq_id);
+
+        if let Some(journal) = &self.journal {
+            // a reset is still caught by the revision of the sequence on restart
+            if let Err(err) = journal.dropped(seq_id) {
+                error!("Couldn't journal invalidation of sequence '{}': {}", seq_id, err);
+            }
+        }
+
+        if let Some(reservations) = &self.reservations {
+            let names = reservations.release(seq_id);
+            if names.is_empty() {
+                return;
+            }
+
+            // a record left behind only costs the leader a failed return once this instance is gone
+            let etcd_client = self.etcd_client.clone();
+            actix_web::rt::spawn(async move {
+                for name in names {
+                    if let Err(err) = etcd_client.delete_meta(&name).await {
+                        warn!("Couldn't delete reservation record '{}': {:?}", name, err);
+                    }
+                }
+            });
+        }
+    }
+
+    fn tenant(&self, tenant: &str) -> Result<&TenantProps, RangeProviderErr> {
+        self.tenants.get(tenant).ok_or_else(|| RangeProviderErr::NoSuchTenant(tenant.to_string()))
+    }
+
+    async fn next_range(&self, seq_id: String, range_size: u64, max_range_size: u64) -> Result<Vec<Range>, RangeProviderErr> {
+        let cx = telemetry::start_span("RangeProvider::next_range", SpanKind::Internal,
+            vec![KeyValue::new(telemetry::SEQUENCE, seq_id.clone()), KeyValue::new(telemetry::RANGE_SIZE, range_size as i64)]);
+
+        telemetry::in_span(cx, self.allocate(seq_id, range_size, max_range_size)).await
+    }
+
+    async fn allocate(&self, seq_id: String, range_size: u64, max_range_size: u64) -> Result<Vec<Range>, RangeProviderErr> {
+        let policy = self.policy(&seq_id).await?;
+        // a policy may only lower the limit of the tenant or server
+        let max_range_size = policy.client_range_max_size.map_or(max_range_size, |max| max.min(max_range_size));
+        let cache = self.cache_of(&policy);
+
         // check if client requests a range of proper size
-        if range_size > self.max_client_range_size {
+        if range_size > max_range_size {
             return Err(
                 RangeProviderErr::Validation(
                     format!("Client requested too large range (requested {}, max {})",
-                            &range_size, self.max_client_range_size)
+                            &range_size, max_range_size)
                 ))
         }
 
+        let epoch = cache.epoch();
+
         // first, try to get requested range from cache
-        let (mut from_cache, needed) = self.cache.get(seq_id.clone(), range_size).await;
+        let (mut from_cache, mut needed) = cache.get(seq_id.clone(), range_size).await;
+
+        // ranges left unserved before restart go to cache on the first miss
+        if needed > 0 {
+            if let Some(restored) = self.journal.as_ref().and_then(|j| j.take_restored(&seq_id)) {
+                for range in restored {
+                    cache.put(seq_id.clone(), range).await;
+                }
+
+                let (more, still_needed) = cache.get(seq_id.clone(), needed).await;
+                from_cache.extend(more);
+                needed = still_needed;
+            }
+        }
+
+        self.release_evicted();
+        telemetry::record(KeyValue::new(telemetry::CACHE_HIT, needed == 0));
+
         if needed == 0 {
+            debug_sampled!("Served {} ids of {} from cache", range_size, seq_id);
+            self.claim_served(&seq_id, &from_cache).await?;
+            self.journal_served(&seq_id, &from_cache).await?;
+            self.demand.record(&seq_id, range_size, None);
             return Ok(from_cache)
         }
 
         // if there wasn't enough ranges in cache, get new range from etcd
-        let new_range = self.etcd_client.next_range(seq_id.clone(), self.etcd_fetch_size).await?;
+        let fetch_size = match policy.fetch_size {
+            Some(fetch_size) => fetch_size.max(needed + 1),
+            None => self.demand.next_fetch_size(&seq_id, needed + 1),
+        };
+        telemetry::record(KeyValue::new(telemetry::FETCH_SIZE, fetch_size as i64));
+        debug_sampled!("Fetching {} ids of {} from etcd, {} missing in cache", fetch_size, seq_id, needed);
+        let (new_range, mod_revision) = self.etcd_client.next_range(seq_id.clone(), fetch_size).await?;
+
+        // the sequence was deleted or reset meanwhile, so these ranges may belong to its old incarnation
+        if cache.invalidated_since(epoch, &seq_id) {
+            return Err(RangeProviderErr::SeqChanged(seq_id))
+        }
+
+        if let Some(journal) = &self.journal {
+            journal.fetched(&seq_id, &new_range, mod_revision).await.map_err(RangeProviderErr::Journal)?;
+        }
+
         let (left, rest) = split_range(new_range, needed).unwrap();
 
+        // a range left without a record is never given back, so its ids are safe to serve anyway
+        if let Some(reservations) = &self.reservations {
+            if let Err(err) = reservations.reserve(&self.etcd_client, &seq_id, &rest, mod_revision).await {
+                warn!("Couldn't record reservation of range {:?} of sequence '{}': {:?}", rest, seq_id, err);
+            }
+        }
+
         // one part of new range is returned alongside with cached ones, rest is pushed to cache
-        self.cache.put(seq_id, rest).await;
+        cache.put(seq_id.clone(), rest).await;
         from_cache.push(left);
 
+        self.claim_served(&seq_id, &from_cache).await?;
+        self.journal_served(&seq_id, &from_cache).await?;
+        self.demand.record(&seq_id, range_size, Some(fetch_size));
         Ok(from_cache)
     }
 
-    pub async fn create_sequence(&self, seq_id: String) -> Result<(), EtcdErr> {
-        self.etcd_client.create_seq(seq_id).await
+    // Evicted ranges are journaled as served, so they are not resumed after restart, and may go back to etcd.
+    // Both happen in background, it is not worth delaying the request
+    fn release_evicted(&self) {
+        let evicted = self.caches.drain_evicted();
+
+        if evicted.is_empty() || (self.journal.is_none() && !self.return_evicted) {
+            return;
+        }
+
+        let journal = self.journal.clone();
+        let reservations = self.reservations.clone();
+        let return_evicted = self.return_evicted;
+        let etcd_client = self.etcd_client.clone();
+
+        actix_web::rt::spawn(async move {
+            for (seq_id, range) in evicted {
+                if let Some(journal) = &journal {
+                    if let Err(err) = journal.served(&seq_id, std::slice::from_ref(&range)).await {
+                        // the range may be resumed after restart, so it must not be given to others
+                        error!("Couldn't journal evicted range {:?} of sequence '{}': {}", range, seq_id, err);
+                        continue;
+                    }
+                }
+
+                if !return_evicted {
+                    continue;
+                }
+
+                // the leader must not give the range back once more
+                if let Some(reservations) = &reservations {
+                    match reservations.claim(&etcd_client, &seq_id, std::slice::from_ref(&range)).await {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(err) => {
+                            warn!("Couldn't claim evicted range {:?} of sequence '{}', it is not returned: {:?}", range, seq_id, err);
+                            continue;
+                        }
+                    }
+                }
+
+                match etcd_client.return_range(seq_id.clone(), &range).await {
+                    Ok(true) => info!("Returned evicted range {:?} of sequence '{}' to etcd", range, seq_id),
+                    Ok(false) => {}
+                    Err(err) => warn!("Couldn't return evicted range {:?} of sequence '{}': {:?}", range, seq_id, err),
+                }
+            }
+        });
+    }
+
+    // ids of recorded ranges must be claimed before clients get them, or the leader could give them back to etcd
+    async fn claim_served(&self, seq_id: &str, ranges: &[Range]) -> Result<(), RangeProviderErr> {
+        let reservations = match &self.reservations {
+            Some(reservations) => reservations,
+            None => return Ok(()),
+        };
+
+        if reservations.claim(&self.etcd_client, seq_id, ranges).await? {
+            return Ok(());
+        }
+
+        // the leader took this instance for gone, what is left in cache may be handed out by others
+        warn!("Reservation of sequence '{}' was collected, dropping its cached ranges", seq_id);
+        self.invalidate(seq_id);
+        Err(RangeProviderErr::SeqChanged(seq_id.to_string()))
+    }
+
+    // ids must be journaled as served before clients get them, or they could be served again after restart
+    async fn journal_served(&self, seq_id: &str, ranges: &[Range]) -> Result<(), RangeProviderErr> {
+        match &self.journal {
+            Some(journal) => journal.served(seq_id, ranges).await.map_err(RangeProviderErr::Journal),
+            None => Ok(()),
+        }
+    }
+}
+
+
+// Sequences of a tenant live under their own key prefix, names from clients must go through checked_seq_name
+pub fn tenant_seq_name(tenant: &str, seq_id: &str) -> String {
+    format!("tenants/{}/{}", tenant, seq_id)
+}
+
+/// Full name of a sequence from names a client gave. They come from decoded path segments, where %2F
+/// is '/', or from gRPC fields, and a '/' in them would reach other tenants' sequences or meta keys
+pub fn checked_seq_name(tenant: Option<&str>, seq_id: &str) -> Result<String, RangeProviderErr> {
+    let seq_name = match tenant {
+        Some(tenant) if !tenant.is_empty() && !tenant.contains('/') => tenant_seq_name(tenant, seq_id),
+        Some(tenant) => return Err(RangeProviderErr::Validation(format!("Bad tenant name '{}'", tenant))),
+        None => seq_id.to_string(),
+    };
+
+    if seq_id.is_empty() || seq_id.contains('/') || !is_seq_name(&seq_name) {
+        return Err(RangeProviderErr::Validation(format!("Bad sequence name '{}'", seq_name)));
+    }
+
+    Ok(seq_name)
+}
+
+/// Tells sequences apart from other keys stored under the key prefix
+pub fn is_seq_name(name: &str) -> bool {
+    match name.strip_prefix("tenants/") {
+        Some(tenant_seq) => matches!(tenant_seq.split_once('/'), Some((tenant, seq)) if !tenant.is_empty() && !seq.is_empty() && !seq.contains('/')),
+        None => !name.is_empty() && !name.contains('/'),
     }
 }
 
@@ -60,24 +469,15 @@ pub fn get_range_size(r: &Range) -> u64 {
 // left's size is size, right is the rest
 // returns none if size is bigger than one of given Range
 pub fn split_range(r: Range, size: u64) -> Option<(Range, Range)> {
-    println!("Must split range {:?}. Size is {}", &r, size);
-    println!("The range size is {}", get_range_size(&r));
-
     if get_range_size(&r) <= size {
         return None;
     }
 
-    let left = Range {
-        begin: r.begin,
-        end: r.begin + size,
-    };
+    let left = Range::new(r.begin, r.begin + size);
 
-    let right = Range {
-        begin: r.begin + size + 1,
-        end: r.end
-    };
+    let right = Range::new(r.begin + size, r.end);
 
-    return Some((left, right));
+    Some((left, right))
 }
 
 
@@ -86,13 +486,44 @@ pub fn split_range(r: Range, size: u64) -> Option<(Range, Range)> {
 
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum RangeProviderErr{
     Etcd(EtcdErr),
-    Validation(String)
+    Validation(String),
+    NoSuchTenant(String),
+    TenantLimit(String),
+    SeqChanged(String),
+    Journal(std::io::Error),
+    Overflow(String),
 }
 
 impl From<EtcdErr> for RangeProviderErr {
     fn from(value: EtcdErr) -> Self {
         Self::Etcd(value)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checked_seq_name, split_range, Range};
+
+    #[test]
+    fn split_range_keeps_every_id() {
+        let (left, right) = split_range(Range::new(100, 200), 30).unwrap();
+
+        assert_eq!(Range::new(100, 130), left);
+        assert_eq!(Range::new(130, 200), right);
+        assert_eq!(None, split_range(Range::new(100, 130), 30));
+    }
+
+    #[test]
+    fn names_with_slashes_are_rejected() {
+        assert_eq!("orders", checked_seq_name(None, "orders").unwrap());
+        assert_eq!("tenants/acme/orders", checked_seq_name(Some("acme"), "orders").unwrap());
+
+        // what %2F in a path segment decodes to
+        for (tenant, seq_id) in [(None, "tenants/acme/orders"), (None, "meta/policy/orders"), (None, ""), (Some("acme"), "a/b"), (Some("acme/x"), "orders"), (Some(""), "orders")] {
+            assert!(checked_seq_name(tenant, seq_id).is_err(), "{:?} {}", tenant, seq_id);
+        }
+    }
+}
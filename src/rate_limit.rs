@@ -0,0 +1,228 @@
+/*
+    Token bucket rate limiting of range requests.
+
+    Every (client, sequence) pair gets two buckets: one for requests and one for ids.
+    A request passes only if both buckets have enough tokens, buckets refill continuously
+    at configured per second rates and hold at most one second worth of tokens.
+    A sequence's policy, if it sets a limit, takes precedence over configured limits.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use actix_web::HttpRequest;
+use crate::auth::{Caller, matches_pattern};
+use crate::config::{RateLimitProps, RateLimitsProps};
+use crate::policy::PolicyStore;
+
+// buckets untouched for this long are full anyway and can be forgotten
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+const CLEANUP_EVERY_N_CHECKS: u64 = 10_000;
+
+
+pub struct RateLimiter {
+    props: RwLock<RateLimitsProps>,
+    policies: Arc<PolicyStore>,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<(String, String), Buckets>,
+    checks: u64,
+}
+
+struct Buckets {
+    requests: Bucket,
+    ids: Bucket,
+    last_refill: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    rate: f64,
+}
+
+
+impl RateLimiter {
+    pub fn new(props: RateLimitsProps, policies: Arc<PolicyStore>) -> Self {
+        Self {
+            props: RwLock::new(props),
+            policies,
+            state: Mutex::new(State { buckets: HashMap::new(), checks: 0 }),
+        }
+    }
+
+    /// Takes tokens for a request of `ids` ids. If there are not enough of them,
+    /// returns how long the client should wait before retrying.
+    pub fn check(&self, client: &str, seq_name: &str, ids: u64) -> Result<(), Duration> {
+        let limit = match self.limit_for(seq_name) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        state.checks += 1;
+        if state.checks.is_multiple_of(CLEANUP_EVERY_N_CHECKS) {
+            state.buckets.retain(|_, b| now.duration_since(b.last_refill) < IDLE_BUCKET_TTL);
+        }
+
+        let buckets = state.buckets
+            .entry((client.to_string(), seq_name.to_string()))
+            .or_insert_with(|| Buckets::full(&limit, now));
+
+        buckets.refill(now, &limit);
+
+        let wait = buckets.requests.wait_time(1.0).max(buckets.ids.wait_time(ids as f64));
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        buckets.requests.tokens -= 1.0;
+        buckets.ids.tokens -= ids as f64;
+
+        Ok(())
+    }
+
+    /// Replaces limits on config reload. Buckets start over full
+    pub fn reconfigure(&self, props: RateLimitsProps) {
+        *self.props.write().unwrap() = props;
+        self.state.lock().unwrap().buckets.clear();
+    }
+
+    /// Clients with API keys are told apart by key, anonymous ones by the address they connect from,
+    /// or the one forwarded by a trusted proxy
+    pub fn client_identity(&self, caller: &Caller, req: &HttpRequest) -> String {
+        let peer = req.peer_addr().map(|a| a.ip());
+        let behind_proxy = peer.is_some_and(|ip| self.props.read().unwrap().trusted_proxies.contains(&ip));
+
+        let conn = req.connection_info();
+        // without forwarding headers the real ip is the peer's address with its port
+        let forwarded = conn.realip_remote_addr().filter(|addr| behind_proxy && Some(*addr) != conn.peer_addr());
+
+        match forwarded {
+            Some(addr) => client_identity_of(caller, Some(addr)),
+            None => client_identity_of(caller, peer.map(|ip| ip.to_string()).as_deref()),
+        }
+    }
+
+    // the sequence's policy wins, then the first rule whose pattern matches the sequence, then the default one.
+    // Only cached policies are looked at, checks must not wait for etcd
+    fn limit_for(&self, seq_name: &str) -> Option<RateLimitProps> {
+        if let Some(limit) = self.policies.cached(seq_name).and_then(|p| p.rate_limit) {
+            return Some(limit);
+        }
+
+        let props = self.props.read().unwrap();
+
+        props.sequences.iter()
+            .find(|rule| matches_pattern(&rule.pattern, seq_name))
+            .map(|rule| &rule.limit)
+            .or(props.default.as_ref())
+            .cloned()
+    }
+}
+
+
+impl Buckets {
+    fn full(limit: &RateLimitProps, now: Instant) -> Self {
+        Self {
+            requests: Bucket { tokens: limit.requests_per_sec as f64, rate: limit.requests_per_sec as f64 },
+            ids: Bucket { tokens: limit.ids_per_sec as f64, rate: limit.ids_per_sec as f64 },
+            last_refill: now,
+        }
+    }
+
+    // the limit may have changed since the buckets were created, e.g. by a new policy
+    fn refill(&mut self, now: Instant, limit: &RateLimitProps) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.requests.rate = limit.requests_per_sec as f64;
+        self.ids.rate = limit.ids_per_sec as f64;
+
+        self.requests.refill(elapsed);
+        self.ids.refill(elapsed);
+        self.last_refill = now;
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, elapsed_secs: f64) {
+        self.tokens = (self.tokens + elapsed_secs * self.rate).min(self.rate);
+    }
+
+    // A request bigger than the bucket passes when the bucket is full and leaves it in debt,
+    // which following requests pay off by waiting
+    fn wait_time(&self, needed: f64) -> Duration {
+        let needed = needed.min(self.rate);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64((needed - self.tokens) / self.rate)
+    }
+}
+
+
+pub fn client_identity_of(caller: &Caller, remote_addr: Option<&str>) -> String {
+    match caller {
+        Caller::ApiKey { name, .. } => format!("key:{}", name),
+        Caller::Anonymous => format!("ip:{}", remote_addr.unwrap_or("unknown")),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use actix_web::test::TestRequest;
+    use crate::auth::Caller;
+    use crate::config::{RateLimitProps, RateLimitsProps};
+    use crate::policy::{PolicyStore, SeqPolicy};
+    use super::RateLimiter;
+
+    #[test]
+    fn limits_ids_per_client_and_sequence() {
+        let policies = Arc::new(PolicyStore::new(Duration::from_secs(30)));
+        let limiter = RateLimiter::new(RateLimitsProps {
+            default: Some(RateLimitProps { requests_per_sec: 100, ids_per_sec: 10 }),
+            sequences: vec![],
+            ..Default::default()
+        }, policies.clone());
+
+        assert!(limiter.check("client-1", "seq", 10).is_ok());
+        assert!(limiter.check("client-1", "seq", 1).is_err());
+
+        // other client and other sequence have their own buckets
+        assert!(limiter.check("client-2", "seq", 10).is_ok());
+        assert!(limiter.check("client-1", "other-seq", 10).is_ok());
+
+        // a policy's limit wins over configured ones
+        let limit = RateLimitProps { requests_per_sec: 100, ids_per_sec: 1000 };
+        policies.put("seq", Some(SeqPolicy { rate_limit: Some(limit), ..Default::default() }));
+        assert!(limiter.check("client-3", "seq", 1000).is_ok());
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_from_trusted_proxies() {
+        let limiter = RateLimiter::new(RateLimitsProps {
+            trusted_proxies: vec!["10.0.0.5".parse().unwrap()],
+            ..Default::default()
+        }, Arc::new(PolicyStore::new(Duration::from_secs(30))));
+
+        let identity = |peer: &str, forwarded: Option<&str>| {
+            let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+            if let Some(forwarded) = forwarded {
+                req = req.insert_header(("X-Forwarded-For", forwarded));
+            }
+            limiter.client_identity(&Caller::Anonymous, &req.to_http_request())
+        };
+
+        assert_eq!("ip:192.168.1.7", identity("192.168.1.7:4040", None));
+        assert_eq!("ip:192.168.1.7", identity("192.168.1.7:5050", Some("1.2.3.4")));
+        assert_eq!("ip:1.2.3.4", identity("10.0.0.5:4040", Some("1.2.3.4")));
+        assert_eq!("ip:10.0.0.5", identity("10.0.0.5:4040", None));
+    }
+}
@@ -0,0 +1,240 @@
+/*
+    Reloading of properties.yaml without restart, on SIGHUP and when the file changes.
+    Env vars and flags still override the file afterwards.
+
+    New properties are validated as a whole before anything is applied. Only fetch size, max
+    client range size, rate limits and logging are applied live. A change of any other property needs
+    a restart, so such a reload is refused as a whole and the running properties stay.
+    Every applied change is logged.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::{error, info};
+use serde_yaml::Value;
+use crate::config::{self, Configs};
+use crate::overrides::Overrides;
+use crate::demand::DemandTracker;
+use crate::rate_limit::RateLimiter;
+use crate::logging;
+
+// top level properties that are applied without restart
+const LIVE_PROPS: [&str; 4] = ["etcd_fetch_range_size", "client_range_max_size", "rate_limits", "logging"];
+
+
+pub struct Reloader {
+    path: String,
+    overrides: Overrides,
+
+    // properties as applied last, new ones are compared to these
+    applied: Mutex<Value>,
+
+    demand: Arc<DemandTracker>,
+    rate_limiter: Arc<RateLimiter>,
+    max_client_range_size: Arc<AtomicU64>,
+}
+
+/// A property that differs between applied and new properties. None means it is not set
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+
+impl Reloader {
+    pub fn new(configs: &Configs, demand: Arc<DemandTracker>, rate_limiter: Arc<RateLimiter>, max_client_range_size: Arc<AtomicU64>) -> Self {
+        Self {
+            path: configs.props_path.clone(),
+            overrides: configs.overrides.clone(),
+            applied: Mutex::new(configs.raw_props.clone()),
+            demand,
+            rate_limiter,
+            max_client_range_size,
+        }
+    }
+
+    /// Re-reads properties and applies them if every change can be applied live
+    pub fn reload(&self) -> Result<Vec<Change>, ReloadErr> {
+        let (props, raw_props) = config::read_properties(&self.path, &self.overrides)?;
+        let mut applied = self.applied.lock().unwrap();
+
+        let mut changes = vec![];
+        diff("", Some(&applied), Some(&raw_props), &mut changes);
+
+        let needs_restart: Vec<String> = changes.iter().filter(|c| !c.is_live()).map(|c| c.path.clone()).collect();
+        if !needs_restart.is_empty() {
+            return Err(ReloadErr::RestartRequired(needs_restart));
+        }
+
+        self.demand.set_fetch_size(props.etcd_fetch_range_size);
+        self.max_client_range_size.store(props.client_range_max_size, Ordering::Relaxed);
+        logging::set_debug_sample_every(props.logging.debug_sample_every);
+
+        // reconfiguring refills buckets, so it is done only if limits changed
+        if changes.iter().any(|c| c.top_level() == "rate_limits") {
+            self.rate_limiter.reconfigure(props.rate_limits);
+        }
+
+        *applied = raw_props;
+        Ok(changes)
+    }
+
+    fn reload_and_log(&self, reason: &str) {
+        match self.reload() {
+            Ok(changes) if changes.is_empty() => info!("Properties reloaded on {}, nothing changed", reason),
+            Ok(changes) => {
+                for change in changes {
+                    info!("Property changed on {}: {}", reason, change);
+                }
+            }
+            Err(ReloadErr::RestartRequired(paths)) =>
+                error!("Properties not reloaded on {}, changes of {} need a restart", reason, paths.join(", ")),
+            Err(ReloadErr::Config(err)) =>
+                error!("Properties not reloaded on {}: {:?}", reason, err),
+        }
+    }
+}
+
+impl Change {
+    fn top_level(&self) -> &str {
+        self.path.split('.').next().unwrap_or_default()
+    }
+
+    fn is_live(&self) -> bool {
+        LIVE_PROPS.contains(&self.top_level())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if [&self.old, &self.new].into_iter().flatten().any(|value| config::is_secret(&self.path, value)) {
+            return write!(f, "{} (value hidden)", self.path);
+        }
+
+        let show = |value: &Option<Value>| match value {
+            Some(value) => serde_json::to_string(value).unwrap_or_default(),
+            None => "(not set)".to_string(),
+        };
+
+        write!(f, "{}: {} -> {}", self.path, show(&self.old), show(&self.new))
+    }
+}
+
+
+/// Reloads properties on SIGHUP and, unless `check_secs` is 0, when the file's modification time changes
+pub fn watch(reloader: Reloader, check_secs: u64) -> io::Result<()> {
+    let reloader = Arc::new(reloader);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        let reloader = reloader.clone();
+
+        actix_web::rt::spawn(async move {
+            while hangups.recv().await.is_some() {
+                reloader.reload_and_log("SIGHUP");
+            }
+        });
+    }
+
+    if check_secs > 0 {
+        actix_web::rt::spawn(async move {
+            let mut modified = modified_at(&reloader.path);
+
+            loop {
+                actix_web::rt::time::sleep(Duration::from_secs(check_secs)).await;
+
+                let now_modified = modified_at(&reloader.path);
+                if now_modified != modified {
+                    modified = now_modified;
+                    reloader.reload_and_log("file change");
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// collects changed leaves of mappings, other values are compared as a whole
+fn diff(path: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    if let (Some(Value::Mapping(old)), Some(Value::Mapping(new))) = (old, new) {
+        let mut keys: Vec<&Value> = old.keys().collect();
+        keys.extend(new.keys().filter(|k| !old.contains_key(*k)));
+
+        for key in keys {
+            let key_name = match key {
+                Value::String(s) => s.clone(),
+                other => serde_json::to_string(other).unwrap_or_default(),
+            };
+            let path = if path.is_empty() { key_name } else { format!("{}.{}", path, key_name) };
+
+            diff(&path, old.get(key), new.get(key), changes);
+        }
+
+        return;
+    }
+
+    if old != new {
+        changes.push(Change { path: path.to_string(), old: old.cloned(), new: new.cloned() });
+    }
+}
+
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum ReloadErr {
+    Config(config::Error),
+    // paths of changed properties that can't be applied live
+    RestartRequired(Vec<String>),
+}
+
+impl From<config::Error> for ReloadErr {
+    fn from(value: config::Error) -> Self {
+        Self::Config(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+    use super::diff;
+
+    #[test]
+    fn nested_changes_are_found_and_classified() {
+        let old: Value = serde_yaml::from_str("
+            client_range_max_size: 100
+            rate_limits: { default: { requests_per_sec: 10, ids_per_sec: 100 } }
+            auth: { api_keys: [ { name: a, key: secret } ] }
+        ").unwrap();
+        let new: Value = serde_yaml::from_str("
+            client_range_max_size: 100
+            rate_limits: { default: { requests_per_sec: 20, ids_per_sec: 100 } }
+            auth: { api_keys: [ { name: a, key: other } ] }
+            key_prefix: ids/
+        ").unwrap();
+
+        let mut changes = vec![];
+        diff("", Some(&old), Some(&new), &mut changes);
+
+        let paths: Vec<(&str, bool)> = changes.iter().map(|c| (c.path.as_str(), c.is_live())).collect();
+        assert_eq!(vec![("rate_limits.default.requests_per_sec", true), ("auth.api_keys", false), ("key_prefix", false)], paths);
+
+        assert_eq!("rate_limits.default.requests_per_sec: 10 -> 20", changes[0].to_string());
+        assert_eq!("auth.api_keys (value hidden)", changes[1].to_string());
+        assert_eq!("key_prefix: (not set) -> \"ids/\"", changes[2].to_string());
+    }
+}
@@ -0,0 +1,153 @@
+/*
+    Records of ranges an instance took from etcd and keeps in cache, so that the leader can give their
+    ids back to etcd once the instance is gone (see maintenance.rs).
+
+    A record under meta/reservations/{instance}/{revision of the fetch} holds the sequence, the range
+    and the claimed mark. Ids are served only below the mark, which moves ahead in CLAIM_STEPS steps
+    by compare-and-set of the whole record. The leader deletes a record the same way before it returns
+    the ids above the mark, so either its delete or a claim of the instance wins, and no served id is
+    returned. An instance that finds its record gone must drop the sequence from cache.
+
+    Every instance keeps meta/instances/{instance} with its election lease (see leader.rs), an
+    instance without the key is gone.
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use crate::etcd_client::{EtcdClient, EtcdErr};
+use crate::range::Range;
+
+pub const RESERVATIONS: &str = "reservations/";
+pub const INSTANCES: &str = "instances/";
+
+const CLAIM_STEPS: u64 = 8;
+
+
+/// A range taken from etcd, ids of it below `claimed` may have been served
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub seq: String,
+    pub range: Range,
+    pub claimed: u64,
+}
+
+/// Reservations of this instance, shared by all workers
+pub struct Reservations {
+    instance_id: String,
+    // by sequence, along with names of their records
+    held: Mutex<HashMap<String, Vec<(String, Reservation)>>>,
+}
+
+
+impl Reservations {
+    pub fn new(instance_id: String) -> Self {
+        Self { instance_id, held: Mutex::new(HashMap::new()) }
+    }
+
+    /// Records a range before it goes to cache, with its first step claimed
+    pub async fn reserve(&self, etcd: &EtcdClient, seq_name: &str, range: &Range, fetch_revision: u64) -> Result<(), EtcdErr> {
+        if range.begin == range.end {
+            return Ok(());
+        }
+
+        let name = format!("{}{}/{}", RESERVATIONS, self.instance_id, fetch_revision);
+        let reservation = Reservation { seq: seq_name.to_string(), range: range.clone(), claimed: next_claim(range, range.begin, range.begin) };
+
+        etcd.put_meta(&name, &serde_json::to_vec(&reservation).unwrap()).await?;
+
+        self.held.lock().unwrap().entry(seq_name.to_string()).or_default().push((name, reservation));
+        Ok(())
+    }
+
+    /// Moves claimed marks past the ranges before they are served.
+    /// Returns false if a record is gone, i.e. the leader took its ids back
+    pub async fn claim(&self, etcd: &EtcdClient, seq_name: &str, ranges: &[Range]) -> Result<bool, EtcdErr> {
+        for range in ranges {
+            while let Some((name, reservation)) = self.unclaimed(seq_name, range) {
+                if !self.claim_one(etcd, &name, reservation, range.end).await? {
+                    self.held.lock().unwrap().entry(seq_name.to_string()).or_default().retain(|(n, _)| *n != name);
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Forgets reservations of the sequence, e.g. it was reset or deleted. Returns names of their records
+    pub fn release(&self, seq_name: &str) -> Vec<String> {
+        self.held.lock().unwrap().remove(seq_name).unwrap_or_default()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // a reservation of the sequence with ids of the range above its mark
+    fn unclaimed(&self, seq_name: &str, range: &Range) -> Option<(String, Reservation)> {
+        self.held.lock().unwrap().get(seq_name)?.iter()
+            .find(|(_, r)| range.begin < r.range.end && r.range.begin < range.end && r.claimed < range.end.min(r.range.end))
+            .cloned()
+    }
+
+    // false if the record is gone
+    async fn claim_one(&self, etcd: &EtcdClient, name: &str, mut reservation: Reservation, up_to: u64) -> Result<bool, EtcdErr> {
+        for _ in 0..5 { //todo: make a property
+            let old = serde_json::to_vec(&reservation).unwrap();
+            let claimed = next_claim(&reservation.range, reservation.claimed, up_to);
+            let new = Reservation { claimed, ..reservation.clone() };
+
+            // a fully claimed record has nothing to give back
+            let written = match claimed == new.range.end {
+                true => etcd.compare_and_put_meta(name, &old, None).await?,
+                false => etcd.compare_and_put_meta(name, &old, Some(&serde_json::to_vec(&new).unwrap())).await?,
+            };
+
+            if written {
+                self.update(name, new);
+                return Ok(true);
+            }
+
+            // another worker may have claimed meanwhile
+            match etcd.get_meta(name).await? {
+                None => return Ok(false),
+                Some(current) => match serde_json::from_slice::<Reservation>(&current) {
+                    Ok(current) if current.claimed >= up_to.min(current.range.end) => {
+                        self.update(name, current);
+                        return Ok(true);
+                    }
+                    Ok(current) => reservation = current,
+                    // not a record this instance wrote, the ids are not safe to serve
+                    Err(_) => return Ok(false),
+                },
+            }
+        }
+
+        Err(EtcdErr::OptimisticTxFailed)
+    }
+
+    // marks only move ahead, concurrent claims may finish in any order
+    fn update(&self, name: &str, reservation: Reservation) {
+        let mut held = self.held.lock().unwrap();
+        let reservations = match held.get_mut(&reservation.seq) {
+            Some(reservations) => reservations,
+            None => return,
+        };
+
+        if let Some((_, r)) = reservations.iter_mut().find(|(n, _)| n == name) {
+            r.claimed = r.claimed.max(reservation.claimed);
+        }
+        reservations.retain(|(_, r)| r.claimed < r.range.end);
+    }
+}
+
+// a step past the mark, at least up to the given id, at most to the end of the range
+fn next_claim(range: &Range, claimed: u64, up_to: u64) -> u64 {
+    let step = ((range.end - range.begin) / CLAIM_STEPS).max(1);
+    claimed.saturating_add(step).max(up_to).min(range.end)
+}
+
+/// Name of the instance a record belongs to
+pub fn record_instance(name: &str) -> Option<&str> {
+    name.rsplit_once('/').map(|(instance, _)| instance)
+}
@@ -0,0 +1,159 @@
+/*
+    Serialized state of sequences, used to back them up and move them between etcd clusters.
+
+    A snapshot is either a single JSON document or NDJSON: a header line with format version
+    and revision followed by one line per sequence.
+ */
+
+use serde::{Deserialize, Serialize};
+use crate::etcd_client::SeqState;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub format_version: u32,
+
+    // etcd revision all sequences were read at
+    pub revision: u64,
+    pub sequences: Vec<SeqState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NdjsonHeader {
+    format_version: u32,
+    revision: u64,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    Ndjson,
+}
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// What to do when an imported sequence already exists
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportPolicy {
+    // leave existing sequence as is
+    Skip,
+    // set imported value even if it is less than the current one
+    Overwrite,
+    // set imported value only if it is greater than the current one
+    TakeMax,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ImportOutcome {
+    Created,
+    Updated { old_value: u64 },
+    Skipped { current_value: u64 },
+    Rejected { reason: String },
+}
+
+impl ImportOutcome {
+    /// Value the import wrote over, Some(None) if it created the sequence, None if it wrote nothing
+    pub fn imported_over(&self) -> Option<Option<u64>> {
+        match self {
+            ImportOutcome::Created => Some(None),
+            ImportOutcome::Updated { old_value } => Some(Some(*old_value)),
+            ImportOutcome::Skipped { .. } | ImportOutcome::Rejected { .. } => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportResult {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub outcome: ImportOutcome,
+}
+
+
+impl Snapshot {
+    pub fn new(revision: u64, sequences: Vec<SeqState>) -> Self {
+        Self { format_version: FORMAT_VERSION, revision, sequences }
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, SnapshotErr> {
+        let snapshot: Snapshot = serde_json::from_slice(json)?;
+        check_version(snapshot.format_version)?;
+
+        Ok(snapshot)
+    }
+
+    pub fn to_ndjson(&self) -> String {
+        let header = NdjsonHeader { format_version: self.format_version, revision: self.revision };
+
+        let mut ndjson = serde_json::to_string(&header).unwrap();
+        for seq in &self.sequences {
+            ndjson.push('\n');
+            ndjson.push_str(&serde_json::to_string(seq).unwrap());
+        }
+
+        ndjson.push('\n');
+        ndjson
+    }
+
+    pub fn from_ndjson(ndjson: &[u8]) -> Result<Self, SnapshotErr> {
+        let mut lines = ndjson.split(|b| *b == b'\n').filter(|l| !l.iter().all(u8::is_ascii_whitespace));
+
+        let header = lines.next().ok_or(SnapshotErr::NoHeader)?;
+        let header: NdjsonHeader = serde_json::from_slice(header)?;
+        check_version(header.format_version)?;
+
+        let sequences = lines.map(serde_json::from_slice).collect::<Result<Vec<SeqState>, _>>()?;
+
+        Ok(Snapshot { format_version: header.format_version, revision: header.revision, sequences })
+    }
+}
+
+fn check_version(format_version: u32) -> Result<(), SnapshotErr> {
+    if format_version != FORMAT_VERSION {
+        return Err(SnapshotErr::UnsupportedVersion(format_version))
+    }
+
+    Ok(())
+}
+
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum SnapshotErr {
+    Json(serde_json::Error),
+    NoHeader,
+    UnsupportedVersion(u32),
+}
+
+impl From<serde_json::Error> for SnapshotErr {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::etcd_client::SeqState;
+    use super::{Snapshot, SnapshotErr};
+
+    #[test]
+    fn ndjson_round_trip() {
+        let seq = SeqState { name: "orders".to_string(), value: 42, create_revision: 3, mod_revision: 7, version: 2 };
+        let ndjson = Snapshot::new(7, vec![seq]).to_ndjson();
+
+        let snapshot = Snapshot::from_ndjson(ndjson.as_bytes()).unwrap();
+        assert_eq!(7, snapshot.revision);
+        assert_eq!("orders", snapshot.sequences[0].name);
+        assert_eq!(42, snapshot.sequences[0].value);
+
+        let newer = ndjson.replacen("\"format_version\":1", "\"format_version\":2", 1);
+        assert!(matches!(Snapshot::from_ndjson(newer.as_bytes()), Err(SnapshotErr::UnsupportedVersion(2))));
+    }
+}
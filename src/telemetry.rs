@@ -0,0 +1,300 @@
+/*
+    Distributed tracing with OpenTelemetry.
+
+    Every HTTP and gRPC request gets a server span that continues the trace of the client if it
+    sent W3C trace context (traceparent header). Range allocation, cache calls and etcd requests
+    get child spans, and the context is passed on to etcd in traceparent too. Spans follow the
+    request through awaits by the context attached to its future, see in_span.
+
+    Spans are exported over OTLP/gRPC only if `tracing` is configured. Without it spans are not
+    recorded, but trace context of requests is still passed on.
+
+    The exporter runs on its own thread, so a slow collector or the flush on shutdown never
+    blocks workers.
+ */
+
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::future::Future;
+use std::thread::{self, JoinHandle};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use log::warn;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, TraceError, Tracer};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tokio::sync::oneshot;
+use crate::config::TracingProps;
+
+const TRACER_NAME: &str = "ngamahi-id-gen";
+
+// attributes of spans, besides standard HTTP ones
+pub const SEQUENCE: &str = "id_gen.sequence";
+pub const RANGE_SIZE: &str = "id_gen.range_size";
+pub const FETCH_SIZE: &str = "id_gen.fetch_size";
+pub const CACHE_HIT: &str = "id_gen.cache_hit";
+pub const IDS_MISSING: &str = "id_gen.ids_missing";
+pub const CAS_RETRIES: &str = "id_gen.cas_retries";
+pub const REQUEST_ID: &str = "id_gen.request_id";
+
+
+/// Exporter of spans, flushes them on shutdown
+pub struct Telemetry {
+    provider: TracerProvider,
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Err(err) = self.provider.shutdown() {
+            warn!("Couldn't flush spans on shutdown: {:?}", err);
+        }
+
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Sets up propagation of trace context, and export of spans if tracing is configured
+pub fn init(props: Option<&TracingProps>) -> Result<Option<Telemetry>, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let props = match props {
+        Some(props) => props.clone(),
+        None => return Ok(None),
+    };
+
+    let (provider_tx, provider_rx) = std::sync::mpsc::channel();
+    let (stop, stop_rx) = oneshot::channel();
+
+    let thread = thread::Builder::new().name("telemetry".to_string()).spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            // the exporter and the batch processor run on this thread's runtime
+            let provider = new_provider(&props);
+            let started = provider.is_ok();
+
+            let _ = provider_tx.send(provider);
+            if started {
+                let _ = stop_rx.await;
+            }
+        })
+    }).map_err(|err| TraceError::Other(Box::new(err)))?;
+
+    let provider = provider_rx.recv().map_err(|err| TraceError::Other(Box::new(err)))??;
+    global::set_tracer_provider(provider.clone());
+
+    Ok(Some(Telemetry { provider, stop, thread }))
+}
+
+fn new_provider(props: &TracingProps) -> Result<TracerProvider, TraceError> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(props.otlp_endpoint.clone())
+        .build()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(props.sample_ratio))))
+        .with_resource(Resource::new([KeyValue::new("service.name", props.service_name.clone())]))
+        .build())
+}
+
+
+/// Starts a span that is a child of the current one and returns the context with it
+pub fn start_span(name: impl Into<Cow<'static, str>>, kind: SpanKind, attributes: Vec<KeyValue>) -> Context {
+    start_span_in(&Context::current(), name, kind, attributes)
+}
+
+/// Starts a server span that continues the trace context found by the extractor
+pub fn continue_trace(extractor: &dyn Extractor, name: impl Into<Cow<'static, str>>, attributes: Vec<KeyValue>) -> Context {
+    let parent = global::get_text_map_propagator(|p| p.extract(extractor));
+    start_span_in(&parent, name, SpanKind::Server, attributes)
+}
+
+fn start_span_in(parent: &Context, name: impl Into<Cow<'static, str>>, kind: SpanKind, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer.span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+
+    parent.with_span(span)
+}
+
+/// Runs the future with the span of the context as the current one, then ends the span
+pub async fn in_span<T, E: Debug>(cx: Context, future: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let result = future.with_context(cx.clone()).await;
+    end(&cx, &result);
+    result
+}
+
+/// Ends the span of the context, marking it failed if the result is an error
+pub fn end<T, E: Debug>(cx: &Context, result: &Result<T, E>) {
+    let span = cx.span();
+
+    if let Err(err) = result {
+        span.set_status(Status::error(format!("{:?}", err)));
+    }
+
+    span.end();
+}
+
+/// Sets an attribute of the current span
+pub fn record(attribute: KeyValue) {
+    Context::current().span().set_attribute(attribute);
+}
+
+/// Adds trace context of the span to headers of an outgoing request
+pub fn inject(cx: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|p| p.inject_context(cx, &mut HeaderInjector(headers)));
+}
+
+
+/// Server span of every HTTP request, named by the route it matched
+pub async fn trace_request(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", req.method().to_string()),
+        KeyValue::new("http.route", route.clone()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    if let Some(seq) = req.match_info().get("seq") {
+        attributes.push(KeyValue::new(SEQUENCE, seq.to_string()));
+    }
+
+    let cx = continue_trace(&HeaderExtractor(req.headers()), format!("{} {}", req.method(), route), attributes);
+
+    let response = next.call(req).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    match &response {
+        Ok(response) => {
+            let status = response.status();
+            span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    span.end();
+
+    response
+}
+
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use actix_web::http::header::HeaderMap;
+    use opentelemetry::global;
+    use opentelemetry::trace::{SpanKind, TraceContextExt};
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{TraceService, TraceServiceServer};
+    use opentelemetry_proto::tonic::collector::trace::v1::{ExportTraceServiceRequest, ExportTraceServiceResponse};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+    use crate::config::TracingProps;
+    use super::{continue_trace, end, init, inject, start_span, HeaderExtractor};
+
+    // one test, as both parts change the global tracer provider
+    #[test]
+    fn trace_context_is_passed_on_and_spans_are_exported() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mut incoming = HeaderMap::new();
+        incoming.insert("traceparent".parse().unwrap(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse().unwrap());
+
+        let cx = continue_trace(&HeaderExtractor(&incoming), "GET /sequence/{seq}", vec![]);
+        let attached = cx.clone().attach();
+        let etcd_cx = start_span("etcd kv/txn", SpanKind::Client, vec![]);
+
+        let mut outgoing = HeaderMap::new();
+        inject(&etcd_cx, &mut outgoing);
+
+        assert_eq!(cx.span().span_context().trace_id(), etcd_cx.span().span_context().trace_id());
+        let traceparent = outgoing.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+
+        drop(attached);
+        spans_are_exported_over_otlp();
+    }
+
+    struct Collector(mpsc::Sender<String>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(&self, request: Request<ExportTraceServiceRequest>) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let spans = request.into_inner().resource_spans.into_iter()
+                .flat_map(|r| r.scope_spans)
+                .flat_map(|s| s.spans);
+
+            for span in spans {
+                let _ = self.0.send(span.name);
+            }
+
+            Ok(Response::new(ExportTraceServiceResponse { partial_success: None }))
+        }
+    }
+
+    fn spans_are_exported_over_otlp() {
+        let (spans, spans_rx) = mpsc::channel();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        thread::spawn(move || actix_web::rt::System::new().block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            Server::builder()
+                .add_service(TraceServiceServer::new(Collector(spans)))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        }));
+
+        let props = TracingProps { otlp_endpoint: format!("http://{}", addr), service_name: "test".to_string(), sample_ratio: 1.0 };
+        let telemetry = init(Some(&props)).unwrap().unwrap();
+
+        let cx = start_span("etcd kv/txn", SpanKind::Client, vec![]);
+        end::<(), ()>(&cx, &Ok(()));
+        telemetry.shutdown();
+
+        assert_eq!("etcd kv/txn", spans_rx.recv_timeout(Duration::from_secs(10)).unwrap());
+    }
+}
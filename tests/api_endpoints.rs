@@ -0,0 +1,115 @@
+mod fake_etcd;
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use actix_web::{test, web, App};
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use ngamahi_id_gen::api_endpoints::{create_seq, create_tenant_seq, get_metrics, get_next_id, get_next_range, get_next_tenant_range, seq_info};
+use ngamahi_id_gen::config::Properties;
+use ngamahi_id_gen::demand::DemandTracker;
+use ngamahi_id_gen::forecast::{Forecast, LatestForecasts, UsageHistory};
+use ngamahi_id_gen::policy::PolicyStore;
+use ngamahi_id_gen::rate_limit::RateLimiter;
+use ngamahi_id_gen::{cache, etcd_client, get_app_data, Shared};
+use fake_etcd::FakeEtcd;
+
+
+fn props(etcd: &FakeEtcd) -> Properties {
+    serde_yaml::from_str(&format!(r#"
+        etcd_addr: "{}"
+        etcd_fetch_range_size: 100
+        client_range_max_size: 100
+        key_prefix: "ids/"
+        tenants:
+          acme:
+            max_sequences: 1
+            client_range_max_size: 10
+    "#, etcd.url())).unwrap()
+}
+
+fn shared(props: &Properties) -> Shared {
+    let policies = Arc::new(PolicyStore::new(Duration::from_secs(60)));
+
+    Shared {
+        caches: cache::new_all(&props.cache),
+        rate_limiter: Arc::new(RateLimiter::new(props.rate_limits.clone(), policies.clone())),
+        demand: Arc::new(DemandTracker::new(props.etcd_fetch_range_size, None)),
+        journal: None,
+        reservations: None,
+        max_client_range_size: Arc::new(AtomicU64::new(props.client_range_max_size)),
+        policies,
+        forecasts: Arc::new(LatestForecasts::new(Duration::from_secs(60))),
+    }
+}
+
+
+// %2F is decoded to '/' in path parameters, such names must not reach tenants' or meta keys
+#[actix_web::test]
+async fn names_with_encoded_slashes_are_rejected() {
+    let etcd = FakeEtcd::start();
+    let props = props(&etcd);
+    let data = get_app_data(props.clone(), shared(&props), etcd_client::new_http_client(awc::Client::default()));
+
+    let app = test::init_service(App::new()
+        .app_data(Data::new(data))
+        .service(get_next_range)
+        .service(get_next_id)
+        .service(create_seq)
+        .service(get_next_tenant_range)
+        .service(create_tenant_seq)
+        .service(seq_info)
+        .service(web::scope("/tenants/{tenant}").service(seq_info))).await;
+
+    let created = test::call_service(&app, test::TestRequest::post().uri("/tenants/acme/sequence/orders").to_request()).await;
+    assert_eq!(StatusCode::OK, created.status());
+
+    let requests = [
+        (test::TestRequest::post().uri("/sequence/tenants%2Facme%2Fmore"), StatusCode::BAD_REQUEST),
+        (test::TestRequest::post().uri("/sequence/meta%2Fleader"), StatusCode::BAD_REQUEST),
+        (test::TestRequest::post().uri("/tenants/acme/sequence/a%2Fb"), StatusCode::BAD_REQUEST),
+        (test::TestRequest::get().uri("/sequence/tenants%2Facme%2Forders?size=50"), StatusCode::BAD_REQUEST),
+        (test::TestRequest::get().uri("/sequence/tenants%2Facme%2Forders/next"), StatusCode::BAD_REQUEST),
+        (test::TestRequest::get().uri("/tenants/acme/sequence/orders%2Fx?size=1"), StatusCode::BAD_REQUEST),
+        // match info keeps %2F encoded, so these look for sequences literally named so
+        (test::TestRequest::get().uri("/sequence/tenants%2Facme%2Forders/info"), StatusCode::NOT_FOUND),
+        (test::TestRequest::get().uri("/tenants/acme/sequence/orders%2Fx/info"), StatusCode::NOT_FOUND),
+    ];
+
+    for (request, status) in requests {
+        let request = request.to_request();
+        let uri = request.uri().clone();
+
+        let response = test::call_service(&app, request).await;
+        assert_eq!(status, response.status(), "{}", uri);
+    }
+
+    // nothing was written besides the tenant's sequence, and it was not touched
+    assert_eq!(None, etcd.get("ids/tenants/acme/more"));
+    assert_eq!(None, etcd.get("ids/meta/leader"));
+    assert_eq!(None, etcd.get("ids/tenants/acme/a/b"));
+    assert_eq!(Some(0), etcd.get_u64("ids/tenants/acme/orders"));
+}
+
+#[actix_web::test]
+async fn metrics_serve_forecasts_of_the_last_capacity_check() {
+    let etcd = FakeEtcd::start();
+    let props = props(&etcd);
+    let shared = shared(&props);
+
+    // 200 ids in 100 s leave 700 ids for 350 s
+    let history = UsageHistory { capacity: 1000, samples: vec![(0, 100), (100, 300)] };
+    shared.forecasts.publish(vec![("orders".to_string(), Forecast::new(1000, 300, &history))]);
+
+    let data = get_app_data(props.clone(), shared, etcd_client::new_http_client(awc::Client::default()));
+    let app = test::init_service(App::new().app_data(Data::new(data)).service(get_metrics)).await;
+
+    let body = test::call_and_read_body(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    assert!(body.contains("id_gen_capacity_used_ratio{sequence=\"orders\"} 0.3\n"), "{}", body);
+    assert!(body.contains("id_gen_exhaustion_seconds{sequence=\"orders\"} 350\n"), "{}", body);
+    // scrapes don't go to etcd
+    assert_eq!(0, etcd.requests("kv/range"));
+}
@@ -0,0 +1,494 @@
+/*
+    In-process stand-in for etcd, serving the part of its v3 JSON gateway the id server uses:
+    kv/range, kv/put, kv/deleterange and kv/txn with compare/success/failure, keeping create and
+    mod revisions and versions of keys the way etcd does, and leases, which expire only when a test says so.
+
+    Faults are injected per etcd API: error statuses, requests dropped before they are applied and
+    responses dropped after, latency of every request, and writers that change a key right before
+    the next transactions comparing it, as other instances racing for the same sequence would.
+
+    Every connection is served by its own thread with blocking I/O, so the server doesn't depend on
+    the runtime of the test. Threads end with the test process.
+ */
+
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose};
+use serde_json::{json, Map, Value};
+
+
+/// What happens to the next request to an etcd API
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    // etcd answers with this status and an error body, nothing is applied
+    Status(u16),
+    // the connection is closed before the request is applied
+    DropRequest,
+    // the request is applied, but the connection is closed instead of answering
+    DropResponse,
+}
+
+pub struct FakeEtcd {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    store: Store,
+    faults: VecDeque<(String, Fault)>,
+    latency: Duration,
+    // key -> (added to its value, transactions left)
+    racing_writes: HashMap<Vec<u8>, (u64, usize)>,
+    // api, e.g. "kv/txn" -> requests served
+    requests: HashMap<String, usize>,
+}
+
+struct Store {
+    revision: u64,
+    kvs: BTreeMap<Vec<u8>, Kv>,
+    leases: HashSet<u64>,
+    last_lease: u64,
+}
+
+#[derive(Clone)]
+struct Kv {
+    value: Vec<u8>,
+    create_revision: u64,
+    mod_revision: u64,
+    version: u64,
+    lease: Option<u64>,
+}
+
+// an empty etcd cluster is at revision 1
+impl Default for Store {
+    fn default() -> Self {
+        Self { revision: 1, kvs: BTreeMap::new(), leases: HashSet::new(), last_lease: 0 }
+    }
+}
+
+
+impl FakeEtcd {
+    pub fn start() -> FakeEtcd {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+
+        FakeEtcd { addr, state }
+    }
+
+    /// Address to give the etcd client, e.g. http://127.0.0.1:40123
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let revision = state.store.next_revision();
+        state.store.put(key.as_bytes(), value.to_vec(), None, revision);
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().store.kvs.get(key.as_bytes()).map(|kv| kv.value.clone())
+    }
+
+    /// Sequences are stored as big-endian u64
+    pub fn put_u64(&self, key: &str, value: u64) {
+        self.put(key, &value.to_be_bytes());
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).map(|value| u64::from_be_bytes(value.try_into().unwrap()))
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.state.lock().unwrap().store.revision
+    }
+
+    /// Requests served by an etcd API so far, faulty ones included
+    pub fn requests(&self, api: &str) -> usize {
+        self.state.lock().unwrap().requests.get(api).copied().unwrap_or(0)
+    }
+
+    /// The fault happens to the next request to the API, e.g. "kv/txn". Faults of an API happen in the order they were added
+    pub fn fail_next(&self, api: &str, fault: Fault) {
+        self.state.lock().unwrap().faults.push_back((api.to_string(), fault));
+    }
+
+    /// Leases run out as if their holders stopped keeping them alive, keys put with them are deleted
+    pub fn expire_leases(&self) {
+        let store = &mut self.state.lock().unwrap().store;
+        let leases: Vec<_> = store.leases.iter().copied().collect();
+
+        for lease in leases {
+            store.revoke(lease);
+        }
+    }
+
+    /// Every request is answered after this delay
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Before each of the next `times` transactions comparing the key, another writer adds `step` to its u64 value
+    pub fn race_writes(&self, key: &str, step: u64, times: usize) {
+        self.state.lock().unwrap().racing_writes.insert(key.as_bytes().to_vec(), (step, times));
+    }
+}
+
+
+// =========| HTTP |==============
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let _ = writer.set_nodelay(true);
+
+    // connections are kept alive, so one carries many requests
+    while let Some((path, body)) = read_request(&mut reader) {
+        let api = path.strip_prefix("/v3/").unwrap_or(&path).to_string();
+
+        let (fault, latency) = {
+            let mut state = state.lock().unwrap();
+            *state.requests.entry(api.clone()).or_default() += 1;
+
+            let fault = state.faults.iter().position(|(faulty, _)| *faulty == api)
+                .and_then(|i| state.faults.remove(i))
+                .map(|(_, fault)| fault);
+
+            (fault, state.latency)
+        };
+
+        thread::sleep(latency);
+
+        let (status, response) = match fault {
+            Some(Fault::DropRequest) => return,
+            Some(Fault::Status(status)) => (status, json!({ "error": "etcdserver: injected fault", "code": 14, "message": "etcdserver: injected fault" })),
+            _ => match serde_json::from_slice::<Value>(&body) {
+                Ok(request) => handle(&api, &request, &mut state.lock().unwrap()),
+                Err(err) => (400, json!({ "error": err.to_string(), "code": 3, "message": err.to_string() })),
+            },
+        };
+
+        if let Some(Fault::DropResponse) = fault {
+            return;
+        }
+
+        if write_response(&mut writer, status, &response).is_err() {
+            return;
+        }
+    }
+}
+
+// None once the client closes the connection
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<(String, Vec<u8>)> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let path = request_line.split_whitespace().nth(1)?.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some((path, body))
+}
+
+fn write_response(writer: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    let reason = if status == 200 { "OK" } else { "Error" };
+
+    // in one write, as small writes wait for acks of the previous ones
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, reason, body.len(), body);
+    writer.write_all(response.as_bytes())
+}
+
+
+// =========| etcd |==============
+
+fn handle(api: &str, request: &Value, state: &mut State) -> (u16, Value) {
+    let store = &mut state.store;
+
+    let response = match api {
+        "kv/range" => store.range(request),
+        "kv/put" => {
+            let revision = store.next_revision();
+            store.put_request(request, revision)
+        }
+        "kv/deleterange" => {
+            let revision = store.revision + 1;
+            store.delete_range(request, revision)
+        }
+        "kv/txn" => {
+            // writers racing for compared keys get there first
+            for key in compared_keys(request) {
+                if let Some((step, times)) = state.racing_writes.get_mut(&key) {
+                    if *times > 0 {
+                        *times -= 1;
+                        let step = *step;
+                        state.store.add(&key, step);
+                    }
+                }
+            }
+
+            state.store.txn(request)
+        }
+        "lease/grant" => {
+            store.last_lease += 1;
+            store.leases.insert(store.last_lease);
+            json!({ "header": store.header(), "ID": store.last_lease.to_string(), "TTL": num(&request["TTL"]).to_string() })
+        }
+        "lease/keepalive" => {
+            let id = num(&request["ID"]);
+            // etcd omits zero fields, so a lease that is gone comes back without TTL
+            match store.leases.contains(&id) {
+                true => json!({ "result": { "header": store.header(), "ID": id.to_string(), "TTL": "60" } }),
+                false => json!({ "result": { "header": store.header(), "ID": id.to_string() } }),
+            }
+        }
+        "lease/revoke" => {
+            if !store.revoke(num(&request["ID"])) {
+                return (404, json!({ "error": "etcdserver: requested lease not found", "code": 5, "message": "etcdserver: requested lease not found" }));
+            }
+
+            json!({ "header": store.header() })
+        }
+        _ => return (404, json!({ "error": "Not Found", "code": 5, "message": "Not Found" })),
+    };
+
+    (200, response)
+}
+
+impl Store {
+    fn header(&self) -> Value {
+        json!({ "cluster_id": "1", "member_id": "1", "revision": self.revision.to_string(), "raft_term": "2" })
+    }
+
+    fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        self.revision
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>, lease: Option<u64>, revision: u64) {
+        let kv = self.kvs.entry(key.to_vec()).or_insert(Kv { value: vec![], create_revision: revision, mod_revision: 0, version: 0, lease: None });
+
+        kv.value = value;
+        kv.mod_revision = revision;
+        kv.version += 1;
+        kv.lease = lease;
+    }
+
+    // keys attached to the lease go with it. False if there is no such lease
+    fn revoke(&mut self, id: u64) -> bool {
+        if !self.leases.remove(&id) {
+            return false;
+        }
+
+        let attached: Vec<_> = self.kvs.iter().filter(|(_, kv)| kv.lease == Some(id)).map(|(key, _)| key.clone()).collect();
+        if !attached.is_empty() {
+            self.revision += 1;
+            for key in attached {
+                self.kvs.remove(&key);
+            }
+        }
+
+        true
+    }
+
+    // a write of another instance, at its own revision
+    fn add(&mut self, key: &[u8], step: u64) {
+        let value = match self.kvs.get(key) {
+            Some(kv) => u64::from_be_bytes(kv.value.clone().try_into().unwrap()),
+            None => return,
+        };
+
+        let revision = self.next_revision();
+        self.put(key, (value + step).to_be_bytes().to_vec(), None, revision);
+    }
+
+    fn put_request(&mut self, request: &Value, revision: u64) -> Value {
+        let lease = request.get("lease").map(num).filter(|lease| *lease != 0);
+        self.put(&bytes(&request["key"]), bytes(&request["value"]), lease, revision);
+
+        json!({ "header": self.header() })
+    }
+
+    fn range(&self, request: &Value) -> Value {
+        let keys = self.keys_in(request);
+        let count = keys.len();
+        let limit = request.get("limit").map(num).filter(|limit| *limit > 0).unwrap_or(u64::MAX) as usize;
+
+        let mut response = Map::new();
+        response.insert("header".to_string(), self.header());
+
+        if !request["count_only"].as_bool().unwrap_or(false) && count > 0 {
+            let kvs: Vec<_> = keys.iter().take(limit).map(|key| kv_json(key, &self.kvs[key])).collect();
+            response.insert("kvs".to_string(), Value::Array(kvs));
+        }
+        if count > limit {
+            response.insert("more".to_string(), Value::Bool(true));
+        }
+        if count > 0 {
+            response.insert("count".to_string(), Value::String(count.to_string()));
+        }
+
+        Value::Object(response)
+    }
+
+    // the store moves to the revision only if something was deleted
+    fn delete_range(&mut self, request: &Value, revision: u64) -> Value {
+        let keys = self.keys_in(request);
+        let prev_kvs: Vec<_> = keys.iter().map(|key| kv_json(key, &self.kvs[key])).collect();
+
+        for key in &keys {
+            self.kvs.remove(key);
+        }
+        if !keys.is_empty() {
+            self.revision = revision;
+        }
+
+        let mut response = Map::new();
+        response.insert("header".to_string(), self.header());
+        if !keys.is_empty() {
+            response.insert("deleted".to_string(), Value::String(keys.len().to_string()));
+
+            if request["prev_kv"].as_bool().unwrap_or(false) {
+                response.insert("prev_kvs".to_string(), Value::Array(prev_kvs));
+            }
+        }
+
+        Value::Object(response)
+    }
+
+    // all operations of a transaction are at one revision, which is new only if one of them writes
+    fn txn(&mut self, request: &Value) -> Value {
+        let succeeded = request["compare"].as_array().into_iter().flatten().all(|c| self.compare(c));
+        let ops = match succeeded {
+            true => &request["success"],
+            false => &request["failure"],
+        };
+
+        let revision = self.revision + 1;
+        let mut responses = vec![];
+
+        for op in ops.as_array().into_iter().flatten() {
+            if let Some(put) = op.get("requestPut") {
+                self.revision = revision;
+                responses.push(json!({ "response_put": self.put_request(put, revision) }));
+            } else if let Some(range) = op.get("requestRange") {
+                responses.push(json!({ "response_range": self.range(range) }));
+            } else if let Some(delete) = op.get("requestDeleteRange") {
+                responses.push(json!({ "response_delete_range": self.delete_range(delete, revision) }));
+            }
+        }
+
+        let mut response = json!({ "header": self.header(), "responses": responses });
+        if succeeded {
+            response["succeeded"] = Value::Bool(true);
+        }
+
+        response
+    }
+
+    fn compare(&self, comparison: &Value) -> bool {
+        let kv = self.kvs.get(&bytes(&comparison["key"]));
+        let target = comparison["target"].as_str().unwrap_or("VERSION");
+
+        let ordering = match target {
+            // a missing key has no value, so comparing it fails whatever the result is
+            "VALUE" => match kv {
+                Some(kv) => kv.value.cmp(&bytes(&comparison["value"])),
+                None => return false,
+            },
+            "CREATE" => kv.map_or(0, |kv| kv.create_revision).cmp(&num(&comparison["create_revision"])),
+            "MOD" => kv.map_or(0, |kv| kv.mod_revision).cmp(&num(&comparison["mod_revision"])),
+            _ => kv.map_or(0, |kv| kv.version).cmp(&num(&comparison["version"])),
+        };
+
+        match comparison["result"].as_str().unwrap_or("EQUAL") {
+            "GREATER" => ordering.is_gt(),
+            "LESS" => ordering.is_lt(),
+            "NOT_EQUAL" => ordering.is_ne(),
+            _ => ordering.is_eq(),
+        }
+    }
+
+    // without range_end the key itself, with "\0" all keys from key on, otherwise keys in [key, range_end)
+    fn keys_in(&self, request: &Value) -> Vec<Vec<u8>> {
+        let key = bytes(&request["key"]);
+
+        match request.get("range_end").map(bytes) {
+            None => self.kvs.get(&key).map(|_| vec![key]).unwrap_or_default(),
+            Some(end) if end == [0] => self.kvs.range(key..).map(|(k, _)| k.clone()).collect(),
+            Some(end) if end <= key => vec![],
+            Some(end) => self.kvs.range(key..end).map(|(k, _)| k.clone()).collect(),
+        }
+    }
+}
+
+fn compared_keys(txn: &Value) -> Vec<Vec<u8>> {
+    txn["compare"].as_array().into_iter().flatten().map(|c| bytes(&c["key"])).collect()
+}
+
+// etcd sends 64-bit numbers as strings and omits empty values
+fn kv_json(key: &[u8], kv: &Kv) -> Value {
+    let mut json = json!({
+        "key": general_purpose::STANDARD.encode(key),
+        "create_revision": kv.create_revision.to_string(),
+        "mod_revision": kv.mod_revision.to_string(),
+        "version": kv.version.to_string(),
+    });
+
+    if !kv.value.is_empty() {
+        json["value"] = Value::String(general_purpose::STANDARD.encode(&kv.value));
+    }
+    if let Some(lease) = kv.lease {
+        json["lease"] = Value::String(lease.to_string());
+    }
+
+    json
+}
+
+fn bytes(value: &Value) -> Vec<u8> {
+    general_purpose::STANDARD.decode(value.as_str().unwrap_or_default()).unwrap()
+}
+
+// the gateway takes 64-bit numbers both as strings and as numbers
+fn num(value: &Value) -> u64 {
+    match value {
+        Value::String(s) => s.parse().unwrap(),
+        Value::Number(n) => n.as_u64().unwrap(),
+        _ => 0,
+    }
+}
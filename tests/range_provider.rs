@@ -0,0 +1,241 @@
+mod fake_etcd;
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use futures::future::join_all;
+use ngamahi_id_gen::cache;
+use ngamahi_id_gen::cache::cursor::IdCursors;
+use ngamahi_id_gen::config::{CacheProps, TenantProps};
+use ngamahi_id_gen::demand::DemandTracker;
+use ngamahi_id_gen::etcd_client::{self, EnlargeTxErr, EtcdErr, EtcdInteropErr};
+use ngamahi_id_gen::policy::{PolicyStore, SeqPolicy};
+use ngamahi_id_gen::range::{Range, RangeProvider, RangeProviderErr};
+use fake_etcd::{FakeEtcd, Fault};
+
+const KEY_PREFIX: &str = "ids/";
+const FETCH_SIZE: u64 = 100;
+
+
+// a worker of an id server, with caches of its own
+fn provider(etcd: &FakeEtcd) -> RangeProvider {
+    RangeProvider {
+        etcd_client: etcd_client::new_etcd_client(
+            etcd_client::new_http_client(awc::Client::default()), etcd.url(), KEY_PREFIX.to_string()),
+        caches: cache::new_all(&CacheProps::default()),
+        demand: Arc::new(DemandTracker::new(FETCH_SIZE, None)),
+        max_client_range_size: Arc::new(AtomicU64::new(1000)),
+        journal: None,
+        reservations: None,
+        return_evicted: false,
+        interleave: vec![],
+        tenants: HashMap::new(),
+        cursors: Rc::new(IdCursors::new()),
+        next_id_batch: 10,
+        policies: Arc::new(PolicyStore::new(Duration::from_secs(60))),
+    }
+}
+
+fn ids(ranges: &[Range]) -> Vec<u64> {
+    ranges.iter().flat_map(|r| r.begin..r.end).collect()
+}
+
+// no id is handed out twice
+fn assert_unique(ids: &[u64]) {
+    let unique: HashSet<_> = ids.iter().collect();
+    assert_eq!(ids.len(), unique.len(), "some ids were handed out twice");
+}
+
+
+#[actix_web::test]
+async fn ranges_are_served_without_gaps_from_cache_and_etcd() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+    provider.create_sequence("orders".to_string()).await.unwrap();
+
+    let mut served = vec![];
+    for size in [1, 7, 30, 62, 99, 1, 250] {
+        let ranges = provider.get_next_range("orders".to_string(), size).await.unwrap();
+        assert_eq!(size, ids(&ranges).len() as u64);
+
+        served.extend(ids(&ranges));
+    }
+
+    assert_eq!((0..450).collect::<Vec<_>>(), served);
+    // 450 ids, fetches of at least 100 ids and more if a request needs it
+    assert!(etcd.requests("kv/txn") <= 5);
+    assert!(etcd.get_u64("ids/orders").unwrap() >= 450);
+}
+
+#[actix_web::test]
+async fn creating_a_sequence_twice_fails_and_keeps_its_value() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+
+    let created = provider.create_sequence("orders".to_string()).await.unwrap();
+    assert_eq!(Some(etcd.revision()), created.revision);
+
+    provider.get_next_range("orders".to_string(), 10).await.unwrap();
+
+    let again = provider.create_sequence("orders".to_string()).await;
+    assert!(matches!(again, Err(RangeProviderErr::Etcd(EtcdErr::CreateSeqTxErr(_)))));
+    assert_eq!(FETCH_SIZE, etcd.get_u64("ids/orders").unwrap());
+}
+
+#[actix_web::test]
+async fn allocation_retries_when_another_writer_moves_the_sequence() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+    provider.create_sequence("orders".to_string()).await.unwrap();
+
+    // two other instances take 1000 ids each between the read and the transaction
+    etcd.race_writes("ids/orders", 1000, 2);
+    let ranges = provider.get_next_range("orders".to_string(), 10).await.unwrap();
+
+    assert_eq!(vec![Range::new(2000, 2010)], ranges);
+    assert_eq!(3, etcd.requests("kv/txn") - 1);
+    assert_eq!(2000 + FETCH_SIZE, etcd.get_u64("ids/orders").unwrap());
+
+    // a writer that never lets go makes allocation give up
+    etcd.race_writes("ids/orders", 1, usize::MAX);
+    let given_up = provider.get_next_range("orders".to_string(), FETCH_SIZE).await;
+
+    assert!(matches!(given_up, Err(RangeProviderErr::Etcd(EtcdErr::OptimisticTxFailed))));
+}
+
+#[actix_web::test]
+async fn concurrent_workers_never_hand_out_the_same_id() {
+    let etcd = FakeEtcd::start();
+    // widens windows between reads and transactions of workers
+    etcd.set_latency(Duration::from_millis(2));
+
+    let workers: Vec<_> = (0..4).map(|_| provider(&etcd)).collect();
+    workers[0].create_sequence("orders".to_string()).await.unwrap();
+
+    // requests of a worker follow each other, workers race
+    let served = join_all(workers.iter().map(|worker| async move {
+        let mut served = vec![];
+        for size in (0..25).map(|i| 1 + i * 7 % 40) {
+            // allocation may give up after too many lost races
+            if let Ok(ranges) = worker.get_next_range("orders".to_string(), size).await {
+                served.extend(ids(&ranges));
+            }
+        }
+        served
+    })).await;
+    let served: Vec<_> = served.into_iter().flatten().collect();
+
+    assert!(served.len() > 1000);
+    assert_unique(&served);
+    assert!(served.iter().all(|id| *id < etcd.get_u64("ids/orders").unwrap()));
+}
+
+#[actix_web::test]
+async fn etcd_failures_surface_as_errors_and_never_cause_duplicates() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+    provider.create_sequence("orders".to_string()).await.unwrap();
+
+    etcd.fail_next("kv/range", Fault::Status(503));
+    etcd.fail_next("kv/txn", Fault::DropRequest);
+    etcd.fail_next("kv/txn", Fault::DropResponse);
+
+    let mut served = vec![];
+    let mut failures = vec![];
+    for _ in 0..6 {
+        match provider.get_next_range("orders".to_string(), FETCH_SIZE).await {
+            Ok(ranges) => served.extend(ids(&ranges)),
+            Err(err) => failures.push(err),
+        }
+    }
+
+    // the first read is of the sequence's policy, then each transaction fails on its own
+    assert_eq!(3, failures.len());
+    assert!(matches!(&failures[0],
+        RangeProviderErr::Etcd(EtcdErr::EtcdInteropErr(EtcdInteropErr::StatusErr { status: 503, .. }))), "{:?}", failures[0]);
+    for dropped in &failures[1..] {
+        assert!(matches!(dropped,
+            RangeProviderErr::Etcd(EtcdErr::EnlargeTxErr(EnlargeTxErr::EtcdInteropError(EtcdInteropErr::SendReqErr(_))))), "{:?}", dropped);
+    }
+
+    // ids of the transaction whose response was lost are skipped, not served twice
+    let lost = FETCH_SIZE + 1;
+    assert_unique(&served);
+    assert_eq!(3 * FETCH_SIZE as usize, served.len());
+    assert!(served.iter().all(|id| *id >= lost && *id < etcd.get_u64("ids/orders").unwrap()));
+}
+
+#[actix_web::test]
+async fn listing_returns_all_sequences() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+
+    // far more than fits in a few kilobytes of etcd response
+    for i in 0..200 {
+        provider.create_sequence(format!("customer-orders-{:03}", i)).await.unwrap();
+    }
+    etcd.put("ids/meta/policy/customer-orders-000", b"{}");
+
+    let (revision, seqs) = provider.list_sequences().await.unwrap();
+
+    assert_eq!(etcd.revision(), revision);
+    assert_eq!(200, seqs.len());
+    assert_eq!("customer-orders-199", seqs[199].name);
+}
+
+#[actix_web::test]
+async fn demand_is_tracked_only_for_served_sequences() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+    provider.create_sequence("orders".to_string()).await.unwrap();
+
+    provider.get_next_range("orders".to_string(), 10).await.unwrap();
+    let missing = provider.get_next_range("missing".to_string(), 10).await;
+
+    assert!(matches!(missing, Err(RangeProviderErr::Etcd(EtcdErr::NoSuchRangeErr(_)))), "{:?}", missing);
+    assert_eq!(vec!["orders".to_string()], provider.demand.all_stats().into_iter().map(|(name, _)| name).collect::<Vec<_>>());
+    assert_eq!(1, provider.demand.stats("orders").unwrap().etcd_fetches);
+}
+
+#[actix_web::test]
+async fn ranges_fetched_while_the_sequence_is_invalidated_are_not_served() {
+    let etcd = FakeEtcd::start();
+    let provider = provider(&etcd);
+    provider.create_sequence("invoices".to_string()).await.unwrap();
+    provider.get_next_range("invoices".to_string(), 10).await.unwrap();
+
+    // invalidations land while the next fetch waits for etcd
+    etcd.set_latency(Duration::from_millis(100));
+    let invalidate_during_fetch = |seq: &'static str| async move {
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        cache::new_common().invalidate(seq);
+    };
+
+    let (fetched, _) = futures::join!(provider.get_next_range("invoices".to_string(), 200), invalidate_during_fetch("invoices-other"));
+    assert_eq!(200, ids(&fetched.unwrap()).len());
+
+    let (fetched, _) = futures::join!(provider.get_next_range("invoices".to_string(), 900), invalidate_during_fetch("invoices"));
+    assert!(matches!(fetched, Err(RangeProviderErr::SeqChanged(_))), "{:?}", fetched);
+}
+
+#[actix_web::test]
+async fn policy_limit_above_the_tenant_one_does_not_raise_it() {
+    let etcd = FakeEtcd::start();
+    let mut provider = provider(&etcd);
+    provider.tenants.insert("acme".to_string(), TenantProps { max_sequences: 10, client_range_max_size: 50 });
+    provider.create_tenant_sequence("acme".to_string(), "orders".to_string()).await.unwrap();
+
+    let policy = SeqPolicy { client_range_max_size: Some(500), ..Default::default() };
+    provider.policies.put("tenants/acme/orders", Some(policy));
+
+    let too_large = provider.get_next_tenant_range("acme".to_string(), "orders".to_string(), 51).await;
+    assert!(matches!(too_large, Err(RangeProviderErr::Validation(_))), "{:?}", too_large);
+    assert_eq!(50, ids(&provider.get_next_tenant_range("acme".to_string(), "orders".to_string(), 50).await.unwrap()).len());
+
+    // a lower one applies
+    provider.policies.put("tenants/acme/orders", Some(SeqPolicy { client_range_max_size: Some(20), ..Default::default() }));
+    let too_large = provider.get_next_tenant_range("acme".to_string(), "orders".to_string(), 21).await;
+    assert!(matches!(too_large, Err(RangeProviderErr::Validation(_))), "{:?}", too_large);
+}
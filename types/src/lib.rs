@@ -0,0 +1,76 @@
+/*
+    Types that the id server sends to its clients.
+ */
+
+use serde::{Deserialize, Serialize};
+
+/// Content type of the fixed-width binary layout: every range is its begin, end and stride
+/// as little-endian u64, back to back. Expanded ranges are just their ids as little-endian u64
+pub const BINARY_CONTENT_TYPE: &str = "application/vnd.ngamahi.ranges";
+
+const BINARY_RANGE_LEN: usize = 24;
+
+
+/// Ids from `begin` (inclusive) to `end` (exclusive), `stride` apart
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Range {
+    pub begin: u64,
+    pub end: u64,
+
+    // more than 1 for interleaved sequences, omitted otherwise
+    #[serde(default = "default_stride", skip_serializing_if = "is_default_stride")]
+    pub stride: u64,
+}
+
+impl Range {
+    pub fn new(begin: u64, end: u64) -> Self {
+        Self { begin, end, stride: 1 }
+    }
+
+    /// Number of ids in the range
+    pub fn len(&self) -> u64 {
+        (self.end - self.begin).div_ceil(self.stride)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.begin >= self.end
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u64> {
+        (self.begin..self.end).step_by(self.stride as usize)
+    }
+}
+
+
+pub fn encode_binary(ranges: &[Range]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ranges.len() * BINARY_RANGE_LEN);
+
+    for r in ranges {
+        bytes.extend_from_slice(&r.begin.to_le_bytes());
+        bytes.extend_from_slice(&r.end.to_le_bytes());
+        bytes.extend_from_slice(&r.stride.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// None if the length is not a multiple of range size
+pub fn decode_binary(bytes: &[u8]) -> Option<Vec<Range>> {
+    if !bytes.len().is_multiple_of(BINARY_RANGE_LEN) {
+        return None;
+    }
+
+    let u64_at = |chunk: &[u8], i: usize| u64::from_le_bytes(chunk[i * 8..(i + 1) * 8].try_into().unwrap());
+
+    Some(bytes.chunks_exact(BINARY_RANGE_LEN)
+        .map(|c| Range { begin: u64_at(c, 0), end: u64_at(c, 1), stride: u64_at(c, 2) })
+        .collect())
+}
+
+fn default_stride() -> u64 {
+    1
+}
+
+fn is_default_stride(stride: &u64) -> bool {
+    *stride == 1
+}